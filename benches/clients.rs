@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use process_transactions::sample::{write_sample, SampleSpec};
+use process_transactions::{Model, ModelConfig, OutputOptions};
+
+// Hashed vs ordered client table: processing a file with many clients (insert-heavy)
+// and writing accounts of a loaded model again and again (read-heavy)
+fn clients(c: &mut Criterion) {
+    let mut input = Vec::new();
+    let spec = SampleSpec { clients: 100_000, transactions: 300_000, ..SampleSpec::default() };
+    write_sample(&mut input, &spec).expect("Error writing input");
+
+    let model = |ordered_clients| Model::with_config(ModelConfig { ordered_clients, ..ModelConfig::default() });
+    let mut group = c.benchmark_group("clients");
+    group.sample_size(10);
+    for (name, ordered_clients) in [("hashed", false), ("ordered", true)] {
+        group.bench_function(format!("insert/{}", name), |b| b.iter(|| {
+            model(ordered_clients).process_reader(&input[..]).expect("Processing failed").records
+        }));
+
+        let mut loaded = model(ordered_clients);
+        loaded.process_reader(&input[..]).expect("Processing failed");
+        group.bench_function(format!("read/{}", name), |b| b.iter(|| {
+            let mut output = Vec::new();
+            loaded.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+            output.len()
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, clients);
+criterion_main!(benches);
@@ -0,0 +1,28 @@
+use std::io::Read;
+use criterion::{criterion_group, criterion_main, Criterion};
+use process_transactions::sample::{write_sample, SampleSpec};
+use process_transactions::{open_file_reader, open_reader, Model};
+
+// Same large file read through Box<dyn Read> and through concrete FileReader
+fn read(c: &mut Criterion) {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let path = dir.path().join("transactions.csv");
+    let spec = SampleSpec { clients: 1000, transactions: 500_000, ..SampleSpec::default() };
+    write_sample(std::fs::File::create(&path).expect("Error creating input"), &spec).expect("Error writing input");
+
+    let open = || std::fs::File::open(&path).expect("Error opening input");
+    let mut group = c.benchmark_group("read");
+    group.sample_size(10);
+    group.bench_function("boxed", |b| b.iter(|| {
+        let reader: Box<dyn Read> = open_reader(open()).expect("Error opening reader");
+        Model::default().process_reader(reader).expect("Processing failed").records
+    }));
+    group.bench_function("monomorphized", |b| b.iter(|| {
+        let reader = open_file_reader(open()).expect("Error opening reader");
+        Model::default().process_reader(reader).expect("Processing failed").records
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, read);
+criterion_main!(benches);
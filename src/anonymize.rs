@@ -0,0 +1,147 @@
+use std::io::{Read, Write};
+use std::str::FromStr;
+use crate::money::{Money, Rounding};
+use crate::source::csv_reader_builder;
+use crate::{ClientID, TransactionID};
+
+// What happens to amounts of anonymized rows
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum AmountMode {
+    // Unchanged, so the file is processed exactly like the original
+    #[default]
+    Keep,
+    // Rounded to whole units
+    Bucket,
+    // Scaled by a keyed factor within ±10%
+    Jitter,
+}
+
+impl FromStr for AmountMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(AmountMode::Keep),
+            "bucket" => Ok(AmountMode::Bucket),
+            "jitter" => Ok(AmountMode::Jitter),
+            _ => Err(format!("Unknown amount mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct AnonymizeSpec {
+    // Same key maps the same IDs the same way, a different key gives unrelated ones
+    pub key: u64,
+    pub amounts: AmountMode,
+}
+
+// Keyed bijections: xor, multiplication by odd constant and xorshift are each invertible,
+// so distinct IDs never collide and references between rows survive
+fn permute_tx(tx: TransactionID, key: u64) -> TransactionID {
+    let mut x = tx ^ key;
+    x ^= x >> 30;
+    x = x.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x ^= x >> 27;
+    x = x.wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+fn permute_client(client: ClientID, key: u64) -> ClientID {
+    let mut x = client ^ key as ClientID;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xc2b2_ae35);
+    (x ^ (x >> 16)) ^ (key >> 32) as ClientID
+}
+
+fn anonymize_amount(amount: &str, tx: Option<TransactionID>, spec: &AnonymizeSpec) -> String {
+    let Ok(money) = amount.parse::<Money>() else {
+        return amount.to_string();
+    };
+    match spec.amounts {
+        AmountMode::Keep => amount.to_string(),
+        AmountMode::Bucket => money.round(0, Rounding::HalfUp).format_fixed(0),
+        AmountMode::Jitter => {
+            // Minor units scaled by 900..=1100 permille, keyed by the row's transaction
+            let permille = 900 + (permute_tx(tx.unwrap_or_default(), !spec.key) % 201) as i128;
+            let text = money.format_fixed(4);
+            let (sign, digits) = text.strip_prefix('-').map_or(("", text.as_str()), |digits| ("-", digits));
+            let Ok(units) = digits.replace('.', "").parse::<i128>() else {
+                return amount.to_string();
+            };
+            let units = units * permille / 1000;
+            format!("{}{}.{:04}", sign, units / 10_000, units % 10_000)
+        }
+    }
+}
+
+// Copies transactions CSV with client and tx columns remapped, other columns as they are.
+// IDs which don't parse are kept, so malformed rows stay malformed. With amounts kept the result
+// is accepted and rejected row for row like the original, disputes pointing at the same deposits.
+pub fn write_anonymized<R: Read, W: Write>(reader: R, writer: W, spec: &AnonymizeSpec) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut rdr = csv_reader_builder().flexible(true).from_reader(reader);
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+    let headers = rdr.headers()?.clone();
+    let column = |name: &str| headers.iter().position(|header| header == name);
+    let (client_index, tx_index, amount_index) = (column("client"), column("tx"), column("amount"));
+    wtr.write_record(&headers)?;
+
+    let mut rows = 0;
+    for record in rdr.records() {
+        let record = record?;
+        let tx = tx_index.and_then(|index| record.get(index)).and_then(|tx| tx.parse::<TransactionID>().ok());
+        let row: Vec<String> = record.iter().enumerate().map(|(index, field)| {
+            if Some(index) == client_index && let Ok(client) = field.parse::<ClientID>() {
+                permute_client(client, spec.key).to_string()
+            } else if Some(index) == tx_index && let Some(tx) = tx {
+                permute_tx(tx, spec.key).to_string()
+            } else if Some(index) == amount_index && !field.is_empty() {
+                anonymize_amount(field, tx, spec)
+            } else {
+                field.to_string()
+            }
+        }).collect();
+        wtr.write_record(&row)?;
+        rows += 1;
+    }
+    wtr.flush()?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Model;
+
+    #[test]
+    fn test_anonymized_rejections_match() {
+        for case in ["05-transactions-chargeback", "06-transactions-unexpected", "07-transactions-dispute-wrong-client", "12-transactions-batches"] {
+            let original = std::fs::read(format!("cases/{}.csv", case)).expect("Error reading input");
+            let mut anonymized = Vec::new();
+            let spec = AnonymizeSpec { key: 42, amounts: AmountMode::Keep };
+            write_anonymized(&original[..], &mut anonymized, &spec).expect("Error anonymizing");
+            let text = String::from_utf8(anonymized.clone()).unwrap();
+            assert!(!text.contains("deposit,1,1,"), "{}", text);
+
+            let process = |input: &[u8]| {
+                let mut model = Model::default();
+                let report = model.process_reader(input).expect("Processing failed");
+                let balance = model.trial_balance();
+                (report.records, report.parse_errors, report.rejected, report.batches, balance.clients, balance.locked_clients, balance.total)
+            };
+            assert_eq!(process(&anonymized), process(&original), "{}", case);
+        }
+
+        // Bijective on a range of small IDs, and keyed
+        let clients: std::collections::HashSet<ClientID> = (0..100_000).map(|client| permute_client(client, 7)).collect();
+        assert_eq!(clients.len(), 100_000);
+        assert_ne!(permute_tx(1, 7), permute_tx(1, 8));
+
+        let jittered = anonymize_amount("100.0", Some(1), &AnonymizeSpec { key: 1, amounts: AmountMode::Jitter });
+        let jittered: f64 = jittered.parse().unwrap();
+        assert!((90.0..=110.0).contains(&jittered), "{}", jittered);
+        assert_eq!(anonymize_amount("2.5", None, &AnonymizeSpec { key: 1, amounts: AmountMode::Bucket }), "3");
+    }
+}
@@ -0,0 +1,57 @@
+use crate::Client;
+use crate::money::Money;
+
+// Balance noise tolerated in expected accounts, far below the 4 decimals of output
+#[cfg(not(feature = "minor-units"))]
+pub fn case_epsilon() -> Money {
+    "0.000000001".parse().expect("Invalid epsilon")
+}
+
+// Minor-units can't represent the noise and computes exactly, so compares exactly
+#[cfg(feature = "minor-units")]
+pub fn case_epsilon() -> Money {
+    Money::zero()
+}
+
+pub fn clients_close(expected: &Client, actual: &Client, epsilon: &Money) -> bool {
+    let close = |expected: &Money, actual: &Money| {
+        let difference = expected - actual;
+        let difference = if difference.is_negative() { -difference } else { difference };
+        difference <= *epsilon
+    };
+    (expected.client, expected.locked) == (actual.client, actual.locked)
+        && close(&expected.available, &actual.available)
+        && close(&expected.held, &actual.held)
+        && close(&expected.pending, &actual.pending)
+        && close(&expected.total, &actual.total)
+}
+
+// Balances equal within epsilon, client ID and lock state exactly; for fixtures from tools with rounding noise
+pub fn assert_client_eq(expected: &Client, actual: &Client, epsilon: &Money) {
+    assert!(clients_close(expected, actual, epsilon), "Client data mismatch for client {}, expected {:?}, actual {:?}, epsilon {}",
+        expected.client, expected, actual, epsilon);
+}
+
+// Minor-units backend can't carry noise below its 4 decimals
+#[cfg(all(test, not(feature = "minor-units")))]
+mod tests {
+    use super::*;
+
+    fn money(text: &str) -> Money {
+        text.parse().expect("Invalid amount")
+    }
+
+    #[test]
+    fn test_assert_client_eq() {
+        let expected = Client { available: money("1.5"), held: money("0.5"), total: money("2.0"), ..Client::new(1) };
+        let off_by = |amount: &str| Client { available: &expected.available + &money(amount), total: &expected.total + &money(amount), ..expected.clone() };
+        let epsilon = case_epsilon();
+        assert_client_eq(&expected, &off_by("0.000000001"), &epsilon);
+        assert_client_eq(&expected, &off_by("-0.000000001"), &epsilon);
+        assert!(!clients_close(&expected, &off_by("0.01"), &epsilon));
+        assert!(!clients_close(&expected, &off_by("-0.01"), &epsilon));
+        assert!(!clients_close(&expected, &Client { locked: true, ..expected.clone() }, &epsilon));
+        let result = std::panic::catch_unwind(|| assert_client_eq(&expected, &off_by("0.01"), &epsilon));
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,5456 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+use log::{info, warn};
+use chrono::{DateTime, Utc};
+
+pub mod anonymize;
+#[cfg(any(test, feature = "test-helpers"))]
+pub mod fixtures;
+pub mod logfile;
+pub mod money;
+pub mod sample;
+pub mod source;
+#[cfg(feature = "xlsx")]
+pub mod xlsx;
+use money::{Money, Rounding};
+use source::{AmountLocale, ColumnMap, CsvSource, NdjsonSource, RecordGuard, SkipRecords, TypeAliases, SourceError, SourceLocation, TransactionSource, DEFAULT_MAX_RECORD_BYTES};
+use sha2::{Digest, Sha256};
+use flate2::read::GzDecoder;
+
+pub type ClientID = u32;
+pub type TransactionID = u64;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    // Administrative reversal of an applied deposit or withdrawal, see Model::rollback
+    Rollback,
+}
+
+impl TransactionType {
+    // Value as written in input
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Rollback => "rollback",
+        }
+    }
+}
+
+impl std::str::FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use TransactionType::*;
+        [Deposit, Withdrawal, Dispute, Resolve, Chargeback, Rollback].into_iter()
+            .find(|tr_type| tr_type.name() == s)
+            .ok_or_else(|| format!("Unknown transaction type: {}", s))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub tr_type: TransactionType,
+    pub client: ClientID,
+    pub tx: TransactionID,
+    pub amount: Option<Money>,
+    // Optional column, RFC3339 or epoch seconds, not used by accounting logic
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<DateTime<Utc>>,
+    // Optional column, settlement batch the row came in, used for per-batch reporting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    // Amount column present but empty ("deposit,1,1,"), amount is None also when there is no such column
+    #[serde(skip)]
+    pub empty_amount: bool,
+    // Amount written without decimal point ("100"), possibly minor units sent as major ones
+    #[serde(skip)]
+    pub integer_amount: bool,
+}
+
+fn deserialize_timestamp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    let Some(text) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    parse_timestamp(&text).map(Some).map_err(serde::de::Error::custom)
+}
+
+pub fn parse_timestamp(text: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(epoch) = text.parse::<i64>() {
+        return DateTime::from_timestamp(epoch, 0).ok_or_else(|| format!("Epoch timestamp out of range: {}", text));
+    }
+    DateTime::parse_from_rfc3339(text)
+        .map(|dt| dt.with_timezone(&Utc))
+        .map_err(|err| format!("Invalid timestamp {}: {}", text, err))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Client {
+    pub client: ClientID,
+    pub available: Money,
+    pub held: Money,
+    // Deposits awaiting settlement with ModelConfig::pending_delay, counted in total but not available
+    #[serde(default)]
+    pub pending: Money,
+    pub total: Money,
+    pub locked: bool,
+    // What locked the account first: chargeback's tx and timestamp (if it had one), or only the reason
+    // for locks by policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_by: Option<TransactionID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<LockReason>,
+}
+
+impl Client {
+    fn new(client: ClientID) -> Self {
+        Client {
+            client,
+            available: Money::zero(),
+            held: Money::zero(),
+            pending: Money::zero(),
+            total: Money::zero(),
+            locked: false,
+            locked_by: None,
+            locked_at: None,
+            lock_reason: None,
+        }
+    }
+
+    // Locks the account, keeping the cause of an earlier lock; chargeback gives its transaction
+    fn lock(&mut self, reason: LockReason, by: Option<&Transaction>) {
+        if self.locked {
+            return;
+        }
+        self.locked = true;
+        self.lock_reason = Some(reason);
+        self.locked_by = by.map(|tr| tr.tx);
+        self.locked_at = by.and_then(|tr| tr.timestamp);
+    }
+
+    // Accounting identity every client keeps after each transaction: available + held + pending == total
+    pub fn check_invariant(&self) -> bool {
+        &(&self.available + &self.held) + &self.pending == self.total
+    }
+}
+
+// Amounts are written with four decimal places
+fn serialize_amount<S: Serializer>(amount: &Money, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&amount.format_fixed(4))
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // Aligned columns with totals footer, for terminals
+    Table,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+// Formatting of accounts output
+#[derive(Debug, Default, Clone)]
+pub struct OutputOptions {
+    // Emits amounts as integer minor units, multiplied by 10^scale
+    pub scale: Option<u32>,
+    // Writes only client and total columns
+    pub balances_only: bool,
+    pub format: OutputFormat,
+    // Decimal places of amounts, 4 by default
+    pub precision: Option<u32>,
+    // Table format drops held and available columns to fit this width
+    pub width: Option<usize>,
+    pub rounding: Rounding,
+    // Adds locked_by, locked_at and lock_reason columns to CSV accounts
+    pub lock_details: bool,
+}
+
+impl OutputOptions {
+    // Rounding for output happens only here, model keeps full precision
+    pub fn format_amount(&self, amount: &Money) -> String {
+        match self.scale {
+            Some(scale) => amount.shift(scale).format_rounded(0, self.rounding),
+            None => amount.format_rounded(self.precision.unwrap_or(4), self.rounding),
+        }
+    }
+}
+
+// Why an account was locked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockReason {
+    Chargeback,
+    // Dispute left available negative with NegativeAvailable::Lock
+    NegativeAvailable,
+    // VelocityAction::LockAccount
+    Velocity,
+    // ModelConfig::lock_on_overflow
+    BalanceOverflow,
+}
+
+impl LockReason {
+    // Same as serialized, for CSV output
+    pub fn code(&self) -> &'static str {
+        match self {
+            LockReason::Chargeback => "chargeback",
+            LockReason::NegativeAvailable => "negative_available",
+            LockReason::Velocity => "velocity",
+            LockReason::BalanceOverflow => "balance_overflow",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AccountRow {
+    client: ClientID,
+    available: String,
+    held: String,
+    // Only with pending delay configured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending: Option<String>,
+    total: String,
+    locked: bool,
+    // Only with velocity limits configured: deposits and withdrawals, withdrawn sum and breaches since model creation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transactions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    withdrawn: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    velocity_breaches: Option<u32>,
+    // Only with OutputOptions::lock_details, empty for accounts not locked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lock_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BalanceRow {
+    client: ClientID,
+    total: String,
+}
+
+// Roll-up of final state across all clients
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct TrialBalance {
+    pub clients: usize,
+    pub locked_clients: usize,
+    #[serde(serialize_with = "serialize_amount")]
+    pub available: Money,
+    #[serde(serialize_with = "serialize_amount")]
+    pub held: Money,
+    // Deposits not yet settled with ModelConfig::pending_delay
+    #[serde(serialize_with = "serialize_amount")]
+    pub pending: Money,
+    // Total funds of locked clients
+    #[serde(serialize_with = "serialize_amount")]
+    pub locked: Money,
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: Money,
+}
+
+impl TrialBalance {
+    // Grand total has to match available plus held plus pending, same identity as for each client
+    pub fn is_consistent(&self) -> bool {
+        &(&self.available + &self.held) + &self.pending == self.total
+    }
+
+    pub fn write_csv<W: Write>(&self, writer: W) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.serialize(self)?;
+        wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn write_json<W: Write>(&self, mut writer: W) -> Result<(), Box<dyn std::error::Error>> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for TrialBalance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Clients: {} ({} locked)", self.clients, self.locked_clients)?;
+        writeln!(f, "Available: {}", self.available.format_fixed(4))?;
+        writeln!(f, "Held: {}", self.held.format_fixed(4))?;
+        if !self.pending.is_zero() {
+            writeln!(f, "Pending: {}", self.pending.format_fixed(4))?;
+        }
+        writeln!(f, "Locked funds: {}", self.locked.format_fixed(4))?;
+        write!(f, "Total: {}", self.total.format_fixed(4))
+    }
+}
+
+// Outcome of transactions of one type
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct TypeCounts {
+    pub applied: u64,
+    pub rejected: u64,
+}
+
+// Cumulative counters updated as the model processes transactions. Shared through Model::metrics,
+// so another thread (e.g. a metrics endpoint) reads them while processing goes on.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    processed: AtomicU64,
+    rejected: AtomicU64,
+    locked: AtomicU64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            processed: self.processed.load(AtomicOrdering::Relaxed),
+            rejected: self.rejected.load(AtomicOrdering::Relaxed),
+            locked: self.locked.load(AtomicOrdering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    // Transactions acted on, applied or rejected
+    pub processed: u64,
+    pub rejected: u64,
+    // Clients locked, including ones locked in loaded snapshot
+    pub locked: u64,
+}
+
+// Counters of everything processed by the model, across inputs, with final balances; see Model::stats
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ModelStats {
+    // By TransactionType::name, types never seen left out
+    pub by_type: BTreeMap<&'static str, TypeCounts>,
+    // Client and locked client counts with aggregate balances
+    pub balance: TrialBalance,
+}
+
+impl fmt::Display for ModelStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, counts) in &self.by_type {
+            writeln!(f, "{}: {} applied, {} rejected", name, counts.applied, counts.rejected)?;
+        }
+        write!(f, "{}", self.balance)
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ModelConfig {
+    // Regulatory cap on client balance, deposits exceeding it are rejected
+    pub max_balance: Option<Money>,
+    // Sanity bounds of a single deposit/withdrawal amount, to catch data-entry errors
+    pub min_amount: Option<Money>,
+    pub max_amount: Option<Money>,
+    // Buffers whole input and applies it in timestamp order (rows without timestamp go first, ties keep file order)
+    pub order_by_timestamp: bool,
+    // Window of timestamps, from since (inclusive) to until (exclusive), rows outside it or without timestamp
+    // are passed over before reaching accounts; input without timestamp column (or, without header,
+    // whose first record has no timestamp) fails before any row is applied
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    // Continues from snapshot after a crash: input records the snapshot had consumed are skipped,
+    // so every later row applies, also disputes of older tx. Must be the snapshot's highest tx, as a check it's the right one.
+    pub resume_from: Option<TransactionID>,
+    // Snapshot clients breaking available + held + pending == total get total recomputed from the parts,
+    // for snapshots written before a fix, instead of the snapshot being refused
+    pub repair_snapshot: bool,
+    // Retries Dispute/Resolve/Chargeback on not yet seen transactions after the whole input is read
+    pub defer_disputes: bool,
+    // Disputes of a client beyond this many open ones are rejected, against dispute flooding
+    pub max_open_disputes: Option<u32>,
+    // Available may go this far below zero by withdrawals, e.g. for fees settling a day late
+    pub overdraft_limit: Money,
+    // Response to a dispute of funds already withdrawn, which leaves available below overdraft limit
+    pub negative_available: NegativeAvailable,
+    // Whether a deposit may be disputed again after its dispute was resolved
+    pub redispute: Redispute,
+    // What chargeback of a deposit does with the held amount, the account is locked either way
+    pub chargeback_semantics: ChargebackSemantics,
+    // Transactions refused on locked accounts, all accepted when not set
+    pub lock_policy: Option<LockPolicy>,
+    // Stored transactions Dispute/Resolve/Chargeback may refer to
+    pub disputable: Disputable,
+    // Locks client whose transaction was rejected as balance overflow, as the feed is likely broken
+    pub lock_on_overflow: bool,
+    // Velocity limits per client since the model was created (not kept in snapshot): deposits and withdrawals
+    // applied, counted only within velocity_window_secs before the row's timestamp when both are given,
+    // and sum of applied withdrawals; velocity_action is taken on a transaction going over either
+    pub max_transactions_per_client: Option<u32>,
+    pub velocity_window_secs: Option<u64>,
+    pub max_withdrawn_per_client: Option<Money>,
+    pub velocity_action: VelocityAction,
+    // Keeps before-image of the last applied transaction for Model::undo_last, for interactive tools;
+    // off by default as it costs a copy per transaction
+    pub undo_log: bool,
+    // Client table kept ordered by ID, for embedders reading clients in order often
+    pub ordered_clients: bool,
+    // Retention of deposits for disputes: evicted from memory this many seconds (by timestamps) after their own
+    // timestamp, deposits without timestamp after retention_transactions subsequent transactions (rejected ones
+    // included); later disputes on them are rejected as expired. Kept while disputed or pending.
+    pub retention_secs: Option<u64>,
+    pub retention_transactions: Option<u64>,
+    // Deposits land in pending balance and move to available after this many subsequent transactions
+    // (rejected ones included), available right away when not set
+    pub pending_delay: Option<u64>,
+    // Thousands separator / decimal mark convention of CSV amounts, plain "1234.56" when not set
+    pub amount_locale: Option<AmountLocale>,
+    // Removes leading "$", "€" or "£" from CSV amounts
+    pub strip_symbols: bool,
+    // Warns of (still applies) amounts without decimal point, e.g. cents sent as "100" meaning 1.00
+    pub warn_integer_amounts: bool,
+    // Fails before processing unless CSV header has exactly the known columns,
+    // and at the first CSV row not fitting them
+    pub strict_schema: bool,
+    // Longer CSV lines are malformed records, source::DEFAULT_MAX_RECORD_BYTES when not set
+    pub max_record_bytes: Option<usize>,
+    // Debugging aids: data rows passed over before processing (still counted for line numbers),
+    // and max rows processed after them
+    pub skip_rows: u64,
+    pub limit_rows: Option<u64>,
+    // Types acted on, others are rejected, all when not set
+    pub allowed_types: Option<Vec<TransactionType>>,
+    // Clients whose rows are passed over, e.g. test accounts, so they don't appear in output
+    pub denied_clients: BTreeSet<ClientID>,
+    // Decimals kept of deposit/withdrawal amounts, extra ones are dropped at ingestion by rounding,
+    // full input precision when not set
+    pub amount_places: Option<u32>,
+    // Rule used at ingestion and for accounts output
+    pub rounding: Rounding,
+    // Trims and lowercases type values and maps common synonyms (source::default_type_aliases),
+    // type_aliases take precedence over the built-in ones
+    pub normalize_types: bool,
+    // Rows with type value which isn't a transaction type (after aliases)
+    pub unknown_types: UnknownTypes,
+    // Alternative type values from policy file, e.g. credit = "deposit"
+    pub type_aliases: TypeAliases,
+    // Canonical CSV column names mapped to partner's header names, e.g. tx = "txn_id"
+    pub column_map: ColumnMap,
+}
+
+impl ModelConfig {
+    // Effective configuration for --print-config, so runs can be audited
+    pub fn to_toml(&self) -> Result<String, toml::ser::Error> {
+        toml::to_string(self)
+    }
+}
+
+// What a dispute leaving available negative does besides holding the funds
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NegativeAvailable {
+    // Logs a warning
+    #[default]
+    Flag,
+    // Logs a warning and locks the account
+    Lock,
+    // Nothing, e.g. when negative balances are expected and reconciled elsewhere
+    Ignore,
+}
+
+impl std::str::FromStr for NegativeAvailable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(NegativeAvailable::Flag),
+            "lock" => Ok(NegativeAvailable::Lock),
+            "ignore" => Ok(NegativeAvailable::Ignore),
+            _ => Err(format!("Unknown negative available policy: {}", s)),
+        }
+    }
+}
+
+// Rule of dispute eligibility. A disputed withdrawal is credited back to held funds, resolve lets
+// the withdrawal stand, chargeback reverses it into available.
+#[derive(Debug, Default, Clone, Copy)]
+pub enum Disputable {
+    #[default]
+    Deposits,
+    DepositsAndWithdrawals,
+    // Embedder's rule, called with the stored deposit or withdrawal
+    Custom(fn(&Transaction) -> bool),
+}
+
+impl Disputable {
+    pub fn allows(&self, tr: &Transaction) -> bool {
+        match self {
+            Disputable::Deposits => tr.tr_type == TransactionType::Deposit,
+            Disputable::DepositsAndWithdrawals => matches!(tr.tr_type, TransactionType::Deposit | TransactionType::Withdrawal),
+            Disputable::Custom(rule) => rule(tr),
+        }
+    }
+}
+
+impl std::str::FromStr for Disputable {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deposits" => Ok(Disputable::Deposits),
+            "deposits-and-withdrawals" => Ok(Disputable::DepositsAndWithdrawals),
+            _ => Err(format!("Unknown dispute eligibility: {}", s)),
+        }
+    }
+}
+
+// Custom rule can't be written out, --print-config shows just that there is one
+impl Serialize for Disputable {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            Disputable::Deposits => "deposits",
+            Disputable::DepositsAndWithdrawals => "deposits-and-withdrawals",
+            Disputable::Custom(_) => "custom",
+        })
+    }
+}
+
+// Disputes of a deposit after the first one is resolved, acquirers differ on this
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Redispute {
+    #[default]
+    Allow,
+    Forbid,
+    // At most this many disputes after the first
+    UpTo(u32),
+}
+
+impl Redispute {
+    // Disputes a deposit may have had before another one is rejected, unlimited when None
+    fn max_disputes(self) -> Option<u32> {
+        match self {
+            Redispute::Allow => None,
+            Redispute::Forbid => Some(1),
+            Redispute::UpTo(times) => Some(times.saturating_add(1)),
+        }
+    }
+}
+
+impl std::str::FromStr for Redispute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "allow" => Ok(Redispute::Allow),
+            "forbid" => Ok(Redispute::Forbid),
+            _ => s.parse().map(Redispute::UpTo).map_err(|_| format!("Unknown redispute policy: {}", s)),
+        }
+    }
+}
+
+// Reading of chargeback on a deposit, feeds differ in who lost the dispute
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChargebackSemantics {
+    // Held amount leaves the account, total drops by it
+    #[default]
+    Clawback,
+    // Held amount returns to available, as with resolve
+    Refund,
+}
+
+impl std::str::FromStr for ChargebackSemantics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clawback" => Ok(ChargebackSemantics::Clawback),
+            "refund" => Ok(ChargebackSemantics::Refund),
+            _ => Err(format!("Unknown chargeback semantics: {}", s)),
+        }
+    }
+}
+
+// What a locked account may no longer do, its rows of frozen types are rejected as AccountLocked
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockPolicy {
+    // No transactions at all
+    FreezeAll,
+    // No deposits, withdrawals or rollbacks, while disputes, resolves and chargebacks of earlier deposits go on
+    FreezeMovementAllowDisputes,
+    // No withdrawals, so funds can still come in and be contested
+    FreezeWithdrawalsOnly,
+}
+
+impl LockPolicy {
+    pub fn freezes(&self, tr_type: TransactionType) -> bool {
+        match self {
+            LockPolicy::FreezeAll => true,
+            LockPolicy::FreezeMovementAllowDisputes => {
+                matches!(tr_type, TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Rollback)
+            }
+            LockPolicy::FreezeWithdrawalsOnly => tr_type == TransactionType::Withdrawal,
+        }
+    }
+}
+
+impl std::str::FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "freeze-all" => Ok(LockPolicy::FreezeAll),
+            "freeze-movement-allow-disputes" => Ok(LockPolicy::FreezeMovementAllowDisputes),
+            "freeze-withdrawals-only" => Ok(LockPolicy::FreezeWithdrawalsOnly),
+            _ => Err(format!("Unknown lock policy: {}", s)),
+        }
+    }
+}
+
+// What happens to a transaction over a velocity limit, counted in ClientCounters::velocity_breaches either way
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VelocityAction {
+    // Rejected
+    #[default]
+    RejectExcess,
+    // Applied with a warning
+    WarnOnly,
+    // Rejected and the account locked
+    LockAccount,
+}
+
+impl std::str::FromStr for VelocityAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject-excess" => Ok(VelocityAction::RejectExcess),
+            "warn-only" => Ok(VelocityAction::WarnOnly),
+            "lock-account" => Ok(VelocityAction::LockAccount),
+            _ => Err(format!("Unknown velocity action: {}", s)),
+        }
+    }
+}
+
+// Handling of rows with unknown type value, always counted in ProcessingReport::unknown_types
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnknownTypes {
+    // Skipped silently, e.g. partner's informational rows
+    Ignore,
+    // Skipped with a warning
+    #[default]
+    Warn,
+    // Fails the run, for pre-flight validation
+    Error,
+}
+
+impl std::str::FromStr for UnknownTypes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(UnknownTypes::Ignore),
+            "warn" => Ok(UnknownTypes::Warn),
+            "error" => Ok(UnknownTypes::Error),
+            _ => Err(format!("Unknown policy for unknown types: {}", s)),
+        }
+    }
+}
+
+// Site policy loaded from TOML file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub rounding: Option<Rounding>,
+    pub amount_places: Option<u32>,
+    pub unknown_types: Option<UnknownTypes>,
+    pub overdraft_limit: Option<Money>,
+    pub max_transactions_per_client: Option<u32>,
+    pub velocity_window_secs: Option<u64>,
+    pub max_withdrawn_per_client: Option<Money>,
+    pub velocity_action: Option<VelocityAction>,
+    #[serde(default)]
+    pub type_aliases: TypeAliases,
+    #[serde(default)]
+    pub column_map: ColumnMap,
+}
+
+impl Policy {
+    pub fn from_file(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|err| format!("Invalid policy file {}: {}", path, err).into())
+    }
+
+    pub fn apply(self, config: &mut ModelConfig) {
+        if let Some(rounding) = self.rounding {
+            config.rounding = rounding;
+        }
+        if self.amount_places.is_some() {
+            config.amount_places = self.amount_places;
+        }
+        if let Some(unknown_types) = self.unknown_types {
+            config.unknown_types = unknown_types;
+        }
+        if let Some(overdraft_limit) = self.overdraft_limit {
+            config.overdraft_limit = overdraft_limit;
+        }
+        if let Some(velocity_action) = self.velocity_action {
+            config.velocity_action = velocity_action;
+        }
+        // Limits given on command line take precedence over policy ones
+        config.max_transactions_per_client = config.max_transactions_per_client.or(self.max_transactions_per_client);
+        config.velocity_window_secs = config.velocity_window_secs.or(self.velocity_window_secs);
+        config.max_withdrawn_per_client = config.max_withdrawn_per_client.take().or(self.max_withdrawn_per_client);
+        config.type_aliases = self.type_aliases;
+        // --column-map entries take precedence over policy ones
+        for (column, name) in self.column_map {
+            config.column_map.entry(column).or_insert(name);
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Model {
+    config: ModelConfig,
+    clients: ClientTable,
+    revertable_transactions: TransactionStore,
+    disputed_transactions: HashSet<TransactionID>,
+    // Disputed_transactions with their amounts by client, clients without open disputes left out
+    open_disputes: HashMap<ClientID, BTreeMap<TransactionID, Money>>,
+    // Disputes applied per deposit, tracked only when config.redispute limits them
+    dispute_counts: HashMap<TransactionID, u32>,
+    charged_back_transactions: HashSet<TransactionID>,
+    rolled_back_transactions: HashSet<TransactionID>,
+    events: Option<EventLog>,
+    counters: HashMap<ClientID, ClientCounters>,
+    // Highest tx seen in input, checkpoint for resuming
+    max_tx: Option<TransactionID>,
+    // Input records consumed since the model was created, where a resumed run continues
+    records_read: u64,
+    // Records of input still to skip when resuming, carried over from one input to the next
+    resume_skip: u64,
+    // Already applied prefix of each input file, keyed by path
+    input_offsets: HashMap<String, InputOffset>,
+    // Deposits in pending balance, ordered by release
+    pending_deposits: VecDeque<PendingDeposit>,
+    // Transactions processed, clock of pending deposits
+    transactions_seen: u64,
+    // Stored deposits expiring by count in order of storing, and by age in order of expiry,
+    // since timestamps may arrive out of order; evicted from the front once expired
+    retention_by_count: VecDeque<RetainedDeposit>,
+    retention_by_age: BTreeSet<(DateTime<Utc>, TransactionID)>,
+    // Evicted deposits, so disputes on them are told apart from unknown ones
+    expired_transactions: HashSet<TransactionID>,
+    // Sum of evicted deposits still in available, by client, for audit
+    expired_available: HashMap<ClientID, Money>,
+    // Latest input timestamp, clock of retention by age
+    latest_timestamp: Option<DateTime<Utc>>,
+    // Totals corrected while loading snapshot, see ModelConfig::repair_snapshot
+    snapshot_repairs: Vec<TotalRepair>,
+    // Applied and rejected transactions by type, for Model::stats
+    type_counts: BTreeMap<&'static str, TypeCounts>,
+    metrics: Arc<Metrics>,
+    // Before-image of the last applied transaction, see Model::undo_last
+    undo: Option<UndoEntry>,
+    // Counts of velocity limits by client, clients without applied deposits/withdrawals left out
+    velocity: HashMap<ClientID, Velocity>,
+}
+
+// Client's deposits and withdrawals applied since the model was created, see ModelConfig::max_transactions_per_client
+#[derive(Debug, Default, Clone)]
+struct Velocity {
+    transactions: u32,
+    // Timestamps of the ones within velocity window, oldest first
+    recent: VecDeque<DateTime<Utc>>,
+    withdrawn: Money,
+}
+
+// State a transaction may change, as it was before the transaction. Clients are the transaction's one
+// and the ones whose pending deposits were released after it.
+struct UndoEntry {
+    tr: Transaction,
+    clients: Vec<(ClientID, Option<Client>)>,
+    counters: Option<ClientCounters>,
+    open_disputes: Option<BTreeMap<TransactionID, Money>>,
+    dispute_count: Option<u32>,
+    stored: Option<Transaction>,
+    disputed: bool,
+    charged_back: bool,
+    rolled_back: bool,
+    // Transaction's own pending deposit and its position in queue
+    pending: Option<(usize, PendingDeposit)>,
+    // Deposits released from pending after the transaction, in release order
+    released: Vec<PendingDeposit>,
+    velocity: Option<Velocity>,
+    max_tx: Option<TransactionID>,
+    transactions_seen: u64,
+    records_read: u64,
+    latest_timestamp: Option<DateTime<Utc>>,
+    // Retention evictions done before the transaction, in eviction order
+    expired: Vec<ExpiredDeposit>,
+}
+
+// Retained deposit popped by expire_deposits: requeued when still needed, otherwise evicted from storage
+struct ExpiredDeposit {
+    deposit: RetainedDeposit,
+    requeued: Option<RetainedDeposit>,
+    // Evicted transaction, its dispute count and client's expired_available before eviction
+    evicted: Option<(Transaction, Option<u32>, Option<Money>)>,
+}
+
+// Snapshot client total replaced by available + held + pending
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotalRepair {
+    pub client: ClientID,
+    pub total: Money,
+    pub repaired: Money,
+}
+
+impl fmt::Display for TotalRepair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "client {}: total {} recomputed as {}", self.client, self.total.format_fixed(4), self.repaired.format_fixed(4))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct PendingDeposit {
+    tx: TransactionID,
+    client: ClientID,
+    amount: Money,
+    // Value of transactions_seen at which deposit becomes available
+    release_at: u64,
+}
+
+// Stored deposit and when it can be evicted, see ModelConfig::retention_secs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RetainedDeposit {
+    tx: TransactionID,
+    expiry: Expiry,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Expiry {
+    // Value of transactions_seen from which deposit is expired
+    Seen(u64),
+    // Latest timestamp from which deposit is expired
+    At(DateTime<Utc>),
+}
+
+// Consumed prefix of an input file, saved in snapshot together with the state it produced,
+// so a restart skips the prefix and a crash can't apply it twice
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputOffset {
+    pub records: u64,
+    pub bytes: u64,
+    // SHA-256 of the first "bytes" bytes of (decompressed) input
+    pub sha256: String,
+}
+
+// Version 2 widened transaction IDs to u64, version 3 client IDs to u32,
+// older files with narrower IDs read unchanged
+const SNAPSHOT_VERSION: u32 = 3;
+const SNAPSHOT_VERSIONS_READ: [u32; 3] = [1, 2, 3];
+
+// Full model state, except config and attached writers
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    clients: Vec<Client>,
+    transactions: Vec<Transaction>,
+    disputed: Vec<TransactionID>,
+    charged_back: Vec<TransactionID>,
+    rolled_back: Vec<TransactionID>,
+    counters: Vec<(ClientID, ClientCounters)>,
+    max_tx: Option<TransactionID>,
+    // Missing in snapshots written before resuming by record offset
+    #[serde(default)]
+    records_read: Option<u64>,
+    #[serde(default)]
+    input_offsets: Vec<(String, InputOffset)>,
+    #[serde(default)]
+    pending_deposits: Vec<PendingDeposit>,
+    #[serde(default)]
+    transactions_seen: u64,
+    #[serde(default)]
+    retention_queue: Vec<RetainedDeposit>,
+    #[serde(default)]
+    expired: Vec<TransactionID>,
+    #[serde(default)]
+    expired_available: Vec<(ClientID, Money)>,
+    #[serde(default)]
+    latest_timestamp: Option<DateTime<Utc>>,
+    #[serde(default)]
+    dispute_counts: Vec<(TransactionID, u32)>,
+}
+
+// Per-client counters of applied (and some rejected) transactions
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientCounters {
+    pub deposits: u32,
+    pub withdrawals: u32,
+    pub disputes: u32,
+    pub resolves: u32,
+    pub chargebacks: u32,
+    // Withdrawals rejected for insufficient funds
+    pub insufficient_funds: u32,
+    // Transactions rejected as balance overflow, rollbacks included
+    #[serde(default)]
+    pub overflows: u32,
+    // Transactions over a velocity limit, whether rejected or not
+    #[serde(default)]
+    pub velocity_breaches: u32,
+}
+
+impl ClientCounters {
+    fn record(&mut self, tr_type: TransactionType) {
+        match tr_type {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Withdrawal => self.withdrawals += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            TransactionType::Resolve => self.resolves += 1,
+            TransactionType::Chargeback => self.chargebacks += 1,
+            TransactionType::Rollback => {}
+        }
+    }
+
+    // Applied transactions of all types
+    pub fn transactions(&self) -> u32 {
+        self.deposits + self.withdrawals + self.disputes + self.resolves + self.chargebacks
+    }
+
+    pub fn dispute_rate(&self) -> f64 {
+        if self.deposits == 0 { 0.0 } else { self.disputes as f64 / self.deposits as f64 }
+    }
+}
+
+// Change of one client between two accounts files, see diff_accounts
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountDelta {
+    Added(Client),
+    Removed(Client),
+    // Differences new minus old, locked is the new state when it flipped
+    Changed { client: ClientID, available: Money, held: Money, total: Money, locked: Option<bool> },
+}
+
+impl fmt::Display for AccountDelta {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let signed = |amount: &Money| {
+            let text = amount.format_fixed(4);
+            if amount.is_negative() { text } else { format!("+{}", text) }
+        };
+        match self {
+            AccountDelta::Added(c) | AccountDelta::Removed(c) => {
+                let kind = if matches!(self, AccountDelta::Added(_)) { "added" } else { "removed" };
+                write!(f, "client {} {}: available {}, held {}, total {}, locked {}", c.client, kind,
+                    c.available.format_fixed(4), c.held.format_fixed(4), c.total.format_fixed(4), c.locked)
+            }
+            AccountDelta::Changed { client, available, held, total, locked } => {
+                write!(f, "client {}: available {}, held {}, total {}", client, signed(available), signed(held), signed(total))?;
+                match locked {
+                    Some(true) => write!(f, ", locked"),
+                    Some(false) => write!(f, ", unlocked"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+// Amounts are read as text, so they stay exact
+#[derive(Deserialize)]
+struct AccountFileRow {
+    client: ClientID,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+// Reads accounts CSV as written by Model::write_accounts with default options
+pub fn read_accounts<R: Read>(reader: R) -> Result<Vec<Client>, Box<dyn std::error::Error>> {
+    let mut rdr = source::csv_reader_builder().from_reader(reader);
+    let mut clients = Vec::new();
+    for result in rdr.deserialize::<AccountFileRow>() {
+        let row = result?;
+        clients.push(Client {
+            client: row.client,
+            available: row.available.parse()?,
+            held: row.held.parse()?,
+            pending: Money::zero(),
+            total: row.total.parse()?,
+            locked: row.locked,
+            locked_by: None,
+            locked_at: None,
+            lock_reason: None,
+        });
+    }
+    Ok(clients)
+}
+
+// Per-client changes ordered by client ID, unchanged clients are left out
+pub fn diff_accounts(old: &[Client], new: &[Client]) -> Vec<AccountDelta> {
+    let old: BTreeMap<ClientID, &Client> = old.iter().map(|c| (c.client, c)).collect();
+    let new: BTreeMap<ClientID, &Client> = new.iter().map(|c| (c.client, c)).collect();
+    let ids: BTreeSet<ClientID> = old.keys().chain(new.keys()).copied().collect();
+    ids.into_iter().filter_map(|id| match (old.get(&id), new.get(&id)) {
+        (None, Some(c)) => Some(AccountDelta::Added((*c).clone())),
+        (Some(c), None) => Some(AccountDelta::Removed((*c).clone())),
+        (Some(before), Some(after)) if before != after => Some(AccountDelta::Changed {
+            client: id,
+            available: &after.available - &before.available,
+            held: &after.held - &before.held,
+            total: &after.total - &before.total,
+            locked: (before.locked != after.locked).then_some(after.locked),
+        }),
+        _ => None,
+    }).collect()
+}
+
+// Longest raw record text kept in RowError
+pub const ROW_ERROR_SNIPPET_BYTES: usize = 256;
+
+// Statement rows held in memory by write_statements before they are appended to files
+const STATEMENT_BUFFER_BYTES: usize = 8 << 20;
+
+// Row skipped while processing, collected by Model::process_reader_collect_errors
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowError {
+    pub location: SourceLocation,
+    // Record as read, cut at ROW_ERROR_SNIPPET_BYTES; empty if the source can't tell
+    pub record: String,
+    pub kind: RowErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RowErrorKind {
+    // Record doesn't parse into a transaction, with parser's message
+    Malformed(String),
+    // Type value isn't a transaction type, skipped per ModelConfig::unknown_types
+    UnknownType(String),
+}
+
+impl RowError {
+    fn new(location: SourceLocation, record: Option<String>, kind: RowErrorKind) -> Self {
+        let mut record = record.unwrap_or_default();
+        if record.len() > ROW_ERROR_SNIPPET_BYTES {
+            let mut end = ROW_ERROR_SNIPPET_BYTES;
+            while !record.is_char_boundary(end) {
+                end -= 1;
+            }
+            record.truncate(end);
+        }
+        RowError { location, record, kind }
+    }
+}
+
+impl fmt::Display for RowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            RowErrorKind::Malformed(message) => write!(f, "{}: {} [{}]", self.location, message, self.record),
+            RowErrorKind::UnknownType(name) => write!(f, "{}: unknown transaction type {} [{}]", self.location, name, self.record),
+        }
+    }
+}
+
+// Client whose live balances differ from ones recomputed by Model::audit
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditMismatch {
+    pub client: ClientID,
+    // Available, held and total
+    pub expected: (Money, Money, Money),
+    pub actual: (Money, Money, Money),
+}
+
+impl fmt::Display for AuditMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (available, held, total) = &self.expected;
+        write!(f, "client {}: expected available {}, held {}, total {}", self.client, available, held, total)?;
+        let (available, held, total) = &self.actual;
+        write!(f, "; found available {}, held {}, total {}", available, held, total)
+    }
+}
+
+// Clients with highest values, ties are broken by lower client ID
+#[derive(Debug, Default, PartialEq)]
+pub struct TopClients {
+    pub by_total: Vec<(ClientID, Money)>,
+    pub by_held: Vec<(ClientID, Money)>,
+    pub by_transactions: Vec<(ClientID, u32)>,
+}
+
+impl fmt::Display for TopClients {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |list: Vec<String>| list.join(", ");
+        writeln!(f, "Top by total: {}", join(self.by_total.iter().map(|(c, v)| format!("{}={}", c, v.format_fixed(4))).collect()))?;
+        writeln!(f, "Top by held: {}", join(self.by_held.iter().map(|(c, v)| format!("{}={}", c, v.format_fixed(4))).collect()))?;
+        write!(f, "Top by transactions: {}", join(self.by_transactions.iter().map(|(c, v)| format!("{}={}", c, v)).collect()))
+    }
+}
+
+struct Ranked<T> {
+    value: T,
+    client: ClientID,
+}
+
+impl<T: PartialOrd> Ord for Ranked<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.value.partial_cmp(&other.value).unwrap_or(Ordering::Equal)
+            .then_with(|| other.client.cmp(&self.client))
+    }
+}
+
+impl<T: PartialOrd> PartialOrd for Ranked<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: PartialOrd> PartialEq for Ranked<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: PartialOrd> Eq for Ranked<T> {}
+
+// Selects n highest values with a bounded min-heap, without sorting all clients
+fn top_n<T: PartialOrd>(items: impl Iterator<Item = (ClientID, T)>, n: usize) -> Vec<(ClientID, T)> {
+    if n == 0 {
+        return Vec::new();
+    }
+    let mut heap = BinaryHeap::with_capacity(n + 1);
+    for (client, value) in items {
+        heap.push(Reverse(Ranked { value, client }));
+        if heap.len() > n {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec().into_iter().map(|Reverse(ranked)| (ranked.client, ranked.value)).collect()
+}
+
+// Thresholds of suspicious-activity report, client is flagged when any is exceeded
+#[derive(Debug, Default, Clone)]
+pub struct SarThresholds {
+    pub max_chargebacks: Option<u32>,
+    // Disputes relative to deposits
+    pub max_dispute_rate: Option<f64>,
+    pub max_insufficient_funds: Option<u32>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct SarEntry {
+    pub client: ClientID,
+    pub deposits: u32,
+    pub disputes: u32,
+    pub chargebacks: u32,
+    pub insufficient_funds: u32,
+    pub dispute_rate: f64,
+    // Triggered rules, separated by ";"
+    pub reasons: String,
+}
+
+// Effect of an applied transaction, written as one NDJSON line to the event log
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Event {
+    pub seq: u64,
+    pub kind: TransactionType,
+    pub client: ClientID,
+    pub tx: TransactionID,
+    pub amount: Money,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+    // Cause of the client's lock, see Client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_by: Option<TransactionID>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locked_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_reason: Option<LockReason>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+}
+
+struct EventLog {
+    writer: Box<dyn Write>,
+    seq: u64,
+    // Writer is flushed after this many events, otherwise only at the end of input
+    flush_interval: Option<u64>,
+    // First write error, reported by the processing loop
+    error: Option<std::io::Error>,
+}
+
+// Counters of a processing run
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ProcessingReport {
+    // Data records read from input, including malformed ones
+    pub records: u64,
+    // Malformed records skipped
+    pub parse_errors: u64,
+    // Rows of unknown transaction type, skipped unless config.unknown_types fails the run
+    pub unknown_types: u64,
+    // Repeats of applied deposits/withdrawals identical to them, e.g. from overlapping daily files, skipped
+    pub duplicates_identical: u64,
+    // Rows outside config.since/until window, skipped
+    pub outside_window: u64,
+    // Amounts without decimal point, counted when config.warn_integer_amounts is set
+    pub integer_amounts: u64,
+    // Byte offset after the last record read
+    pub end_byte: u64,
+    // Rows applied to accounts, deferred ones included once applied
+    pub applied: u64,
+    // Outcome of rows carrying batch column, by batch
+    pub batches: BTreeMap<String, BatchCounts>,
+    // Rejected rows of the whole input by RejectReason::code
+    pub rejected: BTreeMap<&'static str, u64>,
+    // Input had more rows than config.limit_rows allowed
+    pub truncated: bool,
+    // Rounding rule in effect, for the record
+    pub rounding: Rounding,
+}
+
+// Rows of one batch, malformed rows can't be attributed to batch and are not counted here
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BatchCounts {
+    pub rows: u64,
+    pub applied: u64,
+    // Rejected rows by RejectReason::code
+    pub rejected: BTreeMap<&'static str, u64>,
+}
+
+impl BatchCounts {
+    pub fn rejected_total(&self) -> u64 {
+        self.rejected.values().sum()
+    }
+}
+
+impl ProcessingReport {
+    fn record_outcome(&mut self, batch: Option<&str>, result: &Result<(), RejectReason>) {
+        match result {
+            Ok(()) => self.applied += 1,
+            Err(reason) => *self.rejected.entry(reason.code()).or_default() += 1,
+        }
+        let Some(batch) = batch else {
+            return;
+        };
+        let counts = self.batches.entry(batch.to_string()).or_default();
+        match result {
+            Ok(()) => counts.applied += 1,
+            Err(reason) => *counts.rejected.entry(reason.code()).or_default() += 1,
+        }
+    }
+
+    // Adds counters of a run over the next input file, end_byte is the one of the last file
+    pub fn merge(&mut self, other: ProcessingReport) {
+        self.records += other.records;
+        self.parse_errors += other.parse_errors;
+        self.unknown_types += other.unknown_types;
+        self.applied += other.applied;
+        self.duplicates_identical += other.duplicates_identical;
+        self.outside_window += other.outside_window;
+        self.integer_amounts += other.integer_amounts;
+        self.end_byte = other.end_byte;
+        for (batch, counts) in other.batches {
+            let total = self.batches.entry(batch).or_default();
+            total.rows += counts.rows;
+            total.applied += counts.applied;
+            for (code, count) in counts.rejected {
+                *total.rejected.entry(code).or_default() += count;
+            }
+        }
+        for (code, count) in other.rejected {
+            *self.rejected.entry(code).or_default() += count;
+        }
+        self.truncated |= other.truncated;
+    }
+
+    // Rejection counts, most frequent first and ties by name, e.g. "32 insufficient_funds, 4 unknown_transaction"
+    pub fn rejection_summary(&self) -> String {
+        let mut counts: Vec<(&str, u64)> = self.rejected.iter().map(|(code, count)| (*code, *count)).collect();
+        counts.sort_by_key(|(_, count)| Reverse(*count));
+        counts.iter().map(|(code, count)| format!("{} {}", count, code)).collect::<Vec<_>>().join(", ")
+    }
+
+    // One row per batch, with a column for every rejection reason
+    pub fn write_batch_report<W: Write>(&self, writer: W) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        let mut header = vec!["batch", "rows", "applied", "rejected"];
+        header.extend_from_slice(RejectReason::CODES);
+        wtr.write_record(&header)?;
+        for (batch, counts) in &self.batches {
+            let mut row = vec![batch.clone(), counts.rows.to_string(), counts.applied.to_string(), counts.rejected_total().to_string()];
+            row.extend(RejectReason::CODES.iter().map(|code| counts.rejected.get(code).copied().unwrap_or(0).to_string()));
+            wtr.write_record(&row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+// Why a well-formed transaction was not applied
+#[derive(Debug, Clone, PartialEq)]
+pub enum RejectReason {
+    // Deposit/Withdrawal without amount column
+    NoAmount,
+    // Deposit/Withdrawal with empty amount field
+    EmptyAmount,
+    // Deposit would take client balance over configured cap
+    OverMaxBalance { max_balance: Money },
+    // Amount outside of configured min/max amount
+    AmountOutOfRange { amount: Money },
+    // Available would not stay above -overdraft_limit
+    InsufficientFunds { overdraft_limit: Money },
+    // Dispute/Resolve/Chargeback on transaction not seen or not applied
+    UnknownTransaction { tx: TransactionID },
+    RolledBack { tx: TransactionID },
+    ClientMismatch { tx: TransactionID },
+    NotDeposit { tx: TransactionID },
+    AlreadyDisputed { tx: TransactionID },
+    NotDisputed { tx: TransactionID },
+    ClientNotFound { client: ClientID },
+    // Resolve/Chargeback would release more than client holds
+    OverRelease { held: Money, requested: Money },
+    // Stored revertable transaction has no amount, only deposits/withdrawals with amount are stored
+    MissingAmount { tx: TransactionID },
+    RollbackRefused(RollbackError),
+    // Type excluded by config.allowed_types
+    TypeNotAllowed { tr_type: TransactionType },
+    // Deposit/Withdrawal reusing tx of an applied one with different fields
+    DuplicateTransaction { tx: TransactionID },
+    // Dispute while client has config.max_open_disputes open ones
+    TooManyOpenDisputes { open: u32 },
+    // Balance move beyond the range of the money backend
+    BalanceOverflow,
+    // Dispute/Resolve/Chargeback on deposit evicted after retention period
+    Expired { tx: TransactionID },
+    // Deposit or withdrawal refused by config.disputable custom rule
+    PolicyRefused { tx: TransactionID },
+    // Dispute of a resolved deposit beyond config.redispute
+    RedisputeNotAllowed { tx: TransactionID, disputes: u32 },
+    // Deposit/Withdrawal over config.max_transactions_per_client
+    TooManyTransactions { limit: u32 },
+    // Withdrawal taking client's withdrawn sum over config.max_withdrawn_per_client
+    OverWithdrawalLimit { limit: Money },
+    // Transaction type frozen on locked account by config.lock_policy
+    AccountLocked { client: ClientID },
+    // Dispute of deposit or withdrawal reversed by chargeback before
+    AlreadyChargedBack { tx: TransactionID },
+}
+
+impl RejectReason {
+    // All values of code(), in report column order, new ones are appended
+    pub const CODES: &'static [&'static str] = &[
+        "no_amount",
+        "over_max_balance",
+        "insufficient_funds",
+        "unknown_transaction",
+        "rolled_back",
+        "client_mismatch",
+        "not_deposit",
+        "already_disputed",
+        "not_disputed",
+        "client_not_found",
+        "over_release",
+        "missing_amount",
+        "rollback_refused",
+        "amount_out_of_range",
+        "type_not_allowed",
+        "duplicate_transaction",
+        "empty_amount",
+        "too_many_open_disputes",
+        "balance_overflow",
+        "expired",
+        "too_many_transactions",
+        "over_withdrawal_limit",
+        "redispute_not_allowed",
+        "policy_refused",
+        "account_locked",
+        "already_charged_back",
+    ];
+
+    // Stable identifier for reports
+    pub fn code(&self) -> &'static str {
+        match self {
+            RejectReason::NoAmount => "no_amount",
+            RejectReason::EmptyAmount => "empty_amount",
+            RejectReason::OverMaxBalance { .. } => "over_max_balance",
+            RejectReason::AmountOutOfRange { .. } => "amount_out_of_range",
+            RejectReason::InsufficientFunds { .. } => "insufficient_funds",
+            RejectReason::UnknownTransaction { .. } => "unknown_transaction",
+            RejectReason::RolledBack { .. } => "rolled_back",
+            RejectReason::ClientMismatch { .. } => "client_mismatch",
+            RejectReason::NotDeposit { .. } => "not_deposit",
+            RejectReason::AlreadyDisputed { .. } => "already_disputed",
+            RejectReason::NotDisputed { .. } => "not_disputed",
+            RejectReason::ClientNotFound { .. } => "client_not_found",
+            RejectReason::OverRelease { .. } => "over_release",
+            RejectReason::MissingAmount { .. } => "missing_amount",
+            RejectReason::RollbackRefused(_) => "rollback_refused",
+            RejectReason::TypeNotAllowed { .. } => "type_not_allowed",
+            RejectReason::DuplicateTransaction { .. } => "duplicate_transaction",
+            RejectReason::TooManyOpenDisputes { .. } => "too_many_open_disputes",
+            RejectReason::BalanceOverflow => "balance_overflow",
+            RejectReason::Expired { .. } => "expired",
+            RejectReason::TooManyTransactions { .. } => "too_many_transactions",
+            RejectReason::OverWithdrawalLimit { .. } => "over_withdrawal_limit",
+            RejectReason::RedisputeNotAllowed { .. } => "redispute_not_allowed",
+            RejectReason::PolicyRefused { .. } => "policy_refused",
+            RejectReason::AccountLocked { .. } => "account_locked",
+            RejectReason::AlreadyChargedBack { .. } => "already_charged_back",
+        }
+    }
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RejectReason::NoAmount => write!(f, "amount missing"),
+            RejectReason::EmptyAmount => write!(f, "amount empty"),
+            RejectReason::OverMaxBalance { max_balance } => write!(f, "balance would exceed {}", max_balance),
+            RejectReason::AmountOutOfRange { amount } => write!(f, "amount {} out of allowed range", amount),
+            RejectReason::InsufficientFunds { overdraft_limit } if overdraft_limit.is_zero() => write!(f, "insufficient funds"),
+            RejectReason::InsufficientFunds { overdraft_limit } => write!(f, "insufficient funds, overdraft limit {}", overdraft_limit),
+            RejectReason::UnknownTransaction { tx } => write!(f, "transaction {} is unknown", tx),
+            RejectReason::RolledBack { tx } => write!(f, "transaction {} is rolled back", tx),
+            RejectReason::ClientMismatch { tx } => write!(f, "transaction {} belongs to another client", tx),
+            RejectReason::NotDeposit { tx } => write!(f, "transaction {} is not a deposit", tx),
+            RejectReason::AlreadyDisputed { tx } => write!(f, "transaction {} is already disputed", tx),
+            RejectReason::NotDisputed { tx } => write!(f, "transaction {} is not disputed", tx),
+            RejectReason::ClientNotFound { client } => write!(f, "client {} not found", client),
+            RejectReason::OverRelease { held, requested } => write!(f, "release of {} exceeds held {}", requested, held),
+            RejectReason::MissingAmount { tx } => write!(f, "stored transaction {} has no amount", tx),
+            RejectReason::RollbackRefused(err) => write!(f, "rollback refused, {}", err),
+            RejectReason::TypeNotAllowed { tr_type } => write!(f, "type {} is not allowed", tr_type.name()),
+            RejectReason::DuplicateTransaction { tx } => write!(f, "transaction {} was already applied with other fields", tx),
+            RejectReason::TooManyOpenDisputes { open } => write!(f, "client already has {} open disputes", open),
+            RejectReason::BalanceOverflow => write!(f, "balance would overflow"),
+            RejectReason::Expired { tx } => write!(f, "transaction {} is past retention period", tx),
+            RejectReason::TooManyTransactions { limit } => write!(f, "client is over {} transactions", limit),
+            RejectReason::OverWithdrawalLimit { limit } => write!(f, "withdrawals would exceed {}", limit),
+            RejectReason::PolicyRefused { tx } => write!(f, "transaction {} is refused by dispute rule", tx),
+            RejectReason::RedisputeNotAllowed { tx, disputes } => write!(f, "re-dispute of transaction {} not allowed, disputed before: {}", tx, disputes),
+            RejectReason::AccountLocked { client } => write!(f, "account of client {} is locked", client),
+            RejectReason::AlreadyChargedBack { tx } => write!(f, "transaction {} is already charged back", tx),
+        }
+    }
+}
+
+// New balance of a checked add/sub, the caller assigns it only once all moves of a transaction fit
+fn checked_balance(balance: Option<Money>, tr: &Transaction) -> Result<Money, RejectReason> {
+    balance.ok_or_else(|| {
+        warn!("Balance would overflow: {:?}", tr);
+        RejectReason::BalanceOverflow
+    })
+}
+
+// Amount of stored transaction referenced by Dispute/Resolve/Chargeback
+pub fn stored_amount(original_tr: &Transaction) -> Result<Money, RejectReason> {
+    original_tr.amount.clone().ok_or(RejectReason::MissingAmount { tx: original_tr.tx })
+}
+
+fn close_dispute(open_disputes: &mut HashMap<ClientID, BTreeMap<TransactionID, Money>>, client: ClientID, tx: TransactionID) {
+    if let Some(open) = open_disputes.get_mut(&client) {
+        open.remove(&tx);
+        if open.is_empty() {
+            open_disputes.remove(&client);
+        }
+    }
+}
+
+// Shared bounds check for paths releasing held funds (resolve, chargeback)
+pub fn validate_release(held_remaining: &Money, requested: &Money) -> Result<(), RejectReason> {
+    if requested > held_remaining {
+        return Err(RejectReason::OverRelease { held: held_remaining.clone(), requested: requested.clone() });
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollbackEffect {
+    // Deposit amount removed from client available and total
+    Deposit { client: ClientID, amount: Money },
+    // Withdrawal amount returned to client available and total
+    Withdrawal { client: ClientID, amount: Money },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RollbackError {
+    UnknownTransaction(TransactionID),
+    AlreadyRolledBack(TransactionID),
+    Disputed(TransactionID),
+    ChargedBack(TransactionID),
+    // Reversal would take a balance beyond the range of the money backend
+    Overflow(TransactionID),
+    // Deposit was already spent, reversal would take available below the overdraft limit
+    InsufficientFunds(TransactionID),
+}
+
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackError::UnknownTransaction(tx) => write!(f, "transaction {} is unknown or was not applied", tx),
+            RollbackError::AlreadyRolledBack(tx) => write!(f, "transaction {} is already rolled back", tx),
+            RollbackError::Disputed(tx) => write!(f, "transaction {} is disputed", tx),
+            RollbackError::ChargedBack(tx) => write!(f, "transaction {} is charged back", tx),
+            RollbackError::Overflow(tx) => write!(f, "reversing transaction {} would overflow a balance", tx),
+            RollbackError::InsufficientFunds(tx) => write!(f, "reversing transaction {} would overdraw available funds", tx),
+        }
+    }
+}
+
+impl std::error::Error for RollbackError {}
+
+#[derive(Debug, PartialEq)]
+pub struct ReplayError {
+    // Sequence number of the offending event (expected one if event can't be parsed)
+    pub seq: u64,
+    pub message: String,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "event {}: {}", self.seq, self.message)
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+// Client balances by ID, hashed by default; ordered with ModelConfig::ordered_clients, so reads
+// in client order need no sort at the cost of slower inserts
+enum ClientTable {
+    Hashed(HashMap<ClientID, Client>),
+    Ordered(BTreeMap<ClientID, Client>),
+}
+
+impl Default for ClientTable {
+    fn default() -> Self {
+        ClientTable::Hashed(HashMap::new())
+    }
+}
+
+impl ClientTable {
+    fn new(ordered: bool) -> Self {
+        if ordered { ClientTable::Ordered(BTreeMap::new()) } else { ClientTable::default() }
+    }
+
+    fn get(&self, id: &ClientID) -> Option<&Client> {
+        match self {
+            ClientTable::Hashed(clients) => clients.get(id),
+            ClientTable::Ordered(clients) => clients.get(id),
+        }
+    }
+
+    fn get_mut(&mut self, id: &ClientID) -> Option<&mut Client> {
+        match self {
+            ClientTable::Hashed(clients) => clients.get_mut(id),
+            ClientTable::Ordered(clients) => clients.get_mut(id),
+        }
+    }
+
+    fn get_or_insert(&mut self, id: ClientID) -> &mut Client {
+        match self {
+            ClientTable::Hashed(clients) => clients.entry(id).or_insert_with(|| Client::new(id)),
+            ClientTable::Ordered(clients) => clients.entry(id).or_insert_with(|| Client::new(id)),
+        }
+    }
+
+    fn insert(&mut self, id: ClientID, client: Client) {
+        match self {
+            ClientTable::Hashed(clients) => clients.insert(id, client),
+            ClientTable::Ordered(clients) => clients.insert(id, client),
+        };
+    }
+
+    fn remove(&mut self, id: &ClientID) {
+        match self {
+            ClientTable::Hashed(clients) => clients.remove(id),
+            ClientTable::Ordered(clients) => clients.remove(id),
+        };
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ClientTable::Hashed(clients) => clients.len(),
+            ClientTable::Ordered(clients) => clients.len(),
+        }
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        if let ClientTable::Hashed(clients) = self {
+            clients.reserve(additional);
+        }
+    }
+
+    // In no particular order
+    fn values(&self) -> Box<dyn Iterator<Item = &Client> + '_> {
+        match self {
+            ClientTable::Hashed(clients) => Box::new(clients.values()),
+            ClientTable::Ordered(clients) => Box::new(clients.values()),
+        }
+    }
+
+    // Ordered by client ID, sorted only when hashed
+    fn sorted(&self) -> Vec<&Client> {
+        match self {
+            ClientTable::Hashed(clients) => {
+                let mut sorted: Vec<&Client> = clients.values().collect();
+                sorted.sort_by_key(|client| client.client);
+                sorted
+            }
+            ClientTable::Ordered(clients) => clients.values().collect(),
+        }
+    }
+}
+
+// Revertable transactions, kept in memory or spilled to disk when memory cap is reached
+#[derive(Default)]
+struct TransactionStore {
+    memory: HashMap<TransactionID, Transaction>,
+    spill: Option<SpillFile>,
+    // First IO error, reported by the processing loop
+    error: Option<std::io::Error>,
+}
+
+// Append-only NDJSON file with in-memory index of record offsets, removed on drop
+struct SpillFile {
+    path: PathBuf,
+    file: std::fs::File,
+    index: HashMap<TransactionID, u64>,
+    memory_cap: usize,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl TransactionStore {
+    fn get(&mut self, tx: TransactionID) -> Option<Transaction> {
+        if let Some(tr) = self.memory.get(&tx) {
+            return Some(tr.clone());
+        }
+        let spill = self.spill.as_mut()?;
+        let offset = *spill.index.get(&tx)?;
+        match spill.read(offset) {
+            Ok(tr) => Some(tr),
+            Err(err) => {
+                self.error.get_or_insert(err);
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, tr: Transaction) {
+        if let Some(spill) = self.spill.as_mut() {
+            spill.index.remove(&tr.tx);
+            if self.memory.len() >= spill.memory_cap
+                && let Err(err) = spill.append(self.memory.drain())
+            {
+                self.error.get_or_insert(err);
+            }
+        }
+        self.memory.insert(tr.tx, tr);
+    }
+
+    fn contains(&self, tx: TransactionID) -> bool {
+        self.memory.contains_key(&tx) || self.spill.as_ref().is_some_and(|spill| spill.index.contains_key(&tx))
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len() + self.spill.as_ref().map_or(0, |spill| spill.index.len())
+    }
+
+    fn remove(&mut self, tx: TransactionID) {
+        self.memory.remove(&tx);
+        if let Some(spill) = self.spill.as_mut() {
+            spill.index.remove(&tx);
+        }
+    }
+
+    // All stored transactions including spilled ones, ordered by tx
+    fn all(&mut self) -> std::io::Result<Vec<Transaction>> {
+        let mut transactions: Vec<Transaction> = self.memory.values().cloned().collect();
+        if let Some(spill) = self.spill.as_mut() {
+            let offsets: Vec<u64> = spill.index.values().copied().collect();
+            for offset in offsets {
+                transactions.push(spill.read(offset)?);
+            }
+        }
+        transactions.sort_by_key(|tr| tr.tx);
+        Ok(transactions)
+    }
+}
+
+// Numbers spill files of one process, so models sharing a spill dir don't overwrite or delete each other's
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+impl SpillFile {
+    fn create(dir: &Path, memory_cap: usize) -> std::io::Result<Self> {
+        loop {
+            let number = SPILL_FILES.fetch_add(1, AtomicOrdering::Relaxed);
+            let path = dir.join(format!("revertable-{}-{}.ndjson", std::process::id(), number));
+            match std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok(SpillFile { path, file, index: HashMap::new(), memory_cap }),
+                // Left over by a crashed process which had the same pid, not ours to take
+                Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn append(&mut self, transactions: impl Iterator<Item = (TransactionID, Transaction)>) -> std::io::Result<()> {
+        let mut offset = self.file.seek(SeekFrom::End(0))?;
+        let mut writer = std::io::BufWriter::new(&self.file);
+        for (tx, tr) in transactions {
+            let mut line = serde_json::to_vec(&tr)?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+            self.index.insert(tx, offset);
+            offset += line.len() as u64;
+        }
+        writer.flush()
+    }
+
+    fn read(&mut self, offset: u64) -> std::io::Result<Transaction> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        BufReader::new(&self.file).read_line(&mut line)?;
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+impl Model {
+    pub fn with_config(config: ModelConfig) -> Self {
+        Model {
+            clients: ClientTable::new(config.ordered_clients),
+            config,
+            revertable_transactions: TransactionStore::default(),
+            disputed_transactions: HashSet::new(),
+            open_disputes: HashMap::new(),
+            dispute_counts: HashMap::new(),
+            charged_back_transactions: HashSet::new(),
+            rolled_back_transactions: HashSet::new(),
+            events: None,
+            counters: HashMap::new(),
+            max_tx: None,
+            records_read: 0,
+            resume_skip: 0,
+            input_offsets: HashMap::new(),
+            pending_deposits: VecDeque::new(),
+            transactions_seen: 0,
+            retention_by_count: VecDeque::new(),
+            retention_by_age: BTreeSet::new(),
+            expired_transactions: HashSet::new(),
+            expired_available: HashMap::new(),
+            latest_timestamp: None,
+            snapshot_repairs: Vec::new(),
+            type_counts: BTreeMap::new(),
+            metrics: Arc::default(),
+            undo: None,
+            velocity: HashMap::new(),
+        }
+    }
+
+    // Default config with room for expected_clients clients, so the maps don't rehash while growing
+    pub fn with_capacity(expected_clients: usize) -> Self {
+        let mut model = Model::default();
+        model.reserve_clients(expected_clients);
+        model
+    }
+
+    // Capacity hint for clients not yet in model, e.g. after loading snapshot
+    pub fn reserve_clients(&mut self, expected_clients: usize) {
+        self.clients.reserve(expected_clients.saturating_sub(self.clients.len()));
+        self.counters.reserve(expected_clients.saturating_sub(self.counters.len()));
+    }
+
+    // Spills revertable transactions to a file in dir once more than memory_cap are held in memory
+    pub fn set_spill_dir(&mut self, dir: &Path, memory_cap: usize) -> std::io::Result<()> {
+        self.revertable_transactions.spill = Some(SpillFile::create(dir, memory_cap)?);
+        Ok(())
+    }
+
+    // Enables event log, writer should flush per line (e.g. LineWriter) for incremental output
+    pub fn set_events_writer(&mut self, writer: Box<dyn Write>) {
+        self.events = Some(EventLog { writer, seq: 0, flush_interval: None, error: None });
+    }
+
+    // Event log for buffered writer, flushed every flush_interval events so a tailing consumer
+    // sees updates while a long input is processed
+    pub fn set_buffered_events_writer(&mut self, writer: Box<dyn Write>, flush_interval: u64) {
+        self.events = Some(EventLog { writer, seq: 0, flush_interval: Some(flush_interval.max(1)), error: None });
+    }
+
+    fn emit_event(&mut self, kind: TransactionType, client: ClientID, tx: TransactionID, amount: Money, batch: Option<&str>) {
+        let (Some(events), Some(client)) = (self.events.as_mut(), self.clients.get(&client)) else {
+            return;
+        };
+        if events.error.is_some() {
+            return;
+        }
+
+        events.seq += 1;
+        let event = Event {
+            seq: events.seq,
+            kind,
+            client: client.client,
+            tx,
+            amount,
+            available: client.available.clone(),
+            held: client.held.clone(),
+            total: client.total.clone(),
+            locked: client.locked,
+            locked_by: client.locked_by,
+            locked_at: client.locked_at,
+            lock_reason: client.lock_reason,
+            batch: batch.map(str::to_string),
+        };
+        let result = serde_json::to_writer(&mut events.writer, &event)
+            .map_err(std::io::Error::from)
+            .and_then(|_| events.writer.write_all(b"\n"))
+            .and_then(|_| match events.flush_interval {
+                Some(interval) if events.seq % interval == 0 => events.writer.flush(),
+                _ => Ok(()),
+            });
+        if let Err(err) = result {
+            events.error = Some(err);
+        }
+    }
+
+    fn check_errors(&mut self) -> std::io::Result<()> {
+        if let Some(err) = self.revertable_transactions.error.take() {
+            return Err(err);
+        }
+        match self.events.as_mut() {
+            Some(events) => match events.error.take() {
+                Some(err) => Err(err),
+                None => events.writer.flush(),
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn client(&self, id: ClientID) -> Option<&Client> {
+        self.clients.get(&id)
+    }
+
+    // Read-only balance accessors, None for unknown client
+    pub fn available(&self, id: ClientID) -> Option<Money> {
+        self.client(id).map(|client| client.available.clone())
+    }
+
+    pub fn held(&self, id: ClientID) -> Option<Money> {
+        self.client(id).map(|client| client.held.clone())
+    }
+
+    pub fn pending(&self, id: ClientID) -> Option<Money> {
+        self.client(id).map(|client| client.pending.clone())
+    }
+
+    pub fn total(&self, id: ClientID) -> Option<Money> {
+        self.client(id).map(|client| client.total.clone())
+    }
+
+    pub fn is_locked(&self, id: ClientID) -> Option<bool> {
+        self.client(id).map(|client| client.locked)
+    }
+
+    // Currently disputed transactions, in no particular order
+    pub fn disputed(&self) -> impl Iterator<Item = TransactionID> + '_ {
+        self.disputed_transactions.iter().copied()
+    }
+
+    pub fn snapshot_repairs(&self) -> &[TotalRepair] {
+        &self.snapshot_repairs
+    }
+
+    pub fn max_tx(&self) -> Option<TransactionID> {
+        self.max_tx
+    }
+
+    pub fn input_offset(&self, path: &str) -> Option<&InputOffset> {
+        self.input_offsets.get(path)
+    }
+
+    // Processes file skipping the prefix applied by an earlier run (restored from snapshot),
+    // refuses to continue if that prefix has changed since
+    pub fn process_file_resumable(&mut self, path: &str) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        let skip = match self.input_offsets.get(path) {
+            Some(offset) => {
+                if hash_prefix(path, offset.bytes)?.as_ref() != Some(&offset.sha256) {
+                    return Err(format!("Input {} changed since offset {} was recorded", path, offset.bytes).into());
+                }
+                offset.clone()
+            }
+            None => InputOffset { records: 0, bytes: 0, sha256: String::new() },
+        };
+
+        let source = SkipRecords::new(self.csv_source(open_reader(open_input(path)?)?), skip.records);
+        let report = self.process_source(source)?;
+
+        let bytes = report.end_byte.max(skip.bytes);
+        let sha256 = hash_prefix(path, bytes)?.ok_or_else(|| format!("Input {} truncated while processing", path))?;
+        self.input_offsets.insert(path.to_string(), InputOffset { records: skip.records + report.records, bytes, sha256 });
+        Ok(report)
+    }
+
+    pub fn write_snapshot<W: Write>(&mut self, writer: W) -> Result<(), Box<dyn std::error::Error>> {
+        let clients: Vec<Client> = self.clients.sorted().into_iter().cloned().collect();
+        let sorted = |set: &HashSet<TransactionID>| {
+            let mut ids: Vec<TransactionID> = set.iter().copied().collect();
+            ids.sort();
+            ids
+        };
+        let mut counters: Vec<(ClientID, ClientCounters)> = self.counters.iter().map(|(&id, c)| (id, c.clone())).collect();
+        counters.sort_by_key(|(id, _)| *id);
+
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            clients,
+            transactions: self.revertable_transactions.all()?,
+            disputed: sorted(&self.disputed_transactions),
+            charged_back: sorted(&self.charged_back_transactions),
+            rolled_back: sorted(&self.rolled_back_transactions),
+            counters,
+            max_tx: self.max_tx,
+            records_read: Some(self.records_read),
+            input_offsets: {
+                let mut offsets: Vec<(String, InputOffset)> = self.input_offsets.iter().map(|(p, o)| (p.clone(), o.clone())).collect();
+                offsets.sort_by(|a, b| a.0.cmp(&b.0));
+                offsets
+            },
+            pending_deposits: self.pending_deposits.iter().cloned().collect(),
+            transactions_seen: self.transactions_seen,
+            retention_queue: self
+                .retention_by_count
+                .iter()
+                .cloned()
+                .chain(self.retention_by_age.iter().map(|&(at, tx)| RetainedDeposit { tx, expiry: Expiry::At(at) }))
+                .collect(),
+            expired: sorted(&self.expired_transactions),
+            expired_available: {
+                let mut expired: Vec<(ClientID, Money)> = self.expired_available.iter().map(|(&id, amount)| (id, amount.clone())).collect();
+                expired.sort_by_key(|(id, _)| *id);
+                expired
+            },
+            latest_timestamp: self.latest_timestamp,
+            dispute_counts: {
+                let mut counts: Vec<(TransactionID, u32)> = self.dispute_counts.iter().map(|(&tx, &count)| (tx, count)).collect();
+                counts.sort();
+                counts
+            },
+        };
+        serde_json::to_writer(writer, &snapshot)?;
+        Ok(())
+    }
+
+    pub fn from_snapshot<R: Read>(config: ModelConfig, reader: R) -> Result<Model, Box<dyn std::error::Error>> {
+        let mut snapshot: Snapshot = serde_json::from_reader(BufReader::new(reader))?;
+        if !SNAPSHOT_VERSIONS_READ.contains(&snapshot.version) {
+            return Err(format!("Unsupported snapshot version: {}", snapshot.version).into());
+        }
+
+        // Hand-edited or corrupted snapshot would silently carry wrong balances forward
+        let mut repairs = Vec::new();
+        for client in snapshot.clients.iter_mut().filter(|client| !client.check_invariant()) {
+            if !config.repair_snapshot {
+                return Err(format!("Snapshot client {} breaks available + held + pending == total", client.client).into());
+            }
+            let total = &(&client.available + &client.held) + &client.pending;
+            let repair = TotalRepair { client: client.client, total: std::mem::replace(&mut client.total, total.clone()), repaired: total };
+            warn!("Repaired snapshot {}", repair);
+            repairs.push(repair);
+        }
+
+        let mut model = Model::with_config(config);
+        model.snapshot_repairs = repairs;
+        for client in snapshot.clients {
+            if client.locked {
+                model.metrics.locked.fetch_add(1, AtomicOrdering::Relaxed);
+            }
+            model.clients.insert(client.client, client);
+        }
+        for tr in snapshot.transactions {
+            model.revertable_transactions.insert(tr);
+        }
+        for tx in snapshot.disputed {
+            if let Some(tr) = model.revertable_transactions.get(tx) {
+                model.open_disputes.entry(tr.client).or_default().insert(tx, tr.amount.unwrap_or_default());
+            }
+            model.disputed_transactions.insert(tx);
+        }
+        model.charged_back_transactions = snapshot.charged_back.into_iter().collect();
+        model.rolled_back_transactions = snapshot.rolled_back.into_iter().collect();
+        model.counters = snapshot.counters.into_iter().collect();
+        model.max_tx = snapshot.max_tx;
+        model.records_read = snapshot.records_read.unwrap_or_default();
+        if let Some(resume_from) = model.config.resume_from {
+            let Some(records_read) = snapshot.records_read else {
+                return Err("Snapshot has no record offset to resume from".into());
+            };
+            if snapshot.max_tx != Some(resume_from) {
+                let max_tx = snapshot.max_tx.map_or_else(|| "none".to_string(), |tx| tx.to_string());
+                return Err(format!("Resume from tx {} doesn't match snapshot, whose highest tx is {}", resume_from, max_tx).into());
+            }
+            model.resume_skip = records_read;
+        }
+        model.input_offsets = snapshot.input_offsets.into_iter().collect();
+        model.pending_deposits = snapshot.pending_deposits.into_iter().collect();
+        model.transactions_seen = snapshot.transactions_seen;
+        for deposit in snapshot.retention_queue {
+            model.retain_deposit(deposit);
+        }
+        model.expired_transactions = snapshot.expired.into_iter().collect();
+        model.expired_available = snapshot.expired_available.into_iter().collect();
+        model.latest_timestamp = snapshot.latest_timestamp;
+        model.dispute_counts = snapshot.dispute_counts.into_iter().collect();
+        Ok(model)
+    }
+
+    fn process_revertable_transaction(&mut self, tr: Transaction) -> Result<(), RejectReason> {
+        if self.revertable_transactions.contains(tr.tx)
+            || self.rolled_back_transactions.contains(&tr.tx)
+            || self.expired_transactions.contains(&tr.tx)
+        {
+            warn!("Transaction ID already used: {:?}", tr);
+            return Err(RejectReason::DuplicateTransaction { tx: tr.tx });
+        }
+        // Client appears in output even when its transactions are rejected
+        self.clients.get_or_insert(tr.client);
+        self.check_lock(&tr)?;
+
+        let Some(amount) = tr.amount.clone() else {
+            if tr.empty_amount {
+                warn!("Transaction amount empty: {:?}", tr);
+                return Err(RejectReason::EmptyAmount);
+            }
+            warn!("Transaction missing amount: {:?}", tr);
+            return Err(RejectReason::NoAmount);
+        };
+
+        if self.config.min_amount.as_ref().is_some_and(|min_amount| amount < *min_amount)
+            || self.config.max_amount.as_ref().is_some_and(|max_amount| amount > *max_amount)
+        {
+            warn!("Amount out of allowed range: {:?}", tr);
+            return Err(RejectReason::AmountOutOfRange { amount });
+        }
+        let client = self.clients.get_or_insert(tr.client);
+
+        let deposit = tr.tr_type == TransactionType::Deposit;
+        let change = if deposit { amount.clone() } else { -amount.clone() };
+        let available = checked_balance(client.available.checked_add(&change), &tr)?;
+        let total = checked_balance(client.total.checked_add(&change), &tr)?;
+        if let Some(max_balance) = &self.config.max_balance
+            && deposit
+            && (available > *max_balance || total > *max_balance)
+        {
+            warn!("Deposit exceeds max balance {}: {:?}", max_balance, tr);
+            return Err(RejectReason::OverMaxBalance { max_balance: max_balance.clone() });
+        }
+        let pending = match self.config.pending_delay {
+            Some(delay) if deposit => Some((checked_balance(client.pending.checked_add(&amount), &tr)?, delay)),
+            _ => None,
+        };
+        // Withdrawal may take available down to -overdraft_limit, a deposit only adds to it
+        if !deposit && available < -self.config.overdraft_limit.clone() {
+            info!("Insufficient funds for withdrawal: {:?}", tr);
+            self.counters.entry(tr.client).or_default().insufficient_funds += 1;
+            return Err(RejectReason::InsufficientFunds { overdraft_limit: self.config.overdraft_limit.clone() });
+        }
+        // Last check, so a transaction rejected for another reason doesn't count as a breach or lock the account
+        self.check_velocity(&tr, &amount)?;
+
+        let client = self.clients.get_or_insert(tr.client);
+        client.total = total;
+        match pending {
+            Some((pending, delay)) => {
+                client.pending = pending;
+                let release_at = self.transactions_seen + 1 + delay;
+                self.pending_deposits.push_back(PendingDeposit { tx: tr.tx, client: tr.client, amount: amount.clone(), release_at });
+            }
+            None => client.available = available,
+        }
+
+        self.record_velocity(&tr, &amount);
+        self.emit_event(tr.tr_type, tr.client, tr.tx, amount, tr.batch.as_deref());
+        self.counters.entry(tr.client).or_default().record(tr.tr_type);
+
+        if deposit && let Some(expiry) = self.deposit_expiry(tr.timestamp) {
+            self.retain_deposit(RetainedDeposit { tx: tr.tx, expiry });
+        }
+        // Only applied transactions are stored, so they can be disputed or rolled back
+        self.revertable_transactions.insert(tr);
+        Ok(())
+    }
+
+    // Lock state is checked only per config.lock_policy: by default a locked client's other deposits can still be
+    // disputed, resolved and charged back, so funds under investigation keep moving to and from held
+    fn process_dispute_resolve_chargeback(&mut self, tr: Transaction) -> Result<(), RejectReason> {
+        if self.rolled_back_transactions.contains(&tr.tx) {
+            warn!("Dispute/Resolve/Chargeback on rolled back transaction: {:?}", tr);
+            return Err(RejectReason::RolledBack { tx: tr.tx });
+        }
+        if self.expired_transactions.contains(&tr.tx) {
+            warn!("Dispute/Resolve/Chargeback on expired transaction: {:?}", tr);
+            return Err(RejectReason::Expired { tx: tr.tx });
+        }
+
+        let Some(original_tr) = self.revertable_transactions.get(tr.tx) else {
+            warn!("Dispute/Resolve/Chargeback on unknown transaction: {:?}", tr);
+            return Err(RejectReason::UnknownTransaction { tx: tr.tx });
+        };
+
+        if original_tr.client != tr.client {
+            warn!("Dispute/Resolve/Chargeback transaction client mismatch: {:?}, {:?}", tr, original_tr);
+            return Err(RejectReason::ClientMismatch { tx: tr.tx });
+        }
+        self.check_lock(&tr)?;
+        if !self.config.disputable.allows(&original_tr) {
+            if let Disputable::Custom(_) = self.config.disputable {
+                warn!("Dispute/Resolve/Chargeback refused by custom dispute rule: {:?}", tr);
+                return Err(RejectReason::PolicyRefused { tx: tr.tx });
+            }
+            warn!("Dispute/Resolve/Chargeback on non-deposit transaction: {:?}", tr);
+            return Err(RejectReason::NotDeposit { tx: tr.tx });
+        }
+        let withdrawal = original_tr.tr_type == TransactionType::Withdrawal;
+        // Charged back is terminal whatever config.redispute allows: its chargeback already moved the amount,
+        // another one would claw back a deposit or pay back a withdrawal twice
+        if self.charged_back_transactions.contains(&tr.tx) {
+            warn!("Dispute/Resolve/Chargeback on charged back transaction: {:?}", tr);
+            return Err(RejectReason::AlreadyChargedBack { tx: tr.tx });
+        }
+        if tr.tr_type == TransactionType::Dispute {
+            if self.disputed_transactions.contains(&tr.tx) {
+                warn!("Transaction already disputed: {:?}", tr);
+                return Err(RejectReason::AlreadyDisputed { tx: tr.tx });
+            }
+            // Not disputed now, so earlier disputes were resolved
+            let disputes = self.dispute_counts.get(&tr.tx).copied().unwrap_or(0);
+            if self.config.redispute.max_disputes().is_some_and(|max_disputes| disputes >= max_disputes) {
+                warn!("Re-dispute not allowed, disputed before: {}: {:?}", disputes, tr);
+                return Err(RejectReason::RedisputeNotAllowed { tx: tr.tx, disputes });
+            }
+            let open = self.open_disputes.get(&tr.client).map_or(0, |open| open.len() as u32);
+            if let Some(max_open_disputes) = self.config.max_open_disputes
+                && open >= max_open_disputes
+            {
+                warn!("Client already has {} open disputes: {:?}", open, tr);
+                return Err(RejectReason::TooManyOpenDisputes { open });
+            }
+        } else {
+            if !self.disputed_transactions.contains(&tr.tx) {
+                warn!("Resolve/Chargeback on non-disputed transaction: {:?}", tr);
+                return Err(RejectReason::NotDisputed { tx: tr.tx });
+            }
+        }
+
+        let amount = stored_amount(&original_tr).inspect_err(|reason| {
+            warn!("Dispute/Resolve/Chargeback rejected, {}: {:?}", reason, tr);
+        })?;
+
+        let Some(client) = self.clients.get_mut(&tr.client) else {
+            warn!("Client not found for Dispute/Resolve/Chargeback: {:?}", tr);
+            return Err(RejectReason::ClientNotFound { client: tr.client });
+        };
+        let pending = self.pending_deposits.iter().position(|deposit| deposit.tx == tr.tx);
+
+        if matches!(tr.tr_type, TransactionType::Resolve | TransactionType::Chargeback) {
+            validate_release(&client.held, &amount).inspect_err(|reason| {
+                warn!("Resolve/Chargeback rejected, {}: {:?}", reason, tr);
+            })?;
+        }
+
+        // Withdrawal dispute is the client's claim to get the amount back: dispute puts it in held, adding to total
+        // but not available; resolve drops the claim, taking it out of held and total, so balances are as before
+        // the dispute; chargeback pays it out of held into available, so available and total grow by the amount
+        // compared to before the dispute, and locks the account
+        match tr.tr_type {
+            TransactionType::Dispute if withdrawal => {
+                let held = checked_balance(client.held.checked_add(&amount), &tr)?;
+                client.total = checked_balance(client.total.checked_add(&amount), &tr)?;
+                client.held = held;
+                self.disputed_transactions.insert(tr.tx);
+                self.open_disputes.entry(tr.client).or_default().insert(tr.tx, amount.clone());
+                if self.config.redispute != Redispute::Allow {
+                    *self.dispute_counts.entry(tr.tx).or_default() += 1;
+                }
+            }
+            // Disputed pending deposit is held instead of settling, resolve makes it available
+            TransactionType::Dispute => {
+                let held = checked_balance(client.held.checked_add(&amount), &tr)?;
+                match pending {
+                    Some(index) => {
+                        client.pending = checked_balance(client.pending.checked_sub(&amount), &tr)?;
+                        self.pending_deposits.remove(index);
+                    }
+                    None => client.available = checked_balance(client.available.checked_sub(&amount), &tr)?,
+                }
+                client.held = held;
+                if client.available < -self.config.overdraft_limit.clone() {
+                    match self.config.negative_available {
+                        NegativeAvailable::Flag => warn!("Dispute leaves available negative: {:?}", tr),
+                        NegativeAvailable::Lock => {
+                            warn!("Dispute leaves available negative, locking account: {:?}", tr);
+                            client.lock(LockReason::NegativeAvailable, None);
+                        }
+                        NegativeAvailable::Ignore => {}
+                    }
+                }
+                self.disputed_transactions.insert(tr.tx);
+                self.open_disputes.entry(tr.client).or_default().insert(tr.tx, amount.clone());
+                if self.config.redispute != Redispute::Allow {
+                    *self.dispute_counts.entry(tr.tx).or_default() += 1;
+                }
+            }
+            TransactionType::Resolve => {
+                if withdrawal {
+                    client.total = checked_balance(client.total.checked_sub(&amount), &tr)?;
+                } else {
+                    client.available = checked_balance(client.available.checked_add(&amount), &tr)?;
+                }
+                client.held -= &amount;
+                self.disputed_transactions.remove(&tr.tx);
+                close_dispute(&mut self.open_disputes, tr.client, tr.tx);
+            }
+            TransactionType::Chargeback => {
+                if withdrawal || self.config.chargeback_semantics == ChargebackSemantics::Refund {
+                    client.available = checked_balance(client.available.checked_add(&amount), &tr)?;
+                } else {
+                    client.total = checked_balance(client.total.checked_sub(&amount), &tr)?;
+                }
+                client.held -= &amount;
+                self.disputed_transactions.remove(&tr.tx);
+                close_dispute(&mut self.open_disputes, tr.client, tr.tx);
+                self.charged_back_transactions.insert(tr.tx);
+                client.lock(LockReason::Chargeback, Some(&tr));
+            }
+            _ => unreachable!("Dispute/Resolve/Chargeback expected: {:?}", tr),
+        }
+
+        self.emit_event(tr.tr_type, tr.client, tr.tx, amount, tr.batch.as_deref());
+        self.counters.entry(tr.client).or_default().record(tr.tr_type);
+        Ok(())
+    }
+
+    // Reverses the balance effect of an applied deposit or withdrawal outside of the dispute flow.
+    // Disputed and charged back transactions are refused, and so is a deposit already spent beyond the overdraft limit;
+    // rolled back ones can't be disputed later.
+    pub fn rollback(&mut self, tx: TransactionID) -> Result<RollbackEffect, RollbackError> {
+        self.undo = None;
+        self.rollback_in_batch(tx, None)
+    }
+
+    fn rollback_in_batch(&mut self, tx: TransactionID, batch: Option<&str>) -> Result<RollbackEffect, RollbackError> {
+        if self.rolled_back_transactions.contains(&tx) {
+            return Err(RollbackError::AlreadyRolledBack(tx));
+        }
+        if self.disputed_transactions.contains(&tx) {
+            return Err(RollbackError::Disputed(tx));
+        }
+        if self.charged_back_transactions.contains(&tx) {
+            return Err(RollbackError::ChargedBack(tx));
+        }
+        let Some(original_tr) = self.revertable_transactions.get(tx) else {
+            return Err(RollbackError::UnknownTransaction(tx));
+        };
+        let (Some(amount), Some(client)) = (original_tr.amount, self.clients.get_mut(&original_tr.client)) else {
+            return Err(RollbackError::UnknownTransaction(tx));
+        };
+
+        let client_id = client.client;
+        let overflow = || RollbackError::Overflow(tx);
+        let effect = if original_tr.tr_type == TransactionType::Deposit {
+            let total = client.total.checked_sub(&amount).ok_or_else(overflow)?;
+            match self.pending_deposits.iter().position(|deposit| deposit.tx == tx) {
+                Some(index) => {
+                    client.pending = client.pending.checked_sub(&amount).ok_or_else(overflow)?;
+                    self.pending_deposits.remove(index);
+                }
+                None => {
+                    let available = client.available.checked_sub(&amount).ok_or_else(overflow)?;
+                    if available < -self.config.overdraft_limit.clone() {
+                        return Err(RollbackError::InsufficientFunds(tx));
+                    }
+                    client.available = available;
+                }
+            }
+            client.total = total;
+            RollbackEffect::Deposit { client: client_id, amount: amount.clone() }
+        } else {
+            let available = client.available.checked_add(&amount).ok_or_else(overflow)?;
+            client.total = client.total.checked_add(&amount).ok_or_else(overflow)?;
+            client.available = available;
+            RollbackEffect::Withdrawal { client: client_id, amount: amount.clone() }
+        };
+        self.emit_event(TransactionType::Rollback, client_id, tx, amount, batch);
+
+        self.revertable_transactions.remove(tx);
+        self.rolled_back_transactions.insert(tx);
+        Ok(effect)
+    }
+
+    fn process_rollback(&mut self, tr: Transaction) -> Result<(), RejectReason> {
+        if let Some(original_tr) = self.revertable_transactions.get(tr.tx)
+            && original_tr.client != tr.client
+        {
+            warn!("Rollback transaction client mismatch: {:?}, {:?}", tr, original_tr);
+            return Err(RejectReason::ClientMismatch { tx: tr.tx });
+        }
+        self.check_lock(&tr)?;
+
+        match self.rollback_in_batch(tr.tx, tr.batch.as_deref()) {
+            Ok(effect) => {
+                info!("Rolled back: {:?}", effect);
+                Ok(())
+            }
+            Err(err) => {
+                warn!("Rollback refused, {}: {:?}", err, tr);
+                Err(RejectReason::RollbackRefused(err))
+            }
+        }
+    }
+
+    fn process_transaction(&mut self, tr: Transaction) -> Result<(), RejectReason> {
+        let (tr_type, client) = (tr.tr_type, tr.client);
+        // Before image first, so the timestamp and evictions the transaction brings are recorded in it
+        self.undo = (self.config.undo_log && self.events.is_none()).then(|| self.before_image(&tr));
+        self.latest_timestamp = self.latest_timestamp.max(tr.timestamp);
+        self.expire_deposits();
+        let was_locked = self.is_locked(client) == Some(true);
+        let result = self.apply_transaction(tr);
+        if matches!(result, Err(RejectReason::BalanceOverflow | RejectReason::RollbackRefused(RollbackError::Overflow(_)))) {
+            self.record_overflow(client);
+        }
+        // After record_overflow, which may lock the account too
+        if !was_locked && self.is_locked(client) == Some(true) {
+            self.metrics.locked.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        self.count_outcome(tr_type, &result);
+        self.transactions_seen += 1;
+        self.release_pending();
+        // Rejected transaction may still have counted or locked, so the one before can't be undone either
+        if result.is_err() {
+            self.undo = None;
+        }
+        result
+    }
+
+    fn before_image(&mut self, tr: &Transaction) -> UndoEntry {
+        UndoEntry {
+            tr: tr.clone(),
+            clients: vec![(tr.client, self.clients.get(&tr.client).cloned())],
+            counters: self.counters.get(&tr.client).cloned(),
+            open_disputes: self.open_disputes.get(&tr.client).cloned(),
+            dispute_count: self.dispute_counts.get(&tr.tx).copied(),
+            stored: self.revertable_transactions.get(tr.tx),
+            disputed: self.disputed_transactions.contains(&tr.tx),
+            charged_back: self.charged_back_transactions.contains(&tr.tx),
+            rolled_back: self.rolled_back_transactions.contains(&tr.tx),
+            pending: self.pending_deposits.iter().position(|deposit| deposit.tx == tr.tx)
+                .map(|index| (index, self.pending_deposits[index].clone())),
+            released: Vec::new(),
+            velocity: self.velocity.get(&tr.client).cloned(),
+            max_tx: self.max_tx,
+            transactions_seen: self.transactions_seen,
+            records_read: self.records_read,
+            latest_timestamp: self.latest_timestamp,
+            expired: Vec::new(),
+        }
+    }
+
+    // Reverses the last applied transaction by restoring the state it changed, including pending deposits
+    // released after it, and returns it; needs ModelConfig::undo_log. Single level: nothing to undo right after an undo, a rejected
+    // transaction or a rollback() call, nor while writing events log, as written events can't be taken back.
+    // Metrics keep counting the transaction as processed.
+    pub fn undo_last(&mut self) -> Option<Transaction> {
+        let undo = self.undo.take()?;
+        let tx = undo.tr.tx;
+        for deposit in undo.released.into_iter().rev() {
+            self.pending_deposits.push_front(deposit);
+        }
+        if let Some(index) = self.pending_deposits.iter().position(|deposit| deposit.tx == tx) {
+            self.pending_deposits.remove(index);
+        }
+        if let Some((index, deposit)) = undo.pending {
+            self.pending_deposits.insert(index, deposit);
+        }
+
+        for (id, client) in undo.clients {
+            let was_locked = self.is_locked(id) == Some(true);
+            match client {
+                Some(client) => self.clients.insert(id, client),
+                None => self.clients.remove(&id),
+            }
+            if was_locked && self.is_locked(id) != Some(true) {
+                self.metrics.locked.fetch_sub(1, AtomicOrdering::Relaxed);
+            }
+        }
+        let client = undo.tr.client;
+        match undo.counters {
+            Some(counters) => self.counters.insert(client, counters),
+            None => self.counters.remove(&client),
+        };
+        match undo.open_disputes {
+            Some(open) => self.open_disputes.insert(client, open),
+            None => self.open_disputes.remove(&client),
+        };
+        match undo.dispute_count {
+            Some(count) => self.dispute_counts.insert(tx, count),
+            None => self.dispute_counts.remove(&tx),
+        };
+        match undo.velocity {
+            Some(velocity) => self.velocity.insert(client, velocity),
+            None => self.velocity.remove(&client),
+        };
+
+        // Deposit stored by the transaction is also last in count queue, or keyed by its own timestamp in age queue
+        if undo.stored.is_none() && self.revertable_transactions.contains(tx) {
+            if self.retention_by_count.back().is_some_and(|deposit| deposit.tx == tx) {
+                self.retention_by_count.pop_back();
+            }
+            if let Some(Expiry::At(at)) = self.deposit_expiry(undo.tr.timestamp) {
+                self.retention_by_age.remove(&(at, tx));
+            }
+        }
+        match undo.stored {
+            Some(tr) => self.revertable_transactions.insert(tr),
+            None => self.revertable_transactions.remove(tx),
+        }
+        for (set, member) in [
+            (&mut self.disputed_transactions, undo.disputed),
+            (&mut self.charged_back_transactions, undo.charged_back),
+            (&mut self.rolled_back_transactions, undo.rolled_back),
+        ] {
+            if member {
+                set.insert(tx);
+            } else {
+                set.remove(&tx);
+            }
+        }
+
+        // Evictions happened before the transaction, so undone after it
+        for expired in undo.expired.into_iter().rev() {
+            if let Some(requeued) = expired.requeued {
+                match requeued.expiry {
+                    Expiry::Seen(_) => {
+                        self.retention_by_count.pop_back();
+                    }
+                    Expiry::At(at) => {
+                        self.retention_by_age.remove(&(at, requeued.tx));
+                    }
+                }
+            }
+            if let Some((tr, dispute_count, expired_available)) = expired.evicted {
+                self.expired_transactions.remove(&tr.tx);
+                if let Some(count) = dispute_count {
+                    self.dispute_counts.insert(tr.tx, count);
+                }
+                match expired_available {
+                    Some(available) => self.expired_available.insert(tr.client, available),
+                    None => self.expired_available.remove(&tr.client),
+                };
+                self.revertable_transactions.insert(tr);
+            }
+            match expired.deposit.expiry {
+                Expiry::Seen(_) => self.retention_by_count.push_front(expired.deposit),
+                Expiry::At(at) => {
+                    self.retention_by_age.insert((at, expired.deposit.tx));
+                }
+            }
+        }
+        self.latest_timestamp = undo.latest_timestamp;
+        self.max_tx = undo.max_tx;
+        self.transactions_seen = undo.transactions_seen;
+        self.records_read = undo.records_read;
+        if let Some(counts) = self.type_counts.get_mut(undo.tr.tr_type.name()) {
+            counts.applied -= 1;
+        }
+        Some(undo.tr)
+    }
+
+    // Rejects transaction of a type config.lock_policy freezes on a locked account
+    fn check_lock(&self, tr: &Transaction) -> Result<(), RejectReason> {
+        let locked = self.clients.get(&tr.client).is_some_and(|client| client.locked);
+        if locked && self.config.lock_policy.is_some_and(|lock_policy| lock_policy.freezes(tr.tr_type)) {
+            warn!("Transaction on locked account: {:?}", tr);
+            return Err(RejectReason::AccountLocked { client: tr.client });
+        }
+        Ok(())
+    }
+
+    // Applies config.velocity_action to a deposit/withdrawal going over a velocity limit
+    fn check_velocity(&mut self, tr: &Transaction, amount: &Money) -> Result<(), RejectReason> {
+        if !self.velocity_limited() {
+            return Ok(());
+        }
+        let window = self.config.velocity_window_secs.and_then(|secs| i64::try_from(secs).ok()).and_then(chrono::TimeDelta::try_seconds);
+        let velocity = self.velocity.entry(tr.client).or_default();
+        let transactions = match (window, tr.timestamp) {
+            (Some(window), Some(timestamp)) => {
+                while velocity.recent.front().is_some_and(|&recent| recent <= timestamp - window) {
+                    velocity.recent.pop_front();
+                }
+                velocity.recent.len() as u32
+            }
+            _ => velocity.transactions,
+        };
+        let reason = if let Some(limit) = self.config.max_transactions_per_client
+            && transactions >= limit
+        {
+            RejectReason::TooManyTransactions { limit }
+        } else if let Some(limit) = &self.config.max_withdrawn_per_client
+            && tr.tr_type == TransactionType::Withdrawal
+            && &velocity.withdrawn + amount > *limit
+        {
+            RejectReason::OverWithdrawalLimit { limit: limit.clone() }
+        } else {
+            return Ok(());
+        };
+
+        self.counters.entry(tr.client).or_default().velocity_breaches += 1;
+        match self.config.velocity_action {
+            VelocityAction::WarnOnly => {
+                warn!("Velocity limit exceeded, {}: {:?}", reason, tr);
+                Ok(())
+            }
+            VelocityAction::RejectExcess => {
+                warn!("Velocity limit exceeded, rejecting, {}: {:?}", reason, tr);
+                Err(reason)
+            }
+            VelocityAction::LockAccount => {
+                warn!("Velocity limit exceeded, rejecting and locking account, {}: {:?}", reason, tr);
+                self.clients.get_or_insert(tr.client).lock(LockReason::Velocity, None);
+                Err(reason)
+            }
+        }
+    }
+
+    fn record_velocity(&mut self, tr: &Transaction, amount: &Money) {
+        if !self.velocity_limited() {
+            return;
+        }
+        let velocity = self.velocity.entry(tr.client).or_default();
+        velocity.transactions += 1;
+        if self.config.velocity_window_secs.is_some()
+            && let Some(timestamp) = tr.timestamp
+        {
+            velocity.recent.push_back(timestamp);
+        }
+        if tr.tr_type == TransactionType::Withdrawal {
+            velocity.withdrawn += amount;
+        }
+    }
+
+    fn velocity_limited(&self) -> bool {
+        self.config.max_transactions_per_client.is_some() || self.config.max_withdrawn_per_client.is_some()
+    }
+
+    fn record_overflow(&mut self, client: ClientID) {
+        self.counters.entry(client).or_default().overflows += 1;
+        if self.config.lock_on_overflow
+            && let Some(client) = self.clients.get_mut(&client)
+        {
+            warn!("Locking client {} after balance overflow", client.client);
+            client.lock(LockReason::BalanceOverflow, None);
+        }
+    }
+
+    // Clients with transactions rejected as balance overflow and their count, by client ID
+    pub fn overflow_clients(&self) -> Vec<(ClientID, u32)> {
+        let mut clients: Vec<(ClientID, u32)> = self.counters.iter()
+            .filter(|(_, counters)| counters.overflows > 0)
+            .map(|(client, counters)| (*client, counters.overflows))
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    // Handle to the model's live counters, readable from other threads
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    fn count_outcome(&mut self, tr_type: TransactionType, result: &Result<(), RejectReason>) {
+        self.metrics.processed.fetch_add(1, AtomicOrdering::Relaxed);
+        if result.is_err() {
+            self.metrics.rejected.fetch_add(1, AtomicOrdering::Relaxed);
+        }
+        let counts = self.type_counts.entry(tr_type.name()).or_default();
+        match result {
+            Ok(()) => counts.applied += 1,
+            Err(_) => counts.rejected += 1,
+        }
+    }
+
+    // Moves deposits whose delay has passed from pending to available
+    fn release_pending(&mut self) {
+        while let Some(deposit) = self.pending_deposits.front()
+            && deposit.release_at <= self.transactions_seen
+        {
+            let deposit = self.pending_deposits.pop_front().unwrap();
+            if let Some(undo) = self.undo.as_mut() {
+                if !undo.clients.iter().any(|(id, _)| *id == deposit.client) {
+                    undo.clients.push((deposit.client, self.clients.get(&deposit.client).cloned()));
+                }
+                undo.released.push(deposit.clone());
+            }
+            let Some(client) = self.clients.get_mut(&deposit.client) else {
+                continue;
+            };
+            match client.available.checked_add(&deposit.amount) {
+                Some(available) => {
+                    client.pending -= &deposit.amount;
+                    client.available = available;
+                }
+                // Left in pending balance, audit reports it as not matching the queue
+                None => warn!("Releasing deposit {} would overflow available of client {}", deposit.tx, deposit.client),
+            }
+        }
+    }
+
+    // When a deposit stored now expires: by age if it has a timestamp and retention_secs is set,
+    // otherwise by count if retention_transactions is set
+    fn deposit_expiry(&self, timestamp: Option<DateTime<Utc>>) -> Option<Expiry> {
+        if let (Some(timestamp), Some(secs)) = (timestamp, self.config.retention_secs) {
+            let secs = i64::try_from(secs).ok().and_then(chrono::TimeDelta::try_seconds)?;
+            return timestamp.checked_add_signed(secs).map(Expiry::At);
+        }
+        self.config.retention_transactions.map(|count| Expiry::Seen(self.transactions_seen + 1 + count))
+    }
+
+    fn retain_deposit(&mut self, deposit: RetainedDeposit) {
+        match deposit.expiry {
+            Expiry::Seen(_) => self.retention_by_count.push_back(deposit),
+            Expiry::At(at) => {
+                self.retention_by_age.insert((at, deposit.tx));
+            }
+        }
+    }
+
+    // Evicts expired deposits from the fronts of retention queues, which may be all of them when the clock jumps.
+    // Disputed and pending ones are still needed, they are retained again for another period,
+    // each looked at once per call so a zero period can't loop.
+    fn expire_deposits(&mut self) {
+        for _ in 0..self.retention_by_count.len() {
+            if !self.retention_by_count.front().is_some_and(|deposit| matches!(deposit.expiry, Expiry::Seen(seen) if self.transactions_seen >= seen)) {
+                break;
+            }
+            let deposit = self.retention_by_count.pop_front().unwrap();
+            self.expire_deposit(deposit);
+        }
+        for _ in 0..self.retention_by_age.len() {
+            let Some(&(at, tx)) = self.retention_by_age.first() else {
+                break;
+            };
+            if self.latest_timestamp.is_none_or(|latest| latest < at) {
+                break;
+            }
+            self.retention_by_age.pop_first();
+            self.expire_deposit(RetainedDeposit { tx, expiry: Expiry::At(at) });
+        }
+    }
+
+    fn expire_deposit(&mut self, deposit: RetainedDeposit) {
+        if self.disputed_transactions.contains(&deposit.tx) || self.pending_deposits.iter().any(|pending| pending.tx == deposit.tx) {
+            let expiry = match deposit.expiry {
+                Expiry::Seen(_) => self.deposit_expiry(None),
+                Expiry::At(_) => self.deposit_expiry(self.latest_timestamp),
+            };
+            let requeued = expiry.map(|expiry| RetainedDeposit { tx: deposit.tx, expiry });
+            if let Some(requeued) = &requeued {
+                self.retain_deposit(requeued.clone());
+            }
+            self.record_expired(ExpiredDeposit { deposit, requeued, evicted: None });
+            return;
+        }
+        // Rolled back deposits are no longer stored
+        let Some(tr) = self.revertable_transactions.get(deposit.tx) else {
+            self.record_expired(ExpiredDeposit { deposit, requeued: None, evicted: None });
+            return;
+        };
+        let expired_available = self.expired_available.get(&tr.client).cloned();
+        if !self.charged_back_transactions.contains(&tr.tx)
+            && let Some(amount) = &tr.amount
+        {
+            *self.expired_available.entry(tr.client).or_default() += amount;
+        }
+        self.revertable_transactions.remove(tr.tx);
+        let dispute_count = self.dispute_counts.remove(&tr.tx);
+        self.expired_transactions.insert(tr.tx);
+        self.record_expired(ExpiredDeposit { deposit, requeued: None, evicted: Some((tr, dispute_count, expired_available)) });
+    }
+
+    fn record_expired(&mut self, expired: ExpiredDeposit) {
+        if let Some(undo) = &mut self.undo {
+            undo.expired.push(expired);
+        }
+    }
+
+    // Deposits and withdrawals held for disputes and rollbacks, in memory or spilled
+    pub fn stored_transactions(&self) -> usize {
+        self.revertable_transactions.len()
+    }
+
+    fn apply_transaction(&mut self, mut tr: Transaction) -> Result<(), RejectReason> {
+        self.max_tx = self.max_tx.max(Some(tr.tx));
+        if self.config.allowed_types.as_ref().is_some_and(|allowed| !allowed.contains(&tr.tr_type)) {
+            warn!("Transaction type not allowed: {:?}", tr);
+            return Err(RejectReason::TypeNotAllowed { tr_type: tr.tr_type });
+        }
+        // Stored rounded, so disputes and rollbacks move the same amount
+        if let Some(places) = self.config.amount_places
+            && let Some(amount) = &tr.amount
+        {
+            tr.amount = Some(amount.round(places, self.config.rounding));
+        }
+        match tr.tr_type {
+            TransactionType::Deposit | TransactionType::Withdrawal => self.process_revertable_transaction(tr),
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.process_dispute_resolve_chargeback(tr)
+            }
+            TransactionType::Rollback => self.process_rollback(tr),
+        }
+    }
+
+    pub fn process_transactions(&mut self, input: &str) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        let file = open_input(input)?;
+        self.process_reader(open_file_reader(file)?)
+    }
+
+    // CSV source configured with model's amount locale
+    pub fn csv_source<R: Read>(&self, reader: R) -> CsvSource<RecordGuard<R>> {
+        let source = CsvSource::new(reader).with_max_record_bytes(self.config.max_record_bytes.unwrap_or(DEFAULT_MAX_RECORD_BYTES));
+        self.configure_csv(source)
+    }
+
+    fn configure_csv<R: Read>(&self, source: CsvSource<R>) -> CsvSource<R> {
+        source
+            .with_amount_locale(self.config.amount_locale)
+            .with_strip_symbols(self.config.strip_symbols)
+            .with_type_aliases(self.type_aliases(self.config.normalize_types))
+            .with_normalize_types(self.config.normalize_types)
+            .with_strict_schema(self.config.strict_schema)
+            .with_column_map(self.config.column_map.clone())
+    }
+
+    // NDJSON source configured with model's type aliases
+    pub fn ndjson_source<R: Read>(&self, reader: R) -> NdjsonSource<R> {
+        NdjsonSource::new(reader)
+            .with_type_aliases(self.type_aliases(self.config.normalize_types))
+            .with_normalize_types(self.config.normalize_types)
+    }
+
+    // Configured aliases, on top of built-in ones when types are normalized
+    fn type_aliases(&self, normalize_types: bool) -> TypeAliases {
+        if !normalize_types {
+            return self.config.type_aliases.clone();
+        }
+        let mut aliases = source::default_type_aliases();
+        aliases.extend(self.config.type_aliases.iter().map(|(name, tr_type)| (name.trim().to_lowercase(), *tr_type)));
+        aliases
+    }
+
+    // Rewrites transactions CSV in canonical form without applying it: lowercase type names (aliases resolved,
+    // types normalized regardless of config), trimmed fields, amounts with amount_places decimals (4 when not set)
+    // by config rounding, RFC3339 UTC timestamps, columns in type, client, tx, amount[, timestamp][, batch] order.
+    // Rows are sorted by timestamp with order_by_timestamp. Malformed rows and ones of unknown type are left out
+    // with a warning (or fail per config.unknown_types); returns number of rows written.
+    pub fn write_canonical<R: Read, W: Write>(&self, reader: R, writer: W) -> Result<u64, Box<dyn std::error::Error>> {
+        let mut source = self.csv_source(reader).with_normalize_types(true).with_type_aliases(self.type_aliases(true));
+        let mut wtr = csv::Writer::from_writer(writer);
+        // Optional columns kept, known once header is read
+        let mut columns = None;
+        let mut buffered = Vec::new();
+        let (mut written, mut skipped) = (0, 0);
+        loop {
+            let result = source.next_transaction();
+            let (timestamps, batches) = match columns {
+                Some(columns) => columns,
+                None => {
+                    let column = |name: &str| source.headers().is_some_and(|headers| headers.iter().any(|header| header == name));
+                    let (timestamps, batches) = (column("timestamp"), column("batch"));
+                    let mut header = vec!["type", "client", "tx", "amount"];
+                    header.extend(timestamps.then_some("timestamp"));
+                    header.extend(batches.then_some("batch"));
+                    wtr.write_record(&header)?;
+                    *columns.insert((timestamps, batches))
+                }
+            };
+            let Some(result) = result else {
+                break;
+            };
+            match result {
+                Ok((tr, _)) if self.config.order_by_timestamp => buffered.push(tr),
+                Ok((tr, _)) => {
+                    wtr.write_record(self.canonical_row(&tr, timestamps, batches))?;
+                    written += 1;
+                }
+                Err(SourceError::Io(err)) => return Err(err.into()),
+                Err(err @ SourceError::Schema(_)) => return Err(err.into()),
+                Err(SourceError::UnknownType { location, name }) => {
+                    skipped += 1;
+                    match self.config.unknown_types {
+                        UnknownTypes::Ignore => {}
+                        UnknownTypes::Warn => warn!("Leaving out row of unknown transaction type {} at {}", name, location),
+                        UnknownTypes::Error => return Err(SourceError::UnknownType { location, name }.into()),
+                    }
+                }
+                Err(err) => {
+                    skipped += 1;
+                    warn!("Leaving out malformed row at {}", err);
+                }
+            }
+        }
+
+        // Stable sort, so rows with the same timestamp keep file order
+        buffered.sort_by_key(|tr| tr.timestamp);
+        let (timestamps, batches) = columns.unwrap_or_default();
+        for tr in &buffered {
+            wtr.write_record(self.canonical_row(tr, timestamps, batches))?;
+            written += 1;
+        }
+        wtr.flush()?;
+        if skipped > 0 {
+            info!("Left out {} rows", skipped);
+        }
+        Ok(written)
+    }
+
+    fn canonical_row(&self, tr: &Transaction, timestamps: bool, batches: bool) -> Vec<String> {
+        let amount = tr.amount.as_ref().map(|amount| amount.format_rounded(self.config.amount_places.unwrap_or(4), self.config.rounding));
+        let mut row = vec![tr.tr_type.name().to_string(), tr.client.to_string(), tr.tx.to_string(), amount.unwrap_or_default()];
+        if timestamps {
+            row.push(tr.timestamp.map(|timestamp| timestamp.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)).unwrap_or_default());
+        }
+        if batches {
+            row.push(tr.batch.clone().unwrap_or_default());
+        }
+        row
+    }
+
+    pub fn process_reader<R: Read>(&mut self, reader: R) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        self.process_source(self.csv_source(reader))
+    }
+
+    // For embedders with own csv::Reader settings (quoting, escapes), it must read header row
+    pub fn process_csv_reader<R: Read>(&mut self, rdr: csv::Reader<R>) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        self.process_source(self.configure_csv(CsvSource::from_csv_reader(rdr)))
+    }
+
+    // Like process_reader, also returning up to max_errors rows which were skipped as malformed or of unknown type;
+    // report counts all of them, so a corrupt file costs at most max_errors snippets of memory
+    pub fn process_reader_collect_errors<R: Read>(&mut self, reader: R, max_errors: usize) -> Result<(ProcessingReport, Vec<RowError>), Box<dyn std::error::Error>> {
+        self.process_source_collect_errors(self.csv_source(reader), max_errors)
+    }
+
+    pub fn process_source_collect_errors<S: TransactionSource>(&mut self, source: S, max_errors: usize) -> Result<(ProcessingReport, Vec<RowError>), Box<dyn std::error::Error>> {
+        let mut errors = Vec::new();
+        let report = self.process_source_with_errors(source, &mut errors, max_errors)?;
+        Ok((report, errors))
+    }
+
+    // Processing driver shared by all input formats
+    pub fn process_source<S: TransactionSource>(&mut self, source: S) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        self.process_source_with_errors(source, &mut Vec::new(), 0)
+    }
+
+    fn process_source_with_errors<S: TransactionSource>(&mut self, source: S, errors: &mut Vec<RowError>, max_errors: usize) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+        // Records consumed by the run resumed from come first, then rows skipped on request
+        let skip = self.resume_skip + self.config.skip_rows;
+        let mut source = SkipRecords::new(source, skip);
+        let records_read = self.records_read;
+        let mut report = ProcessingReport { rounding: self.config.rounding, ..ProcessingReport::default() };
+        let mut buffered = Vec::new();
+        let mut deferred = Vec::new();
+        let windowed = self.config.since.is_some() || self.config.until.is_some();
+        let window_error = "Time window needs timestamp column, input rows have no timestamps";
+        let mut window_checked = !windowed;
+        let mut timestamped = false;
+        while let Some(result) = source.next_transaction() {
+            // Records before this one, what undo_last of its transaction goes back to
+            self.records_read = records_read + (skip - source.remaining()).min(self.resume_skip) + report.records;
+            // Row past the limit is read only to tell whether the run was truncated
+            if self.config.limit_rows.is_some_and(|limit| report.records >= limit) {
+                report.truncated = true;
+                break;
+            }
+            let tr = match result {
+                Ok((tr, location)) => {
+                    report.end_byte = location.end_byte;
+                    tr
+                }
+                Err(SourceError::Io(err)) => return Err(err.into()),
+                Err(err @ SourceError::Schema(_)) => return Err(err.into()),
+                Err(SourceError::UnknownType { location, name }) => {
+                    report.end_byte = location.end_byte;
+                    report.records += 1;
+                    report.unknown_types += 1;
+                    if errors.len() < max_errors {
+                        errors.push(RowError::new(location, source.raw_record(), RowErrorKind::UnknownType(name.clone())));
+                    }
+                    match self.config.unknown_types {
+                        UnknownTypes::Ignore => {}
+                        UnknownTypes::Warn => warn!("Skipping row of unknown transaction type {} at {}", name, location),
+                        UnknownTypes::Error => return Err(SourceError::UnknownType { location, name }.into()),
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    warn!("Error deserializing transaction at {}", err);
+                    if let SourceError::Record { location, message } = &err {
+                        report.end_byte = location.end_byte;
+                        if errors.len() < max_errors {
+                            errors.push(RowError::new(*location, source.raw_record(), RowErrorKind::Malformed(message.clone())));
+                        }
+                    }
+                    report.records += 1;
+                    report.parse_errors += 1;
+                    continue;
+                }
+            };
+            report.records += 1;
+            // Header tells, or first record for input without header, before any row reaches accounts
+            if !window_checked {
+                window_checked = true;
+                if !source.has_timestamp_column().unwrap_or(tr.timestamp.is_some()) {
+                    return Err(window_error.into());
+                }
+            }
+            if self.config.warn_integer_amounts && tr.integer_amount {
+                warn!("Amount without decimal point, possibly mis-scaled: {:?}", tr);
+                report.integer_amounts += 1;
+            }
+            if self.config.denied_clients.contains(&tr.client) {
+                continue;
+            }
+            if windowed {
+                timestamped |= tr.timestamp.is_some();
+                let inside = tr.timestamp.is_some_and(|timestamp| {
+                    self.config.since.is_none_or(|since| timestamp >= since) && self.config.until.is_none_or(|until| timestamp < until)
+                });
+                if !inside {
+                    report.outside_window += 1;
+                    continue;
+                }
+            }
+            if let Some(batch) = &tr.batch {
+                report.batches.entry(batch.clone()).or_default().rows += 1;
+            }
+
+            if self.config.order_by_timestamp {
+                buffered.push(tr);
+            } else {
+                self.process_or_defer(tr, &mut deferred, &mut report);
+            }
+        }
+
+        // Timestamp column present but empty throughout, nothing reached accounts
+        if report.outside_window > 0 && !timestamped {
+            return Err(window_error.into());
+        }
+        let resumed = (skip - source.remaining()).min(self.resume_skip);
+        self.records_read = records_read + resumed + report.records;
+        self.resume_skip -= resumed;
+
+        // Stable sort, so transactions with the same timestamp are applied in file order
+        buffered.sort_by_key(|tr| tr.timestamp);
+        for tr in buffered {
+            self.process_or_defer(tr, &mut deferred, &mut report);
+        }
+
+        for tr in deferred {
+            let batch = tr.batch.clone();
+            let result = if self.revertable_transactions.contains(tr.tx) || self.expired_transactions.contains(&tr.tx) {
+                self.process_transaction(tr)
+            } else {
+                warn!("Dispute/Resolve/Chargeback still on unknown transaction at end of input: {:?}", tr);
+                let result = Err(RejectReason::UnknownTransaction { tx: tr.tx });
+                self.count_outcome(tr.tr_type, &result);
+                result
+            };
+            report.record_outcome(batch.as_deref(), &result);
+        }
+
+        self.check_errors()?;
+        Ok(report)
+    }
+
+    fn process_or_defer(&mut self, tr: Transaction, deferred: &mut Vec<Transaction>, report: &mut ProcessingReport) {
+        let refers_to_other = matches!(tr.tr_type, TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback);
+        if self.config.defer_disputes
+            && refers_to_other
+            && !self.revertable_transactions.contains(tr.tx)
+            && !self.rolled_back_transactions.contains(&tr.tx)
+            && !self.expired_transactions.contains(&tr.tx)
+        {
+            info!("Deferring transaction on not yet seen transaction: {:?}", tr);
+            deferred.push(tr);
+        } else if self.is_identical_duplicate(&tr) {
+            info!("Skipping repeat of applied transaction: {:?}", tr);
+            report.duplicates_identical += 1;
+        } else {
+            let batch = tr.batch.clone();
+            let result = self.process_transaction(tr);
+            report.record_outcome(batch.as_deref(), &result);
+        }
+    }
+
+    // Deposit/Withdrawal row equal to the stored one field by field, amount as written (scale included)
+    fn is_identical_duplicate(&mut self, tr: &Transaction) -> bool {
+        if !matches!(tr.tr_type, TransactionType::Deposit | TransactionType::Withdrawal) {
+            return false;
+        }
+        let Some(stored) = self.revertable_transactions.get(tr.tx) else {
+            return false;
+        };
+        // Stored amount was rounded at ingestion
+        let amount = match (self.config.amount_places, &tr.amount) {
+            (Some(places), Some(amount)) => Some(amount.round(places, self.config.rounding)),
+            (_, amount) => amount.clone(),
+        };
+        (stored.tr_type, stored.client, stored.timestamp, &stored.batch) == (tr.tr_type, tr.client, tr.timestamp, &tr.batch)
+            && stored.amount.map(|amount| amount.to_string()) == amount.map(|amount| amount.to_string())
+    }
+
+    // Rebuilds model from event log, events are facts so no business validation is done
+    pub fn replay_events<R: Read>(reader: R) -> Result<Model, ReplayError> {
+        let mut model = Model::default();
+        let mut expected_seq = 1;
+        for line in BufReader::new(reader).lines() {
+            let line = line.map_err(|err| ReplayError { seq: expected_seq, message: err.to_string() })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: Event = serde_json::from_str(&line)
+                .map_err(|err| ReplayError { seq: expected_seq, message: format!("corrupt event: {}", err) })?;
+            if event.seq != expected_seq {
+                return Err(ReplayError { seq: event.seq, message: format!("out of sequence, expected {}", expected_seq) });
+            }
+            model.apply_event(event);
+            expected_seq += 1;
+        }
+        Ok(model)
+    }
+
+    fn apply_event(&mut self, event: Event) {
+        match event.kind {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                self.revertable_transactions.insert(Transaction {
+                    tr_type: event.kind,
+                    client: event.client,
+                    tx: event.tx,
+                    amount: Some(event.amount),
+                    timestamp: None,
+                    batch: event.batch,
+                    empty_amount: false,
+                    integer_amount: false,
+                });
+            }
+            TransactionType::Dispute => {
+                self.disputed_transactions.insert(event.tx);
+                self.open_disputes.entry(event.client).or_default().insert(event.tx, event.amount.clone());
+            }
+            TransactionType::Resolve => {
+                self.disputed_transactions.remove(&event.tx);
+                close_dispute(&mut self.open_disputes, event.client, event.tx);
+            }
+            TransactionType::Chargeback => {
+                self.disputed_transactions.remove(&event.tx);
+                close_dispute(&mut self.open_disputes, event.client, event.tx);
+                self.charged_back_transactions.insert(event.tx);
+            }
+            TransactionType::Rollback => {
+                self.revertable_transactions.remove(event.tx);
+                self.rolled_back_transactions.insert(event.tx);
+            }
+        }
+
+        // Events log deposits, not their settlement, so pending is as of the client's last event
+        let pending = &(&event.total - &event.available) - &event.held;
+        self.clients.insert(event.client, Client {
+            client: event.client,
+            available: event.available,
+            held: event.held,
+            pending,
+            total: event.total,
+            locked: event.locked,
+            locked_by: event.locked_by,
+            locked_at: event.locked_at,
+            lock_reason: event.lock_reason,
+        });
+    }
+
+    // Client's transactions under dispute now with their amounts, by tx; kept up to date per transaction,
+    // so it doesn't read stored transactions
+    pub fn open_disputes(&self, client: ClientID) -> impl Iterator<Item = (TransactionID, Money)> + '_ {
+        self.open_disputes.get(&client).into_iter().flatten().map(|(tx, amount)| (*tx, amount.clone()))
+    }
+
+    pub fn counters(&self, id: ClientID) -> Option<&ClientCounters> {
+        self.counters.get(&id)
+    }
+
+    // Clients exceeding any of the thresholds, ordered by client ID
+    pub fn suspicious_clients(&self, thresholds: &SarThresholds) -> Vec<SarEntry> {
+        let mut entries: Vec<SarEntry> = self.counters.iter().filter_map(|(&client, counters)| {
+            let mut reasons = Vec::new();
+            if thresholds.max_chargebacks.is_some_and(|max| counters.chargebacks > max) {
+                reasons.push("chargebacks");
+            }
+            if thresholds.max_dispute_rate.is_some_and(|max| counters.dispute_rate() > max) {
+                reasons.push("dispute_rate");
+            }
+            if thresholds.max_insufficient_funds.is_some_and(|max| counters.insufficient_funds > max) {
+                reasons.push("insufficient_funds");
+            }
+            if reasons.is_empty() {
+                return None;
+            }
+            Some(SarEntry {
+                client,
+                deposits: counters.deposits,
+                disputes: counters.disputes,
+                chargebacks: counters.chargebacks,
+                insufficient_funds: counters.insufficient_funds,
+                dispute_rate: counters.dispute_rate(),
+                reasons: reasons.join(";"),
+            })
+        }).collect();
+        entries.sort_by_key(|entry| entry.client);
+        entries
+    }
+
+    pub fn write_suspicious_clients<W: Write>(&self, writer: W, thresholds: &SarThresholds) -> Result<(), Box<dyn std::error::Error>> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for entry in self.suspicious_clients(thresholds) {
+            wtr.serialize(entry)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    pub fn top_clients(&self, n: usize) -> TopClients {
+        TopClients {
+            by_total: top_n(self.clients.values().map(|c| (c.client, c.total.clone())), n),
+            by_held: top_n(self.clients.values().map(|c| (c.client, c.held.clone())), n),
+            by_transactions: top_n(self.counters.iter().map(|(&id, c)| (id, c.transactions())), n),
+        }
+    }
+
+    // Transactions applied and rejected by type since the model was created, with current balances
+    pub fn stats(&self) -> ModelStats {
+        ModelStats { by_type: self.type_counts.clone(), balance: self.trial_balance() }
+    }
+
+    pub fn trial_balance(&self) -> TrialBalance {
+        let mut balance = TrialBalance::default();
+        for client in self.clients.values() {
+            balance.clients += 1;
+            balance.available += &client.available;
+            balance.held += &client.held;
+            balance.pending += &client.pending;
+            balance.total += &client.total;
+            if client.locked {
+                balance.locked_clients += 1;
+                balance.locked += &client.total;
+            }
+        }
+        balance
+    }
+
+    // Recomputes balances from stored transactions and disputed set, to catch bugs in incremental updates.
+    // Deposits count as available, as held while disputed and once charged back not at all (as available with
+    // ChargebackSemantics::Refund), withdrawals are taken from available, their amount also in held while disputed
+    // and not at all once charged back; rolled back transactions are no longer stored, expired deposits only as their sum.
+    pub fn audit(&mut self) -> std::io::Result<Vec<AuditMismatch>> {
+        let mut expected: BTreeMap<ClientID, (Money, Money, Money)> = self.clients.values().map(|client| (client.client, Default::default())).collect();
+        for (client, amount) in &self.expired_available {
+            expected.entry(*client).or_default().0 += amount;
+        }
+        let pending: HashSet<TransactionID> = self.pending_deposits.iter().map(|deposit| deposit.tx).collect();
+        for tr in self.revertable_transactions.all()? {
+            let Some(amount) = &tr.amount else {
+                continue;
+            };
+            let (available, held, pending_total) = expected.entry(tr.client).or_default();
+            match tr.tr_type {
+                TransactionType::Deposit if self.disputed_transactions.contains(&tr.tx) => *held += amount,
+                TransactionType::Deposit if self.charged_back_transactions.contains(&tr.tx) => {
+                    if self.config.chargeback_semantics == ChargebackSemantics::Refund {
+                        *available += amount;
+                    }
+                }
+                TransactionType::Deposit if pending.contains(&tr.tx) => *pending_total += amount,
+                TransactionType::Deposit => *available += amount,
+                // Disputed withdrawal is claimed back in held, charged back one is paid back
+                _ if self.disputed_transactions.contains(&tr.tx) => {
+                    *available -= amount;
+                    *held += amount;
+                }
+                _ if self.charged_back_transactions.contains(&tr.tx) => {}
+                _ => *available -= amount,
+            }
+        }
+
+        let mismatches = expected.into_iter().filter_map(|(client, (available, held, pending))| {
+            let total = &(&available + &held) + &pending;
+            let expected = (available, held, total);
+            let actual = self.clients.get(&client)
+                .map(|c| (c.available.clone(), c.held.clone(), c.total.clone()))
+                .unwrap_or_default();
+            (expected != actual).then_some(AuditMismatch { client, expected, actual })
+        });
+        Ok(mismatches.collect())
+    }
+
+    // Writes accounts CSV ordered by client ID, so output is deterministic
+    pub fn write_accounts<W: Write>(&self, writer: W, options: &OutputOptions) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_account_rows(writer, &self.clients.sorted(), options)
+    }
+
+    // Writes sorted accounts to dir as "accounts_0001.csv", "accounts_0002.csv"... of at most rows_per_file
+    // clients each, every file with header, for parallel ingestion; returns number of files
+    pub fn write_accounts_sharded(&self, dir: &Path, rows_per_file: usize, options: &OutputOptions) -> Result<usize, Box<dyn std::error::Error>> {
+        let clients = self.clients.sorted();
+        let mut files = 0;
+        for chunk in clients.chunks(rows_per_file.max(1)) {
+            files += 1;
+            write_atomic(&dir.join(format!("accounts_{:04}.csv", files)), |writer| self.write_account_rows(writer, chunk, options))?;
+        }
+        // Shards of an earlier, larger output would read as part of this one
+        let mut stale = files + 1;
+        while std::fs::remove_file(dir.join(format!("accounts_{:04}.csv", stale))).is_ok() {
+            stale += 1;
+        }
+        Ok(files)
+    }
+
+    fn write_account_rows<W: Write>(&self, writer: W, clients: &[&Client], options: &OutputOptions) -> Result<(), Box<dyn std::error::Error>> {
+        if options.format == OutputFormat::Table {
+            return self.write_table(writer, clients, options);
+        }
+
+        let mut wtr = csv::Writer::from_writer(writer);
+        for client in clients {
+            if options.balances_only {
+                wtr.serialize(BalanceRow { client: client.client, total: options.format_amount(&client.total) })?;
+                continue;
+            }
+            let velocity = self.velocity_limited().then(|| {
+                let velocity = self.velocity.get(&client.client);
+                let breaches = self.counters.get(&client.client).map_or(0, |counters| counters.velocity_breaches);
+                (velocity.map_or(0, |velocity| velocity.transactions), velocity.map_or_else(Money::zero, |velocity| velocity.withdrawn.clone()), breaches)
+            });
+            wtr.serialize(AccountRow {
+                client: client.client,
+                available: options.format_amount(&client.available),
+                held: options.format_amount(&client.held),
+                pending: self.config.pending_delay.map(|_| options.format_amount(&client.pending)),
+                total: options.format_amount(&client.total),
+                locked: client.locked,
+                transactions: velocity.as_ref().map(|(transactions, _, _)| *transactions),
+                withdrawn: velocity.as_ref().map(|(_, withdrawn, _)| options.format_amount(withdrawn)),
+                velocity_breaches: velocity.as_ref().map(|(_, _, breaches)| *breaches),
+                locked_by: options.lock_details.then(|| client.locked_by.map(|tx| tx.to_string()).unwrap_or_default()),
+                locked_at: options.lock_details.then(|| client.locked_at.map(|timestamp| timestamp.to_rfc3339()).unwrap_or_default()),
+                lock_reason: options.lock_details.then(|| client.lock_reason.map(|reason| reason.code().to_string()).unwrap_or_default()),
+            })?;
+        }
+        wtr.flush()?;
+
+        Ok(())
+    }
+
+    // Writes "client-<id>.csv" to dir for each client in event log (only listed ones when given):
+    // its events in order with running balances, then a closing row with current balances.
+    // Event log is read once; rows are buffered per client and appended to temporary statement files
+    // whenever buffers reach STATEMENT_BUFFER_BYTES, so neither memory nor open files grow with the log.
+    pub fn write_statements<R: Read>(&self, events: R, dir: &Path, only: Option<&BTreeSet<ClientID>>) -> Result<usize, Box<dyn std::error::Error>> {
+        let tmp_path = |client: ClientID| dir.join(format!(".client-{}.csv.tmp-{}", client, std::process::id()));
+        // Rows not yet written per client, and whether its temporary file was created
+        let mut statements: BTreeMap<ClientID, (Vec<u8>, bool)> = BTreeMap::new();
+        let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+            let row = |buffer: &mut Vec<u8>, record: [String; 8]| -> Result<usize, Box<dyn std::error::Error>> {
+                let len = buffer.len();
+                let mut wtr = csv::WriterBuilder::new().buffer_capacity(256).from_writer(&mut *buffer);
+                wtr.write_record(record)?;
+                wtr.flush()?;
+                drop(wtr);
+                Ok(buffer.len() - len)
+            };
+            let flush = |client: ClientID, buffer: &mut Vec<u8>, created: &mut bool| -> std::io::Result<()> {
+                let mut file = std::fs::OpenOptions::new().create(true).write(true).append(*created).truncate(!*created).open(tmp_path(client))?;
+                *created = true;
+                file.write_all(buffer)?;
+                buffer.clear();
+                Ok(())
+            };
+
+            let mut buffered = 0;
+            for line in BufReader::new(events).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let event: Event = serde_json::from_str(&line)?;
+                if !only.is_none_or(|only| only.contains(&event.client)) {
+                    continue;
+                }
+                let (buffer, created) = statements.entry(event.client).or_default();
+                if !*created && buffer.is_empty() {
+                    buffered += row(buffer, ["seq", "type", "tx", "amount", "available", "held", "total", "locked"].map(String::from))?;
+                }
+                buffered += row(buffer, [
+                    event.seq.to_string(),
+                    event.kind.name().to_string(),
+                    event.tx.to_string(),
+                    event.amount.to_string(),
+                    event.available.format_fixed(4),
+                    event.held.format_fixed(4),
+                    event.total.format_fixed(4),
+                    event.locked.to_string(),
+                ])?;
+                if buffered >= STATEMENT_BUFFER_BYTES {
+                    for (client, (buffer, created)) in statements.iter_mut() {
+                        if !buffer.is_empty() {
+                            flush(*client, buffer, created)?;
+                        }
+                    }
+                    buffered = 0;
+                }
+            }
+
+            for (client, (buffer, created)) in statements.iter_mut() {
+                if let Some(current) = self.client(*client) {
+                    row(buffer, [
+                        String::new(),
+                        "closing".to_string(),
+                        String::new(),
+                        String::new(),
+                        current.available.format_fixed(4),
+                        current.held.format_fixed(4),
+                        current.total.format_fixed(4),
+                        current.locked.to_string(),
+                    ])?;
+                }
+                flush(*client, buffer, created)?;
+                std::fs::File::open(tmp_path(*client))?.sync_all()?;
+                std::fs::rename(tmp_path(*client), dir.join(format!("client-{}.csv", client)))?;
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            for client in statements.keys() {
+                let _ = std::fs::remove_file(tmp_path(*client));
+            }
+        }
+        result.map(|()| statements.len())
+    }
+
+    fn write_table<W: Write>(&self, mut writer: W, clients: &[&Client], options: &OutputOptions) -> Result<(), Box<dyn std::error::Error>> {
+        struct Column {
+            header: &'static str,
+            cells: Vec<String>,
+            footer: String,
+            // Dropped first when table doesn't fit
+            optional: bool,
+        }
+        let amounts = |header, amount: fn(&Client) -> &Money, footer: &Money, optional| Column {
+            header,
+            cells: clients.iter().map(|client| options.format_amount(amount(client))).collect(),
+            footer: options.format_amount(footer),
+            optional,
+        };
+
+        let balance = self.trial_balance();
+        let mut columns = vec![Column {
+            header: "client",
+            cells: clients.iter().map(|client| client.client.to_string()).collect(),
+            footer: "total".to_string(),
+            optional: false,
+        }];
+        if !options.balances_only {
+            columns.push(amounts("available", |client| &client.available, &balance.available, true));
+            columns.push(amounts("held", |client| &client.held, &balance.held, true));
+            if self.config.pending_delay.is_some() {
+                let pending: Money = clients.iter().map(|client| &client.pending).sum();
+                columns.push(amounts("pending", |client| &client.pending, &pending, true));
+            }
+        }
+        columns.push(amounts("total", |client| &client.total, &balance.total, false));
+        if !options.balances_only {
+            columns.push(Column {
+                header: "locked",
+                cells: clients.iter().map(|client| client.locked.to_string()).collect(),
+                footer: format!("{} locked", balance.locked_clients),
+                optional: false,
+            });
+        }
+
+        let width = |column: &Column| {
+            column.cells.iter().chain([&column.footer]).map(String::len).max().unwrap_or(0).max(column.header.len())
+        };
+        let table_width = |columns: &[Column]| columns.iter().map(|column| width(column) + 2).sum::<usize>() - 2;
+        if let Some(max_width) = options.width {
+            while table_width(&columns) > max_width
+                && let Some(index) = columns.iter().rposition(|column| column.optional)
+            {
+                columns.remove(index);
+            }
+        }
+
+        let widths: Vec<usize> = columns.iter().map(width).collect();
+        let line = |cells: Vec<&str>| {
+            cells.iter().zip(&widths).enumerate()
+                .map(|(i, (cell, width))| if i + 1 == widths.len() { format!("{:<width$}", cell) } else { format!("{:>width$}", cell) })
+                .collect::<Vec<String>>()
+                .join("  ")
+        };
+        let separator = "-".repeat(table_width(&columns));
+        writeln!(writer, "{}", line(columns.iter().map(|column| column.header).collect()).trim_end())?;
+        writeln!(writer, "{}", separator)?;
+        for row in 0..clients.len() {
+            writeln!(writer, "{}", line(columns.iter().map(|column| column.cells[row].as_str()).collect()).trim_end())?;
+        }
+        writeln!(writer, "{}", separator)?;
+        writeln!(writer, "{}", line(columns.iter().map(|column| column.footer.as_str()).collect()).trim_end())?;
+        Ok(())
+    }
+
+    pub fn print_to_stdout(&mut self, options: &OutputOptions) -> Result<(), Box<dyn std::error::Error>> {
+        self.write_accounts(std::io::stdout(), options)
+    }
+
+    // SHA-256 of accounts output in given format, for end-to-end verification of pipelines
+    pub fn accounts_checksum(&self, options: &OutputOptions) -> Result<String, Box<dyn std::error::Error>> {
+        let mut hasher = Sha256::new();
+        self.write_accounts(&mut hasher, options)?;
+        Ok(hex(&hasher.finalize()))
+    }
+}
+
+// Writes file via temporary file in the same directory, fsync and rename,
+// so on failure the destination is either missing or keeps its previous contents
+pub fn write_atomic<F>(path: &Path, write: F) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnOnce(&mut dyn Write) -> Result<(), Box<dyn std::error::Error>>,
+{
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| format!("Invalid output path: {}", path.display()))?;
+    let tmp_path = dir.join(format!(".{}.tmp-{}", name.to_string_lossy(), std::process::id()));
+
+    let result = (|| {
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// SHA-256 hex digest of the first bytes of (decompressed) file, None if file is shorter
+fn hash_prefix(path: &str, bytes: u64) -> std::io::Result<Option<String>> {
+    let mut reader = open_reader(open_input(path)?)?.take(bytes);
+    let mut hasher = Sha256::new();
+    let copied = std::io::copy(&mut reader, &mut hasher)?;
+    if copied < bytes {
+        return Ok(None);
+    }
+    Ok(Some(hex(&hasher.finalize())))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Opens input file, directory fails here with a clear message instead of on the first read
+pub fn open_input(path: &str) -> std::io::Result<std::fs::File> {
+    let file = std::fs::File::open(path)?;
+    if file.metadata()?.is_dir() {
+        return Err(std::io::Error::new(std::io::ErrorKind::IsADirectory, format!("Input {} is a directory", path)));
+    }
+    Ok(file)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Peeks the first bytes of the input and transparently decompresses gzip streams,
+// so both "file.csv.gz" and "cat file.csv.gz | process-transactions" work.
+pub fn open_reader<R: Read + 'static>(reader: R) -> std::io::Result<Box<dyn Read>> {
+    let mut reader = BufReader::new(reader);
+    let is_gzip = reader.fill_buf()?.starts_with(&GZIP_MAGIC);
+    if is_gzip {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// Input file read through a concrete type, so processing loop is monomorphized for it
+// instead of going through Box<dyn Read> of open_reader on every read
+pub enum FileReader {
+    Plain(BufReader<std::fs::File>),
+    // Boxed for its size, still a concrete type
+    Gzip(Box<GzDecoder<BufReader<std::fs::File>>>),
+}
+
+impl Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            FileReader::Plain(reader) => reader.read(buf),
+            FileReader::Gzip(reader) => reader.read(buf),
+        }
+    }
+}
+
+// Same detection as open_reader, for files
+pub fn open_file_reader(file: std::fs::File) -> std::io::Result<FileReader> {
+    let mut reader = BufReader::new(file);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(FileReader::Gzip(Box::new(GzDecoder::new(reader))))
+    } else {
+        Ok(FileReader::Plain(reader))
+    }
+}
+
+// Character encoding of text input, for Windows exports which aren't UTF-8
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InputEncoding {
+    #[default]
+    Utf8,
+    // Little-endian unless the input starts with big-endian BOM
+    Utf16,
+    // Read as Windows-1252, its superset which Windows tools actually write
+    Latin1,
+}
+
+impl std::str::FromStr for InputEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf8" => Ok(InputEncoding::Utf8),
+            "utf16" => Ok(InputEncoding::Utf16),
+            "latin1" => Ok(InputEncoding::Latin1),
+            _ => Err(format!("Unknown input encoding: {}", s)),
+        }
+    }
+}
+
+// Transcodes input to UTF-8 before parsing, dropping BOM. UTF-8 input is passed through as is,
+// so byte offsets stay those of the file.
+pub fn decode_reader(reader: Box<dyn Read>, encoding: InputEncoding) -> Box<dyn Read> {
+    let encoding = match encoding {
+        InputEncoding::Utf8 => return reader,
+        InputEncoding::Utf16 => encoding_rs::UTF_16LE,
+        InputEncoding::Latin1 => encoding_rs::WINDOWS_1252,
+    };
+    Box::new(encoding_rs_io::DecodeReaderBytesBuilder::new().encoding(Some(encoding)).build(reader))
+}
+
+// Maps the whole file instead of copying it through read buffers, for very large inputs.
+// Gzip is detected the same way as in open_reader.
+pub fn open_mmap(file: &std::fs::File) -> std::io::Result<Box<dyn Read>> {
+    // Safety: like with --track-offsets, input must not be modified while being processed,
+    // truncating mapped file makes reads fault
+    let map = unsafe { memmap2::Mmap::map(file)? };
+    let reader = std::io::Cursor::new(map);
+    if reader.get_ref().starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(GzDecoder::new(reader)))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::fixtures::{assert_client_eq, case_epsilon};
+
+    fn money(text: &str) -> Money {
+        text.parse().expect("Invalid amount")
+    }
+
+    #[test]
+    fn test_base() {
+        run_case("01-transactions-base", "01-accounts-base")
+    }
+
+    #[test]
+    fn test_dispute() {
+        run_case("02-transactions-dispute", "02-accounts-dispute")
+    }
+
+    #[test]
+    fn test_resolve() {
+        run_case("03-transactions-resolve", "03-accounts-resolve")
+    }
+
+    #[test]
+    fn test_resolve_no_dispute() {
+        run_case("04-transactions-resolve-no-dispute", "04-accounts-resolve-no-dispute")
+    }
+
+    #[test]
+    fn test_chargeback() {
+        run_case("05-transactions-chargeback", "05-accounts-chargeback")
+    }
+
+    #[test]
+    fn test_unexpected() {
+        run_case("06-transactions-unexpected", "06-accounts-unexpected")
+    }
+
+    #[test]
+    fn test_dispute_wrong_client() {
+        run_case("07-transactions-dispute-wrong-client", "07-accounts-dispute-wrong-client")
+    }
+
+    #[test]
+    fn test_gzip_stream() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let csv_text = std::fs::read("cases/01-transactions-base.csv").expect("Error reading input");
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&csv_text).expect("Error compressing");
+        let gz_bytes = encoder.finish().expect("Error compressing");
+        assert!(gz_bytes.starts_with(&GZIP_MAGIC));
+
+        let mut model = Model::default();
+        let reader = open_reader(std::io::Cursor::new(gz_bytes)).expect("Error opening reader");
+        model.process_reader(reader).expect("Processing failed");
+        check_accounts(&model, "01-accounts-base");
+    }
+
+    #[test]
+    fn test_mmap_matches_buffered() {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().join("transactions.csv");
+        std::fs::copy("cases/05-transactions-chargeback.csv", &path).expect("Error copying input");
+
+        let file = std::fs::File::open(&path).expect("Error opening input");
+        let buffered = process_checksum(open_reader(file).expect("Error opening reader"));
+        let file = std::fs::File::open(&path).expect("Error opening input");
+        let mapped = process_checksum(open_mmap(&file).expect("Error mapping input"));
+        assert_eq!(mapped, buffered);
+        assert!(buffered.0.records > 0);
+    }
+
+    #[test]
+    fn test_decode_utf16() {
+        let text = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input");
+        let expected = process_checksum(text.as_bytes());
+
+        // As written by Windows "Unicode" export: BOM and UTF-16LE
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().join("transactions.csv");
+        let bytes: Vec<u8> = [0xff, 0xfe].into_iter().chain(text.encode_utf16().flat_map(u16::to_le_bytes)).collect();
+        std::fs::write(&path, bytes).expect("Error writing input");
+        let file = std::fs::File::open(&path).expect("Error opening input");
+        let decoded = process_checksum(decode_reader(open_reader(file).expect("Error opening reader"), InputEncoding::Utf16));
+        assert_eq!(decoded, expected);
+        assert!(expected.0.records > 0);
+
+        let mut decoded = String::new();
+        decode_reader(Box::new(&b"d\xe9p\xf4t \x80"[..]), InputEncoding::Latin1)
+            .read_to_string(&mut decoded).expect("Error decoding");
+        assert_eq!(decoded, "dépôt €");
+    }
+
+    #[test]
+    fn test_process_csv_reader() {
+        let csv_text = "type,client,tx,amount,batch\ndeposit,1,1,5.0,\"A\\\"1\"\ndeposit,1,2,1.0,\"A\\\"1\"\n";
+        let rdr = csv::ReaderBuilder::new().escape(Some(b'\\')).double_quote(false).from_reader(csv_text.as_bytes());
+        let mut model = Model::default();
+        let report = model.process_csv_reader(rdr).expect("Processing failed");
+        assert_eq!((report.parse_errors, report.batches["A\"1"].applied), (0, 2));
+        assert_eq!(model.total(1), Some(money("6.0")));
+
+        // Default reader doesn't know the escape
+        let report = Model::default().process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert!(!report.batches.contains_key("A\"1"));
+    }
+
+    #[test]
+    fn test_directory_input() {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().to_str().unwrap();
+        let err = Model::default().process_transactions(path).expect_err("Directory processed");
+        assert_eq!(err.to_string(), format!("Input {} is a directory", path));
+        assert_eq!(err.downcast_ref::<std::io::Error>().map(|err| err.kind()), Some(std::io::ErrorKind::IsADirectory));
+    }
+
+    #[test]
+    fn test_timestamp() {
+        run_case("08-transactions-timestamp", "08-accounts-timestamp");
+
+        let mut model = Model::default();
+        model.process_transactions("cases/08-transactions-timestamp.csv").expect("Processing failed");
+        let mut timestamp = |tx| model.revertable_transactions.get(tx).expect("Transaction missing").timestamp;
+        assert_eq!(timestamp(1), Some(parse_timestamp("2024-01-01T10:00:00Z").unwrap()));
+        assert_eq!(timestamp(2), DateTime::from_timestamp(1704103200, 0));
+        assert_eq!(timestamp(3), None);
+        assert_eq!(timestamp(4), Some(parse_timestamp("2024-01-01T10:00:00Z").unwrap()));
+    }
+
+    #[test]
+    fn test_order_by_timestamp() {
+        let csv_text = "type, client, tx, amount, timestamp
+dispute, 1, 1,, 2024-01-01T12:00:00Z
+withdrawal, 1, 2, 1.0, 2024-01-01T11:00:00Z
+deposit, 1, 1, 3.0, 2024-01-01T10:00:00Z
+deposit, 1, 3, 0.5, 2024-01-01T11:00:00Z
+";
+        // In file order the dispute and withdrawal precede the deposit and are ignored
+        let mut model = Model::default();
+        process_text(&mut model, csv_text);
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("3.5"), money("0.0"), money("3.5")));
+
+        let mut model = Model::with_config(ModelConfig { order_by_timestamp: true, ..Default::default() });
+        process_text(&mut model, csv_text);
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("-0.5"), money("3.0"), money("2.5")));
+    }
+
+    #[test]
+    fn test_time_window() {
+        let at = |text: &str| Some(parse_timestamp(text).expect("Invalid timestamp"));
+        let run = |since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>| {
+            let mut model = Model::with_config(ModelConfig { since, until, ..ModelConfig::default() });
+            let report = model.process_transactions("cases/19-transactions-window.csv").expect("Processing failed");
+            let balances: Vec<_> = [1, 2].into_iter().map(|client| model.client(client).map(|c| (c.available.clone(), c.held.clone()))).collect();
+            (report.outside_window, report.rejected, balances)
+        };
+        let rejected = |codes: &[(&'static str, u64)]| codes.iter().copied().collect::<BTreeMap<_, _>>();
+        let balance = |available: &str, held: &str| Some((money(available), money(held)));
+
+        assert_eq!(run(None, None), (0, rejected(&[]), vec![balance("-1.0", "10.0"), balance("4.0", "0")]));
+        assert_eq!(run(None, at("2024-01-02T00:00:00Z")), (4, rejected(&[]), vec![balance("10.0", "0"), balance("5.0", "0")]));
+        // Until is exclusive
+        assert_eq!(run(None, at("2024-01-01T12:00:00Z")), (5, rejected(&[]), vec![balance("10.0", "0"), None]));
+        assert_eq!(run(None, at("2024-01-03T00:00:00Z")), (2, rejected(&[]), vec![balance("9.0", "0"), balance("5.0", "0")]));
+        // Dispute of a deposit before the window is unknown to the model
+        assert_eq!(run(at("2024-01-02T00:00:00Z"), None),
+            (2, rejected(&[("insufficient_funds", 2), ("unknown_transaction", 1)]), vec![balance("3.0", "0"), balance("0", "0")]));
+        assert_eq!(run(at("2024-01-02T00:00:00Z"), at("2024-01-03T00:00:00Z")),
+            (4, rejected(&[("insufficient_funds", 1)]), vec![balance("3.0", "0"), None]));
+
+        let mut model = Model::with_config(ModelConfig { until: at("2024-01-02T00:00:00Z"), ..ModelConfig::default() });
+        let err = model.process_transactions("cases/05-transactions-chargeback.csv").unwrap_err();
+        assert_eq!(err.to_string(), "Time window needs timestamp column, input rows have no timestamps");
+        assert_eq!(model.client(1), None);
+        // Fails on the header, rest of input isn't read
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n".as_bytes().chain(FailingReader);
+        let err = model.process_reader(input).unwrap_err();
+        assert_eq!(err.to_string(), "Time window needs timestamp column, input rows have no timestamps");
+        // Without header, the first record tells
+        let err = model.process_source(NdjsonSource::new("{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.0\"}\n".as_bytes().chain(FailingReader))).unwrap_err();
+        assert_eq!(err.to_string(), "Time window needs timestamp column, input rows have no timestamps");
+        assert_eq!(model.client(1), None);
+    }
+
+    // Fails on every read
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("injected failure"))
+        }
+    }
+
+    #[test]
+    fn test_spill_dir() {
+        let csv_text = "type, client, tx, amount, timestamp
+deposit, 1, 1, 1.0, 2024-01-01T10:00:00Z
+deposit, 2, 2, 2.0,
+deposit, 1, 3, 3.0,
+withdrawal, 1, 4, 0.5,
+deposit, 2, 5, 5.0,
+dispute, 1, 1,,
+dispute, 2, 2,,
+resolve, 2, 2,,
+dispute, 1, 3,,
+chargeback, 1, 3,,
+rollback, 2, 5,,
+";
+        let mut expected = Model::default();
+        process_text(&mut expected, csv_text);
+
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let mut model = Model::default();
+        model.set_spill_dir(dir.path(), 2).expect("Error creating spill file");
+        process_text(&mut model, csv_text);
+        assert!(!model.revertable_transactions.spill.as_ref().unwrap().index.is_empty(), "Nothing spilled");
+        assert_eq!(model.revertable_transactions.get(1).and_then(|tr| tr.timestamp), parse_timestamp("2024-01-01T10:00:00Z").ok());
+
+        for id in [1, 2] {
+            assert_eq!(expected.client(id), model.client(id), "Client data mismatch for client {}", id);
+        }
+
+        // Second model in the same dir has its own file, which goes away with it
+        let mut other = Model::default();
+        other.set_spill_dir(dir.path(), 2).expect("Error creating spill file");
+        process_text(&mut other, csv_text);
+        assert_eq!(std::fs::read_dir(dir.path()).expect("Error listing spill dir").count(), 2);
+        drop(other);
+        assert_eq!(model.revertable_transactions.get(1).map(|tr| tr.tx), Some(1));
+        assert_eq!(std::fs::read_dir(dir.path()).expect("Error listing spill dir").count(), 1);
+    }
+
+    fn process_text(model: &mut Model, csv_text: &str) {
+        model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+    }
+
+    fn transaction(tr_type: TransactionType, client: ClientID, tx: TransactionID, amount: Option<&str>) -> Transaction {
+        Transaction { tr_type, client, tx, amount: amount.map(money), timestamp: None, batch: None, empty_amount: false, integer_amount: false }
+    }
+
+    // Model written to a snapshot and read back with the same config
+    fn round_trip(model: &mut Model) -> Model {
+        let mut snapshot = Vec::new();
+        model.write_snapshot(&mut snapshot).expect("Error writing snapshot");
+        Model::from_snapshot(model.config.clone(), snapshot.as_slice()).expect("Error reading snapshot")
+    }
+
+    // Fresh model's report and accounts checksum, for comparing ways of reading the same input
+    fn process_checksum<R: Read>(reader: R) -> (ProcessingReport, String) {
+        let mut model = Model::default();
+        let report = model.process_reader(reader).expect("Processing failed");
+        (report, model.accounts_checksum(&OutputOptions::default()).expect("Error computing checksum"))
+    }
+
+    #[test]
+    fn test_rollback_deposit() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\n");
+
+        assert_eq!(model.rollback(2), Ok(RollbackEffect::Deposit { client: 1, amount: money("2.0") }));
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("1.0"), money("0.0"), money("1.0")));
+
+        // Rolled back transaction can't be disputed or rolled back again
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 2,\n");
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("1.0"), money("0.0"), money("1.0")));
+        assert_eq!(model.rollback(2), Err(RollbackError::AlreadyRolledBack(2)));
+    }
+
+    #[test]
+    fn test_rollback_withdrawal() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 3.0\nwithdrawal, 1, 2, 2.0\nrollback, 1, 2,\n");
+
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("3.0"), money("0.0"), money("3.0")));
+        assert_eq!(model.rollback(2), Err(RollbackError::AlreadyRolledBack(2)));
+    }
+
+    #[test]
+    fn test_rollback_refused() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+withdrawal, 1, 3, 10.0
+dispute, 1, 1,
+dispute, 1, 2,
+chargeback, 1, 2,
+rollback, 2, 1,
+");
+
+        assert_eq!(model.rollback(1), Err(RollbackError::Disputed(1)));
+        assert_eq!(model.rollback(2), Err(RollbackError::ChargedBack(2)));
+        // Insufficient funds withdrawal was never applied
+        assert_eq!(model.rollback(3), Err(RollbackError::UnknownTransaction(3)));
+        assert_eq!(model.rollback(4), Err(RollbackError::UnknownTransaction(4)));
+
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone(), client.locked), (money("0.0"), money("1.0"), money("1.0"), true));
+
+        // Deposit already mostly withdrawn, reversing it would overdraw
+        for (overdraft_limit, result, available) in [("0", Err(RollbackError::InsufficientFunds(1)), "2.0"), ("10", Ok(()), "-8.0")] {
+            let mut model = Model::with_config(ModelConfig { overdraft_limit: money(overdraft_limit), ..ModelConfig::default() });
+            process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 8.0\n");
+            assert_eq!(model.rollback(1).map(|_| ()), result);
+            assert_eq!(model.available(1), Some(money(available)));
+            assert_eq!(model.audit().expect("Audit failed"), vec![]);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Lines written by each flush
+    #[derive(Clone, Default)]
+    struct FlushCounter {
+        buffer: SharedBuffer,
+        flushed_lines: std::rc::Rc<std::cell::RefCell<Vec<usize>>>,
+    }
+
+    impl Write for FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.buffer.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let lines = self.buffer.0.borrow().iter().filter(|byte| **byte == b'\n').count();
+            self.flushed_lines.borrow_mut().push(lines);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_events_flush_interval() {
+        let counter = FlushCounter::default();
+        let mut model = Model::default();
+        model.set_buffered_events_writer(Box::new(counter.clone()), 2);
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 1.0\n\
+            withdrawal, 1, 3, 5.0\ndeposit, 1, 4, 1.0\ndeposit, 1, 5, 1.0\ndeposit, 1, 6, 1.0\n");
+        // Rejected withdrawal emits no event, the last odd one is flushed at the end of input
+        assert_eq!(*counter.flushed_lines.borrow(), vec![2, 4, 5]);
+    }
+
+    // Minor-units backend has no scale to keep
+    #[cfg(not(feature = "minor-units"))]
+    #[test]
+    fn test_amount_scale_round_trip() {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let buffer = SharedBuffer::default();
+        let mut model = Model::default();
+        model.set_events_writer(Box::new(buffer.clone()));
+        model.set_spill_dir(dir.path(), 1).expect("Error creating spill file");
+        let amounts = ["10.5", "3", "0.25", "100.000", "1.12345678"];
+        let mut csv_text = String::from("type, client, tx, amount\n");
+        for (tx, amount) in (1..).zip(amounts) {
+            csv_text.push_str(&format!("deposit, {}, {}, {}\ndispute, {}, {},\n", tx, tx, amount, tx, tx));
+        }
+        process_text(&mut model, &csv_text);
+
+        // Both deposit and dispute (of spilled transaction) events show amount as it was written
+        let text = String::from_utf8(buffer.0.borrow().clone()).expect("Invalid UTF-8");
+        let logged: Vec<String> = text.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).expect("Invalid event")["amount"].as_str().unwrap().to_string())
+            .collect();
+        let expected: Vec<&str> = amounts.iter().flat_map(|amount| [*amount, *amount]).collect();
+        assert_eq!(logged, expected);
+        // Accounts keep canonical four decimals
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert!(String::from_utf8(output).unwrap().contains("\n1,0.0000,10.5000,10.5000,false\n"));
+    }
+
+    #[test]
+    fn test_events_dispute_lifecycle() {
+        let buffer = SharedBuffer::default();
+        let mut model = Model::default();
+        model.set_events_writer(Box::new(buffer.clone()));
+        process_text(&mut model, "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.0
+withdrawal, 1, 3, 10.0
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 1, 2,
+chargeback, 1, 2,
+");
+
+        let text = String::from_utf8(buffer.0.borrow().clone()).expect("Invalid UTF-8");
+        let events: Vec<Event> = text.lines()
+            .map(|line| serde_json::from_str(line).expect("Invalid event"))
+            .collect();
+
+        // Rejected withdrawal is not an event
+        let kinds: Vec<(TransactionType, TransactionID)> = events.iter().map(|e| (e.kind, e.tx)).collect();
+        assert_eq!(kinds, vec![
+            (TransactionType::Deposit, 1),
+            (TransactionType::Deposit, 2),
+            (TransactionType::Dispute, 1),
+            (TransactionType::Resolve, 1),
+            (TransactionType::Dispute, 2),
+            (TransactionType::Chargeback, 2),
+        ]);
+        for (i, event) in events.iter().enumerate() {
+            assert_eq!(event.seq, i as u64 + 1, "Sequence gap at {:?}", event);
+        }
+
+        let dispute = &events[2];
+        assert_eq!((dispute.amount.clone(), dispute.available.clone(), dispute.held.clone(), dispute.total.clone()), (money("1.0"), money("2.0"), money("1.0"), money("3.0")));
+        let chargeback = &events[5];
+        assert_eq!((chargeback.amount.clone(), chargeback.available.clone(), chargeback.held.clone(), chargeback.total.clone(), chargeback.locked),
+            (money("2.0"), money("1.0"), money("0.0"), money("1.0"), true));
+    }
+
+    #[test]
+    fn test_replay_round_trip() {
+        let buffer = SharedBuffer::default();
+        let mut model = Model::default();
+        model.set_events_writer(Box::new(buffer.clone()));
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 2, 10, 0.1\ndeposit, 2, 11, 0.2\ndispute, 2, 11,\nrollback, 2, 10,\n");
+
+        let events = buffer.0.borrow().clone();
+        let replayed = Model::replay_events(events.as_slice()).expect("Replay failed");
+
+        let mut expected = Vec::new();
+        model.write_accounts(&mut expected, &OutputOptions::default()).expect("Error writing accounts");
+        let mut actual = Vec::new();
+        replayed.write_accounts(&mut actual, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(expected), String::from_utf8(actual));
+        assert_eq!(model.disputed_transactions, replayed.disputed_transactions);
+    }
+
+    #[test]
+    fn test_replay_errors() {
+        let event = |seq| format!(r#"{{"seq":{},"kind":"deposit","client":1,"tx":{},"amount":1.0,"available":1.0,"held":0.0,"total":1.0,"locked":false}}"#, seq, seq);
+
+        let events = format!("{}\n{}\n", event(1), event(3));
+        let err = Model::replay_events(events.as_bytes()).err().expect("Replay should fail");
+        assert_eq!(err.seq, 3);
+
+        let events = format!("{}\n{{\"seq\":2,\n", event(1));
+        let err = Model::replay_events(events.as_bytes()).err().expect("Replay should fail");
+        assert_eq!(err.seq, 2);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut model = Model::default();
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        let stats = model.stats();
+        let counts = |applied, rejected| TypeCounts { applied, rejected };
+        assert_eq!(stats.by_type, BTreeMap::from([
+            ("chargeback", counts(1, 0)),
+            ("deposit", counts(3, 0)),
+            ("dispute", counts(1, 0)),
+            ("withdrawal", counts(1, 1)),
+        ]));
+        assert_eq!((stats.balance.clients, stats.balance.locked_clients), (2, 1));
+        assert_eq!((stats.balance.available.clone(), stats.balance.held.clone(), stats.balance.total.clone()), (money("2.5"), money("0"), money("2.5")));
+        assert!(stats.to_string().starts_with("chargeback: 1 applied, 0 rejected\ndeposit: 3 applied, 0 rejected\n"), "{}", stats);
+
+        // Counts add up across inputs
+        model.process_reader("type, client, tx, amount\ndeposit, 3, 6, 1.0\n".as_bytes()).expect("Processing failed");
+        assert_eq!(model.stats().by_type["deposit"], counts(4, 0));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut model = Model::default();
+        let metrics = model.metrics();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let sample: String = (1..=2000).map(|tx| format!("deposit, {}, {}, 1.0\n", tx % 10, tx)).collect();
+        let seen = std::thread::scope(|scope| {
+            // Reads while the model processes, without access to it
+            let reader = scope.spawn(|| {
+                let mut seen = vec![metrics.snapshot()];
+                while !done.load(AtomicOrdering::Relaxed) {
+                    seen.push(metrics.snapshot());
+                }
+                seen
+            });
+            process_text(&mut model, &format!("type, client, tx, amount\n{}", sample));
+            let middle = model.metrics_snapshot();
+            assert_eq!(middle, MetricsSnapshot { processed: 2000, rejected: 0, locked: 0 });
+            process_text(&mut model, "type, client, tx, amount\ndispute, 1, 1,\nchargeback, 1, 1,\nwithdrawal, 2, 2001, 500.0\n");
+            done.store(true, AtomicOrdering::Relaxed);
+            reader.join().unwrap()
+        });
+        assert!(seen.windows(2).all(|pair| pair[0].processed <= pair[1].processed && pair[0].rejected <= pair[1].rejected && pair[0].locked <= pair[1].locked));
+        let end = model.metrics_snapshot();
+        assert_eq!(end, MetricsSnapshot { processed: 2003, rejected: 1, locked: 1 });
+        assert_eq!(metrics.snapshot(), end);
+
+        // Locked clients of snapshot count from the start
+        let restored = round_trip(&mut model);
+        assert_eq!(restored.metrics_snapshot(), MetricsSnapshot { processed: 0, rejected: 0, locked: 1 });
+    }
+
+    #[test]
+    fn test_trial_balance() {
+        let mut model = Model::default();
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 3, 10, 0.1234\ndeposit, 3, 11, 1.0\ndispute, 3, 11,\n");
+
+        let balance = model.trial_balance();
+        assert_eq!((balance.clients, balance.locked_clients), (3, 1));
+        assert_eq!(balance.available, money("2.6234"));
+        assert_eq!(balance.held, money("1.0"));
+        assert_eq!(balance.locked, money("0.5"));
+        assert_eq!(balance.total, money("3.6234"));
+        assert!(balance.is_consistent());
+
+        let mut csv_out = Vec::new();
+        balance.write_csv(&mut csv_out).expect("Error writing trial balance");
+        assert_eq!(String::from_utf8(csv_out).unwrap(),
+            "clients,locked_clients,available,held,pending,locked,total\n3,1,2.6234,1.0000,0.0000,0.5000,3.6234\n");
+
+        // Deposits still pending are part of the total
+        let mut model = Model::with_config(ModelConfig { pending_delay: Some(5), ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 10.0\n");
+        let balance = model.trial_balance();
+        assert_eq!((balance.available.clone(), balance.held.clone(), balance.pending.clone(), balance.total.clone()),
+            (money("0"), money("0"), money("10.0"), money("10.0")));
+        assert!(balance.is_consistent());
+        assert!(balance.to_string().contains("Pending: 10.0000"), "{}", balance);
+    }
+
+    #[test]
+    fn test_client_invariant() {
+        let mut client = Client { available: money("1.5"), held: money("2.25"), total: money("3.75"), ..Client::new(1) };
+        assert!(client.check_invariant());
+        client.held = money("2.2");
+        assert!(!client.check_invariant());
+
+        let mut snapshot = Vec::new();
+        let mut model = Model::default();
+        model.clients.insert(1, client);
+        model.write_snapshot(&mut snapshot).expect("Error writing snapshot");
+        assert!(Model::from_snapshot(ModelConfig::default(), snapshot.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_audit_pass() {
+        for entry in std::fs::read_dir("cases").expect("Error listing cases") {
+            let path = entry.expect("Error listing cases").path();
+            let name = path.to_str().unwrap().to_string();
+            if !name.contains("-transactions-") || !name.ends_with(".csv") {
+                continue;
+            }
+            let mut model = Model::default();
+            model.process_transactions(&name).expect("Processing failed");
+            assert_eq!(model.audit().expect("Audit failed"), vec![], "{}", name);
+        }
+
+        let mut model = Model::default();
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        model.clients.get_mut(&1).expect("Client missing").held += &money("0.5");
+        let mismatches = model.audit().expect("Audit failed");
+        assert_eq!(mismatches.iter().map(|m| m.client).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(mismatches[0].actual.1, &mismatches[0].expected.1 + &money("0.5"));
+    }
+
+    #[test]
+    fn test_diff_accounts() {
+        let old = "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n2,1.0000,0.0000,1.0000,false\n3,2.0000,0.0000,2.0000,false\n";
+        let new = "client,available,held,total,locked\n1,3.5000,1.5000,5.0000,false\n2,1.0000,0.0000,1.0000,false\n4,0.5000,0.0000,0.5000,false\n";
+        let old = read_accounts(old.as_bytes()).expect("Error reading old");
+        let mut new = read_accounts(new.as_bytes()).expect("Error reading new");
+        let deltas = diff_accounts(&old, &new);
+        assert_eq!(deltas[0], AccountDelta::Changed {
+            client: 1, available: money("-1.5"), held: money("1.5"), total: money("0"), locked: None,
+        });
+        assert_eq!(deltas[0].to_string(), "client 1: available -1.5000, held +1.5000, total +0.0000");
+        assert_eq!(deltas[1], AccountDelta::Removed(old[2].clone()));
+        assert_eq!(deltas[2].to_string(), "client 4 added: available 0.5000, held 0.0000, total 0.5000, locked false");
+        assert_eq!(deltas.len(), 3);
+
+        new[1].locked = true;
+        assert_eq!(diff_accounts(&old, &new)[1].to_string(), "client 2: available +0.0000, held +0.0000, total +0.0000, locked");
+    }
+
+    #[test]
+    fn test_suspicious_clients() {
+        run_case("09-transactions-suspicious", "09-accounts-suspicious");
+
+        let mut model = Model::default();
+        model.process_transactions("cases/09-transactions-suspicious.csv").expect("Processing failed");
+        let thresholds = SarThresholds {
+            max_chargebacks: Some(0),
+            max_dispute_rate: Some(0.5),
+            max_insufficient_funds: Some(1),
+        };
+        let flagged: Vec<(ClientID, String)> = model.suspicious_clients(&thresholds).into_iter()
+            .map(|entry| (entry.client, entry.reasons))
+            .collect();
+        assert_eq!(flagged, vec![
+            (1, "chargebacks".to_string()),
+            (2, "dispute_rate".to_string()),
+            (3, "insufficient_funds".to_string()),
+        ]);
+
+        assert!(model.suspicious_clients(&SarThresholds::default()).is_empty());
+    }
+
+    #[test]
+    fn test_wide_transaction_ids() {
+        let big = 1u64 << 40;
+        let mut model = Model::default();
+        process_text(&mut model, &format!("type, client, tx, amount\ndeposit, 1, {}, 5.0\ndeposit, 1, {}, 1.0\n\
+            dispute, 1, {},\nresolve, 1, {},\ndispute, 1, {},\nchargeback, 1, {},\n", big, big + 1, big, big, big + 1, big + 1));
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.locked), (money("5.0"), money("0"), true));
+        assert_eq!(model.max_tx(), Some(big + 1));
+
+        // Same tx truncated to u32 is not known
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 0,\n");
+        assert_eq!(model.disputed().count(), 0);
+
+        let mut restored = round_trip(&mut model);
+        process_text(&mut restored, &format!("type, client, tx, amount\ndispute, 1, {},\n", big));
+        assert_eq!(restored.disputed().collect::<Vec<_>>(), vec![big]);
+    }
+
+    #[test]
+    fn test_wide_client_ids() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 70000, 1, 5.0\ndeposit, 4464, 2, 1.0\n\
+            dispute, 4464, 1,\ndispute, 70000, 1,\ndeposit, 4000000000, 3, 2.0\n");
+        // 70000 truncated to u16 is 4464, dispute from it is a client mismatch
+        assert_eq!(model.held(4464), Some(money("0")));
+        assert_eq!(model.held(70000), Some(money("5.0")));
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n\
+            4464,1.0000,0.0000,1.0000,false\n70000,0.0000,5.0000,5.0000,false\n4000000000,2.0000,0.0000,2.0000,false\n");
+
+        let mut restored = round_trip(&mut model);
+        process_text(&mut restored, "type, client, tx, amount\nchargeback, 70000, 1,\n");
+        assert_eq!(restored.is_locked(70000), Some(true));
+    }
+
+    #[test]
+    fn test_pending_delay() {
+        let mut model = Model::with_config(ModelConfig { pending_delay: Some(2), ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 5.0\n");
+        let balances = |model: &Model| {
+            let client = model.client(1).expect("Client missing");
+            (client.available.clone(), client.pending.clone(), client.total.clone())
+        };
+        assert_eq!(balances(&model), (money("0"), money("5.0"), money("5.0")));
+        // Pending funds can't be withdrawn, rejected row still counts
+        process_text(&mut model, "type, client, tx, amount\nwithdrawal, 1, 2, 1.0\n");
+        assert_eq!(balances(&model), (money("0"), money("5.0"), money("5.0")));
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 2, 3, 1.0\n");
+        assert_eq!(balances(&model), (money("5.0"), money("0"), money("5.0")));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Survives snapshot, disputed pending deposit is held and never settles
+        let mut model = round_trip(&mut model);
+        assert_eq!(model.pending(2), Some(money("1.0")));
+        process_text(&mut model, "type, client, tx, amount\ndispute, 2, 3,\ndeposit, 3, 4, 1.0\ndeposit, 3, 5, 1.0\n");
+        assert_eq!((model.available(2), model.held(2), model.pending(2)), (Some(money("0")), Some(money("1.0")), Some(money("0"))));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert!(String::from_utf8(output).unwrap().starts_with("client,available,held,pending,total,locked\n1,5.0000,0.0000,0.0000,5.0000,false\n"));
+    }
+
+    #[test]
+    fn test_retention() {
+        let timed = |tr_type, client, tx, amount, timestamp: Option<i64>| Transaction {
+            timestamp: timestamp.and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            ..transaction(tr_type, client, tx, amount)
+        };
+        let deposit = |client, tx, timestamp| timed(TransactionType::Deposit, client, tx, Some("1.0"), timestamp);
+        let dispute = |client, tx, timestamp| timed(TransactionType::Dispute, client, tx, None, timestamp);
+
+        // By count: disputable for 2 subsequent transactions, expired on the third
+        let mut model = Model::with_config(ModelConfig { retention_transactions: Some(2), ..ModelConfig::default() });
+        assert_eq!(model.process_transaction(deposit(1, 1, None)), Ok(()));
+        assert_eq!(model.process_transaction(deposit(1, 2, None)), Ok(()));
+        assert_eq!(model.process_transaction(dispute(1, 1, None)), Ok(()));
+        assert_eq!(model.process_transaction(dispute(1, 2, None)), Ok(()));
+        assert_eq!(model.process_transaction(deposit(2, 3, None)), Ok(()));
+        // Disputed ones are kept, tx 3 is not yet expired
+        assert_eq!(model.stored_transactions(), 3);
+        model.process_transaction(deposit(2, 4, None)).unwrap();
+        model.process_transaction(deposit(2, 5, None)).unwrap();
+        assert_eq!(model.process_transaction(dispute(2, 3, None)), Err(RejectReason::Expired { tx: 3 }));
+        assert!(RejectReason::CODES.contains(&RejectReason::Expired { tx: 3 }.code()));
+        assert_eq!(model.process_transaction(dispute(2, 9, None)), Err(RejectReason::UnknownTransaction { tx: 9 }));
+        // Evicted tx can't be reused either
+        assert_eq!(model.process_transaction(deposit(2, 3, None)), Err(RejectReason::DuplicateTransaction { tx: 3 }));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Survives snapshot, resolved deposit expires one period after being requeued
+        let mut model = round_trip(&mut model);
+        assert_eq!(model.process_transaction(timed(TransactionType::Resolve, 1, 1, None, None)), Ok(()));
+        assert_eq!(model.process_transaction(dispute(2, 3, None)), Err(RejectReason::Expired { tx: 3 }));
+        for tx in 10..14 {
+            model.process_transaction(deposit(3, tx, None)).unwrap();
+        }
+        assert_eq!(model.process_transaction(dispute(1, 1, None)), Err(RejectReason::Expired { tx: 1 }));
+        assert_eq!(model.available(1), Some(money("1.0")));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // By age when deposit has timestamp, measured against latest input timestamp
+        let config = ModelConfig { retention_secs: Some(100), retention_transactions: Some(1), ..ModelConfig::default() };
+        let mut model = Model::with_config(config);
+        model.process_transaction(deposit(1, 1, Some(1000))).unwrap();
+        model.process_transaction(deposit(1, 2, None)).unwrap();
+        model.process_transaction(deposit(1, 3, Some(1050))).unwrap();
+        // tx 2 has no timestamp, so count applies
+        assert_eq!(model.process_transaction(dispute(1, 2, None)), Err(RejectReason::Expired { tx: 2 }));
+        assert_eq!(model.process_transaction(dispute(1, 1, Some(1099))), Ok(()));
+        assert_eq!(model.process_transaction(dispute(1, 3, Some(1150))), Err(RejectReason::Expired { tx: 3 }));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Older deposit arriving after a newer one still expires on time
+        let mut model = Model::with_config(ModelConfig { retention_secs: Some(100), ..ModelConfig::default() });
+        model.process_transaction(deposit(1, 1, Some(2000))).unwrap();
+        model.process_transaction(deposit(1, 2, Some(1900))).unwrap();
+        model.process_transaction(deposit(1, 3, Some(2010))).unwrap();
+        assert_eq!(model.process_transaction(dispute(1, 2, None)), Err(RejectReason::Expired { tx: 2 }));
+        assert_eq!(model.process_transaction(dispute(1, 1, None)), Ok(()));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Long run keeps only recent deposits and withdrawals in memory
+        let mut input = Vec::new();
+        let spec = sample::SampleSpec { transactions: 20_000, ..sample::SampleSpec::default() };
+        sample::write_sample(&mut input, &spec).expect("Error writing sample");
+        let mut unbounded = Model::default();
+        unbounded.process_reader(input.as_slice()).expect("Processing failed");
+        let mut model = Model::with_config(ModelConfig { retention_transactions: Some(1000), ..ModelConfig::default() });
+        let report = model.process_reader(input.as_slice()).expect("Processing failed");
+        assert!(model.stored_transactions() * 2 < unbounded.stored_transactions(), "{} {}", model.stored_transactions(), unbounded.stored_transactions());
+        assert_eq!(model.trial_balance().clients, unbounded.trial_balance().clients);
+        assert!(report.rejected.contains_key("expired"), "{:?}", report.rejected);
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+    }
+
+    #[test]
+    fn test_undo_last() {
+        let snapshot = |model: &mut Model| {
+            let mut snapshot = Vec::new();
+            model.write_snapshot(&mut snapshot).expect("Error writing snapshot");
+            String::from_utf8(snapshot).unwrap()
+        };
+        let mut model = Model::with_config(ModelConfig { undo_log: true, ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 5.0\ndeposit, 1, 2, 2.0\n");
+        let after_deposit = snapshot(&mut model);
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 1,\n");
+        assert_eq!((model.available(1), model.held(1)), (Some(money("2.0")), Some(money("5.0"))));
+        assert_eq!(model.undo_last().map(|tr| (tr.tr_type, tr.tx)), Some((TransactionType::Dispute, 1)));
+        assert_eq!(snapshot(&mut model), after_deposit);
+        // Single level
+        assert!(model.undo_last().is_none());
+
+        // Chargeback unlocks again, rejected transaction leaves nothing to undo
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 1,\nchargeback, 1, 1,\n");
+        assert_eq!(model.metrics_snapshot().locked, 1);
+        assert!(model.undo_last().is_some());
+        assert_eq!((model.is_locked(1), model.held(1), model.metrics_snapshot().locked), (Some(false), Some(money("5.0")), 0));
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 1,\nwithdrawal, 1, 3, 9.0\n");
+        assert!(model.undo_last().is_none());
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Deposit creating client, with deposits released from pending after it
+        let mut model = Model::with_config(ModelConfig { undo_log: true, pending_delay: Some(1), ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 5.0\n");
+        let after_deposit = snapshot(&mut model);
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 2, 2, 1.0\n");
+        assert_eq!(model.available(1), Some(money("5.0")));
+        assert!(model.undo_last().is_some());
+        assert_eq!(snapshot(&mut model), after_deposit);
+        assert_eq!(model.client(2), None);
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Later timestamp evicting an earlier deposit, both taken back with it
+        let mut model = Model::with_config(ModelConfig { undo_log: true, retention_secs: Some(100), ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount, timestamp\ndeposit, 1, 1, 5.0, 1000\n");
+        let after_deposit = snapshot(&mut model);
+        process_text(&mut model, "type, client, tx, amount, timestamp\ndeposit, 1, 2, 2.0, 1200\n");
+        assert_eq!(model.stored_transactions(), 1);
+        assert!(model.undo_last().is_some());
+        assert_eq!(snapshot(&mut model), after_deposit);
+        process_text(&mut model, "type, client, tx, amount, timestamp\ndispute, 1, 1,, 1050\n");
+        assert_eq!(model.held(1), Some(money("5.0")));
+
+        // Off by default
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 5.0\n");
+        assert!(model.undo_last().is_none());
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let input = std::fs::read("cases/20-transactions-messy.csv").expect("Error reading input");
+        let expected = std::fs::read_to_string("cases/20-transactions-canonical.csv").expect("Error reading expected output");
+        let model = Model::default();
+        let mut output = Vec::new();
+        assert_eq!(model.write_canonical(&input[..], &mut output).expect("Error canonicalizing"), 6);
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, expected);
+
+        // Canonical file is its own canonical form, and processes like the original
+        let mut again = Vec::new();
+        model.write_canonical(output.as_bytes(), &mut again).expect("Error canonicalizing");
+        assert_eq!(String::from_utf8(again).unwrap(), expected);
+        let process = |input: &[u8]| {
+            let mut model = Model::with_config(ModelConfig { normalize_types: true, ..ModelConfig::default() });
+            let report = model.process_reader(input).expect("Processing failed");
+            (report.applied, report.rejected, model.trial_balance())
+        };
+        assert_eq!(process(output.as_bytes()), process(&input));
+
+        // Sorted by timestamp when requested, rows without one first
+        let model = Model::with_config(ModelConfig { order_by_timestamp: true, ..ModelConfig::default() });
+        let mut output = Vec::new();
+        model.write_canonical(&input[..], &mut output).expect("Error canonicalizing");
+        let txs: Vec<String> = String::from_utf8(output).unwrap().lines().skip(1).map(|line| line.split(',').take(3).collect::<Vec<_>>().join(",")).collect();
+        assert_eq!(txs, ["deposit,2,3", "resolve,2,3", "deposit,1,1", "withdrawal,1,2", "dispute,1,1", "chargeback,1,1"]);
+    }
+
+    #[test]
+    fn test_velocity_limits() {
+        let input = std::fs::read("cases/21-transactions-velocity.csv").expect("Error reading input");
+        let run = |config: ModelConfig| {
+            let mut model = Model::with_config(config);
+            let report = model.process_reader(&input[..]).expect("Processing failed");
+            let rejected: Vec<(&str, u64)> = report.rejected.into_iter().collect();
+            let clients: Vec<(Money, bool, u32)> = [1, 2].iter().map(|&id| {
+                let client = model.client(id).expect("Client missing");
+                (client.total.clone(), client.locked, model.counters[&id].velocity_breaches)
+            }).collect();
+            (model, rejected, clients)
+        };
+        let rejected = |code, count| vec![(code, count)];
+
+        // Per run, excess rejected
+        let (model, reasons, clients) = run(ModelConfig { max_transactions_per_client: Some(2), ..ModelConfig::default() });
+        assert_eq!(reasons, rejected("too_many_transactions", 3));
+        assert_eq!(clients, vec![(money("20.0"), false, 2), (money("2.0"), false, 1)]);
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked,transactions,withdrawn,velocity_breaches\n\
+            1,20.0000,0.0000,20.0000,false,2,0.0000,2\n2,2.0000,0.0000,2.0000,false,2,0.0000,1\n");
+
+        // Window counts only recent timestamped rows, rows without timestamp count per run
+        let (_, reasons, clients) = run(ModelConfig { max_transactions_per_client: Some(2), velocity_window_secs: Some(3600), ..ModelConfig::default() });
+        assert_eq!(reasons, rejected("too_many_transactions", 2));
+        assert_eq!(clients, vec![(money("16.0"), false, 1), (money("2.0"), false, 1)]);
+
+        let (_, reasons, clients) = run(ModelConfig { max_withdrawn_per_client: Some(money("5")), ..ModelConfig::default() });
+        assert_eq!(reasons, rejected("over_withdrawal_limit", 1));
+        assert_eq!(clients, vec![(money("16.0"), false, 1), (money("3.0"), false, 0)]);
+
+        // Warn only applies everything, lock account rejects and locks
+        let config = ModelConfig { max_transactions_per_client: Some(2), velocity_action: VelocityAction::WarnOnly, ..ModelConfig::default() };
+        let (_, reasons, clients) = run(config);
+        assert_eq!(reasons, vec![]);
+        assert_eq!(clients, vec![(money("12.0"), false, 2), (money("3.0"), false, 1)]);
+        let config = ModelConfig { max_withdrawn_per_client: Some(money("5")), velocity_action: VelocityAction::LockAccount, ..ModelConfig::default() };
+        let (_, reasons, clients) = run(config);
+        assert_eq!(reasons, rejected("over_withdrawal_limit", 1));
+        assert_eq!(clients, vec![(money("16.0"), true, 1), (money("3.0"), false, 0)]);
+        assert!(RejectReason::CODES.contains(&RejectReason::OverWithdrawalLimit { limit: money("5") }.code()));
+
+        // Rows rejected for another reason are no breach and don't lock
+        let config = ModelConfig {
+            max_withdrawn_per_client: Some(money("5")),
+            max_balance: Some(money("20")),
+            velocity_action: VelocityAction::LockAccount,
+            ..ModelConfig::default()
+        };
+        let mut model = Model::with_config(config);
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 12.0\nwithdrawal, 1, 1, 6.0\ndeposit, 1, 3, 30.0\n";
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!(report.rejected, BTreeMap::from([("duplicate_transaction", 1), ("insufficient_funds", 1), ("over_max_balance", 1)]));
+        assert_eq!((model.is_locked(1), model.counters[&1].velocity_breaches), (Some(false), 0));
+        let report = model.process_reader("type, client, tx, amount\nwithdrawal, 1, 4, 6.0\n".as_bytes()).expect("Processing failed");
+        assert_eq!(report.rejected, BTreeMap::from([("over_withdrawal_limit", 1)]));
+        assert_eq!((model.is_locked(1), model.counters[&1].velocity_breaches), (Some(true), 1));
+
+        // Policy keys, command line limits win
+        let policy: Policy = toml::from_str("max_transactions_per_client = 5\nmax_withdrawn_per_client = \"100\"\nvelocity_action = \"lock-account\"\n").expect("Invalid policy");
+        let mut config = ModelConfig { max_transactions_per_client: Some(2), ..ModelConfig::default() };
+        policy.apply(&mut config);
+        assert_eq!((config.max_transactions_per_client, config.max_withdrawn_per_client, config.velocity_action), (Some(2), Some(money("100")), VelocityAction::LockAccount));
+    }
+
+    #[test]
+    fn test_redispute_policy() {
+        let (dispute, resolve) = (transaction(TransactionType::Dispute, 1, 1, None), transaction(TransactionType::Resolve, 1, 1, None));
+        let balances = |model: &Model| (model.available(1).unwrap(), model.held(1).unwrap());
+        for (redispute, redisputes) in [(Redispute::Allow, 3), (Redispute::Forbid, 0), (Redispute::UpTo(1), 1)] {
+            let mut model = Model::with_config(ModelConfig { redispute, ..ModelConfig::default() });
+            model.process_transaction(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+            assert_eq!(model.process_transaction(dispute.clone()), Ok(()));
+            assert_eq!(model.process_transaction(resolve.clone()), Ok(()));
+            for disputes in 1..=3 {
+                let result = model.process_transaction(dispute.clone());
+                if disputes <= redisputes {
+                    assert_eq!(result, Ok(()), "{:?}", redispute);
+                    assert_eq!(balances(&model), (money("0"), money("10.0")));
+                    assert_eq!(model.process_transaction(resolve.clone()), Ok(()));
+                } else {
+                    assert_eq!(result, Err(RejectReason::RedisputeNotAllowed { tx: 1, disputes: redisputes + 1 }), "{:?}", redispute);
+                    assert_eq!(balances(&model), (money("10.0"), money("0")));
+                    assert_eq!(model.process_transaction(resolve.clone()), Err(RejectReason::NotDisputed { tx: 1 }));
+                }
+            }
+            assert_eq!(balances(&model), (money("10.0"), money("0")));
+            assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+            // Charged back is terminal, checked before the count
+            let chargeback = transaction(TransactionType::Chargeback, 1, 1, None);
+            let mut model = Model::with_config(ModelConfig { redispute, ..ModelConfig::default() });
+            model.process_transaction(transaction(TransactionType::Deposit, 1, 1, Some("10.0"))).unwrap();
+            assert_eq!(model.process_transaction(dispute.clone()), Ok(()));
+            assert_eq!(model.process_transaction(chargeback.clone()), Ok(()));
+            assert_eq!(model.process_transaction(dispute.clone()), Err(RejectReason::AlreadyChargedBack { tx: 1 }), "{:?}", redispute);
+            assert_eq!(model.process_transaction(chargeback), Err(RejectReason::AlreadyChargedBack { tx: 1 }));
+            assert_eq!((model.total(1), model.held(1)), (Some(money("0")), Some(money("0"))));
+        }
+
+        // Count survives snapshot
+        let mut model = Model::with_config(ModelConfig { redispute: Redispute::Forbid, ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndispute, 1, 1,\nresolve, 1, 1,\n");
+        let mut model = round_trip(&mut model);
+        assert_eq!(model.process_transaction(dispute), Err(RejectReason::RedisputeNotAllowed { tx: 1, disputes: 1 }));
+        assert!(RejectReason::CODES.contains(&RejectReason::RedisputeNotAllowed { tx: 1, disputes: 1 }.code()));
+        assert_eq!("2".parse::<Redispute>(), Ok(Redispute::UpTo(2)));
+    }
+
+    #[test]
+    fn test_disputable_withdrawals() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 4.0\ndispute, 1, 2,\n";
+        let balances = |model: &Model| {
+            let client = model.client(1).expect("Client missing");
+            (client.available.clone(), client.held.clone(), client.total.clone(), client.locked)
+        };
+        let mut model = Model::default();
+        let report = model.process_reader(input.as_bytes()).expect("Processing failed");
+        assert_eq!(report.rejected.get("not_deposit"), Some(&1));
+
+        let mut model = Model::with_config(ModelConfig { disputable: Disputable::DepositsAndWithdrawals, ..ModelConfig::default() });
+        let report = model.process_reader(input.as_bytes()).expect("Processing failed");
+        assert_eq!(report.rejected.get("not_deposit"), None);
+        assert_eq!(balances(&model), (money("6.0"), money("4.0"), money("10.0"), false));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+        let mut resolved = round_trip(&mut model);
+        // Resolve lets withdrawal stand, chargeback reverses it
+        process_text(&mut resolved, "type, client, tx, amount\nresolve, 1, 2,\n");
+        assert_eq!(balances(&resolved), (money("6.0"), money("0"), money("6.0"), false));
+        assert_eq!(resolved.audit().expect("Audit failed"), vec![]);
+        process_text(&mut model, "type, client, tx, amount\nchargeback, 1, 2,\n");
+        assert_eq!(balances(&model), (money("10.0"), money("0"), money("10.0"), true));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+
+        // Custom rule, e.g. only large transactions
+        fn large(tr: &Transaction) -> bool {
+            tr.amount.as_ref().is_some_and(|amount| *amount >= money("5"))
+        }
+        let mut model = Model::with_config(ModelConfig { disputable: Disputable::Custom(large), ..ModelConfig::default() });
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 1, 2, 1.0\nwithdrawal, 1, 3, 2.0\ndispute, 1, 1,\ndispute, 1, 2,\ndispute, 1, 3,\n";
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        // Withdrawal refused by the rule too, not reported as not_deposit
+        assert_eq!(report.rejected, BTreeMap::from([("policy_refused", 2)]));
+        assert_eq!(model.held(1), Some(money("10.0")));
+        assert_eq!(model.process_transaction(transaction(TransactionType::Dispute, 1, 3, None)), Err(RejectReason::PolicyRefused { tx: 3 }));
+        assert_eq!(RejectReason::PolicyRefused { tx: 3 }.code(), "policy_refused");
+        assert!(RejectReason::CODES.contains(&"policy_refused"));
+    }
+
+    #[test]
+    fn test_warn_integer_amounts() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 100\ndeposit, 1, 2, 100.00\nwithdrawal, 1, 3, 1.5\ndispute, 1, 1,\n";
+        let mut model = Model::with_config(ModelConfig { warn_integer_amounts: true, ..ModelConfig::default() });
+        let report = model.process_reader(input.as_bytes()).expect("Processing failed");
+        // Flagged only, still applied
+        assert_eq!((report.integer_amounts, report.applied), (1, 4));
+        assert_eq!(model.held(1), Some(money("100")));
+
+        let mut model = Model::default();
+        let report = model.process_reader(input.as_bytes()).expect("Processing failed");
+        assert_eq!(report.integer_amounts, 0);
+    }
+
+    #[test]
+    fn test_lock_policy() {
+        // Client 1 locked by chargeback of tx 1, deposit 3 still disputed, deposit 2 not
+        let setup = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 1, 2, 5.0\ndeposit, 1, 3, 3.0\n\
+            dispute, 1, 3,\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        let rows = [
+            ("deposit", "deposit, 1, 10, 1.0"),
+            ("withdrawal", "withdrawal, 1, 11, 1.0"),
+            ("dispute", "dispute, 1, 2,"),
+            ("resolve", "resolve, 1, 3,"),
+            ("chargeback", "chargeback, 1, 3,"),
+            ("rollback", "rollback, 1, 2,"),
+        ];
+        let policies = [
+            (None, [false, false, false, false, false, false]),
+            (Some(LockPolicy::FreezeAll), [true, true, true, true, true, true]),
+            (Some(LockPolicy::FreezeMovementAllowDisputes), [true, true, false, false, false, true]),
+            (Some(LockPolicy::FreezeWithdrawalsOnly), [false, true, false, false, false, false]),
+        ];
+        for (lock_policy, frozen) in policies {
+            for ((name, row), frozen) in rows.iter().zip(frozen) {
+                let mut model = Model::with_config(ModelConfig { lock_policy, ..ModelConfig::default() });
+                let report = model.process_reader(setup.as_bytes()).expect("Processing failed");
+                assert_eq!((report.applied, model.is_locked(1)), (6, Some(true)));
+                let before = model.client(1).cloned();
+
+                let report = model.process_reader(format!("type, client, tx, amount\n{}\n", row).as_bytes()).expect("Processing failed");
+                let expected = if frozen { Some(&1) } else { None };
+                assert_eq!(report.rejected.get("account_locked"), expected, "{:?} {}", lock_policy, name);
+                assert_eq!((report.applied, model.client(1).cloned() == before), (!frozen as u64, frozen), "{:?} {}", lock_policy, name);
+            }
+        }
+
+        // Unlocked accounts are not affected
+        let mut model = Model::with_config(ModelConfig { lock_policy: Some(LockPolicy::FreezeAll), ..ModelConfig::default() });
+        let report = model.process_reader("type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 0.5\ndispute, 1, 1,\n".as_bytes()).expect("Processing failed");
+        assert_eq!(report.applied, 3);
+        assert_eq!("freeze-movement-allow-disputes".parse(), Ok(LockPolicy::FreezeMovementAllowDisputes));
+        assert!("freeze".parse::<LockPolicy>().is_err());
+        assert!(RejectReason::CODES.contains(&RejectReason::AccountLocked { client: 1 }.code()));
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_flow() {
+        let config = ModelConfig { disputable: Disputable::DepositsAndWithdrawals, ..ModelConfig::default() };
+        let setup = "type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 4.0\n";
+        let run = |rows: &str| {
+            let mut model = Model::with_config(config.clone());
+            process_text(&mut model, setup);
+            let report = model.process_reader(format!("type, client, tx, amount\n{}", rows).as_bytes()).expect("Processing failed");
+            assert_eq!(model.audit().expect("Audit failed"), vec![], "{}", rows);
+            let client = model.client(1).expect("Client missing");
+            let balances = (client.available.clone(), client.held.clone(), client.total.clone(), client.locked);
+            (balances, report.rejected)
+        };
+        let rejected = |code| BTreeMap::from([(code, 1)]);
+
+        // Claim held, available untouched
+        assert_eq!(run("dispute, 1, 2,\n"), ((money("6.0"), money("4.0"), money("10.0"), false), BTreeMap::new()));
+        // Resolve drops the claim, back to balances before dispute
+        assert_eq!(run("dispute, 1, 2,\nresolve, 1, 2,\n"), ((money("6.0"), money("0"), money("6.0"), false), BTreeMap::new()));
+        // Chargeback pays the withdrawal back once
+        assert_eq!(run("dispute, 1, 2,\nchargeback, 1, 2,\n"), ((money("10.0"), money("0"), money("10.0"), true), BTreeMap::new()));
+        assert_eq!(run("dispute, 1, 2,\nchargeback, 1, 2,\ndispute, 1, 2,\nchargeback, 1, 2,\n"),
+            ((money("10.0"), money("0"), money("10.0"), true), BTreeMap::from([("already_charged_back", 2)])));
+        // Resolved claim may be raised again
+        assert_eq!(run("dispute, 1, 2,\nresolve, 1, 2,\ndispute, 1, 2,\nchargeback, 1, 2,\n"), ((money("10.0"), money("0"), money("10.0"), true), BTreeMap::new()));
+        // Resolve/Chargeback without dispute, and dispute twice
+        assert_eq!(run("resolve, 1, 2,\n"), ((money("6.0"), money("0"), money("6.0"), false), rejected("not_disputed")));
+        assert_eq!(run("chargeback, 1, 2,\n"), ((money("6.0"), money("0"), money("6.0"), false), rejected("not_disputed")));
+        assert_eq!(run("dispute, 1, 2,\ndispute, 1, 2,\n"), ((money("6.0"), money("4.0"), money("10.0"), false), rejected("already_disputed")));
+        // Withdrawal and deposit disputed together
+        assert_eq!(run("dispute, 1, 2,\ndispute, 1, 1,\nchargeback, 1, 1,\nresolve, 1, 2,\n"), ((money("-4.0"), money("0"), money("-4.0"), true), BTreeMap::new()));
+        assert_eq!(RejectReason::AlreadyChargedBack { tx: 2 }.code(), *RejectReason::CODES.last().unwrap());
+    }
+
+    #[test]
+    fn test_chargeback_semantics() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 1, 2, 5.0\ndispute, 1, 1,\nchargeback, 1, 1,\n";
+        for (semantics, available, total) in [("clawback", "5.0", "5.0"), ("refund", "15.0", "15.0")] {
+            let chargeback_semantics = semantics.parse().expect("Invalid semantics");
+            let mut model = Model::with_config(ModelConfig { chargeback_semantics, ..ModelConfig::default() });
+            let report = model.process_reader(input.as_bytes()).expect("Processing failed");
+            assert_eq!(report.applied, 4);
+            let client = model.client(1).expect("Client missing");
+            assert_eq!((client.available.clone(), client.held.clone(), client.total.clone(), client.locked),
+                (money(available), money("0"), money(total), true), "{}", semantics);
+            assert_eq!(model.audit().expect("Audit failed"), vec![], "{}", semantics);
+
+            // Charged back deposit can't be rolled back, whichever way it went
+            assert!(matches!(model.rollback(1), Err(RollbackError::ChargedBack(1))));
+            // Nor charged back again
+            let report = model.process_reader("type, client, tx, amount\ndispute, 1, 1,\nchargeback, 1, 1,\n".as_bytes()).expect("Processing failed");
+            assert_eq!(report.rejected, BTreeMap::from([("already_charged_back", 2)]));
+            assert_eq!(model.total(1), Some(money(total)), "{}", semantics);
+        }
+        assert_eq!(ModelConfig::default().chargeback_semantics, ChargebackSemantics::Clawback);
+        assert!("reverse".parse::<ChargebackSemantics>().is_err());
+    }
+
+    #[test]
+    fn test_lock_cause() {
+        let buffer = SharedBuffer::default();
+        let mut model = Model::default();
+        model.set_events_writer(Box::new(buffer.clone()));
+        process_text(&mut model, "type, client, tx, amount, timestamp\ndeposit, 1, 1, 10.0, 2024-01-02T00:00:00Z\ndeposit, 1, 2, 5.0,\n\
+            dispute, 1, 1, ,\nchargeback, 1, 1, , 2024-01-03T00:00:00Z\ndispute, 1, 2, ,\nchargeback, 1, 2, ,\n");
+        let cause = |client: &Client| (client.locked, client.locked_by, client.locked_at, client.lock_reason);
+        let expected = (true, Some(1), Some("2024-01-03T00:00:00Z".parse().unwrap()), Some(LockReason::Chargeback));
+        // Later chargeback keeps the first cause
+        assert_eq!(cause(model.client(1).expect("Client missing")), expected);
+
+        let events = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let last: Event = serde_json::from_str(events.lines().last().unwrap()).expect("Invalid event");
+        assert_eq!((last.locked, last.locked_by, last.locked_at, last.lock_reason), expected);
+        let replayed = Model::replay_events(events.as_bytes()).expect("Replay failed");
+        assert_eq!(cause(replayed.client(1).expect("Client missing")), expected);
+
+        let restored = round_trip(&mut model);
+        assert_eq!(cause(restored.client(1).expect("Client missing")), expected);
+
+        // Policy lock records reason only
+        let config = ModelConfig { max_transactions_per_client: Some(1), velocity_action: VelocityAction::LockAccount, ..ModelConfig::default() };
+        let mut model = Model::with_config(config);
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 1.0\ndeposit, 2, 3, 1.0\n");
+        assert_eq!(cause(model.client(2).expect("Client missing")), (true, None, None, Some(LockReason::Velocity)));
+        assert_eq!(cause(model.client(1).expect("Client missing")), (false, None, None, None));
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions { lock_details: true, ..OutputOptions::default() }).expect("Error writing accounts");
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert!(lines[0].ends_with("locked,transactions,withdrawn,velocity_breaches,locked_by,locked_at,lock_reason"), "{}", output);
+        assert!(lines[1].ends_with(",false,1,0.0000,0,,,"), "{}", output);
+        assert!(lines[2].ends_with(",true,1,0.0000,1,,,velocity"), "{}", output);
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut model = Model::default();
+        let listing = |model: &Model, client| model.open_disputes(client).collect::<Vec<_>>();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 10.0\ndeposit, 1, 2, 5.0\ndeposit, 1, 3, 1.5\ndeposit, 2, 4, 2.0\n");
+        assert_eq!(listing(&model, 1), vec![]);
+
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 3,\ndispute, 1, 1,\ndispute, 2, 4,\ndispute, 1, 4,\n");
+        assert_eq!(listing(&model, 1), vec![(1, money("10.0")), (3, money("1.5"))]);
+        assert_eq!(listing(&model, 2), vec![(4, money("2.0"))]);
+
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 1,\nchargeback, 2, 4,\n");
+        assert_eq!(listing(&model, 1), vec![(3, money("1.5"))]);
+        assert_eq!(listing(&model, 2), vec![]);
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 2,\n");
+        assert_eq!(listing(&model, 1), vec![(2, money("5.0")), (3, money("1.5"))]);
+
+        let mut restored = round_trip(&mut model);
+        assert_eq!(listing(&restored, 1), listing(&model, 1));
+        assert_eq!(listing(&restored, 2), vec![]);
+        process_text(&mut restored, "type, client, tx, amount\nchargeback, 1, 2,\nresolve, 1, 3,\n");
+        assert_eq!(listing(&restored, 1), vec![]);
+        assert_eq!(listing(&model, 99), vec![]);
+    }
+
+    // Boundary of i64 minor units, one unit more is rejected instead of wrapping
+    #[cfg(feature = "minor-units")]
+    #[test]
+    fn test_balance_overflow() {
+        let max = "922337203685477.5807";
+        let mut model = Model::default();
+        assert_eq!(model.process_transaction(transaction(TransactionType::Deposit, 1, 1, Some(max))), Ok(()));
+        assert_eq!(model.process_transaction(transaction(TransactionType::Deposit, 1, 2, Some("0.0001"))), Err(RejectReason::BalanceOverflow));
+        assert_eq!(model.client(1).expect("Client missing").total, money(max));
+
+        assert_eq!(model.process_transaction(transaction(TransactionType::Withdrawal, 1, 3, Some("1.0"))), Ok(()));
+        assert_eq!(model.process_transaction(transaction(TransactionType::Deposit, 1, 4, Some("0.5"))), Ok(()));
+        assert_eq!(model.process_transaction(transaction(TransactionType::Dispute, 1, 1, None)), Ok(()));
+        assert_eq!(model.held(1), Some(money(max)));
+        // Available has room, total doesn't, so nothing moves
+        assert_eq!(model.process_transaction(transaction(TransactionType::Deposit, 1, 5, Some("1.0"))), Err(RejectReason::BalanceOverflow));
+        assert_eq!(model.available(1), Some(money("-0.5")));
+        assert_eq!(model.rollback(3), Err(RollbackError::Overflow(3)));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+        assert!(RejectReason::CODES.contains(&RejectReason::BalanceOverflow.code()));
+    }
+
+    // Arbitrary precision backend has no limit to reach
+    #[cfg(not(feature = "bigdecimal"))]
+    #[test]
+    fn test_lock_on_overflow() {
+        let max = if cfg!(feature = "minor-units") { "922337203685477.5807" } else { "79228162514264337593543950335" };
+        for lock_on_overflow in [false, true] {
+            let mut model = Model::with_config(ModelConfig { lock_on_overflow, ..ModelConfig::default() });
+            let csv_text = format!("type, client, tx, amount\ndeposit, 1, 1, {}\nwithdrawal, 1, 2, 1\ndeposit, 1, 3, 1\ndeposit, 2, 4, 1\n", max);
+            let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+            assert_eq!((report.parse_errors, report.applied), (0, 4));
+            // At the limit, one more unit doesn't fit
+            let report = model.process_reader("type, client, tx, amount\ndeposit, 1, 5, 1\n".as_bytes()).expect("Processing failed");
+            assert_eq!(report.rejected.get("balance_overflow"), Some(&1));
+            let client = model.client(1).expect("Client missing");
+            assert_eq!((client.available.clone(), client.total.clone(), client.locked), (money(max), money(max), lock_on_overflow));
+            assert_eq!(model.is_locked(2), Some(false));
+            assert_eq!(model.metrics_snapshot().locked, lock_on_overflow as u64);
+            assert_eq!(model.overflow_clients(), vec![(1, 1)]);
+            assert_eq!(model.audit().expect("Audit failed"), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_overdraft_limit() {
+        let policy: Policy = toml::from_str("overdraft_limit = \"5\"").expect("Invalid policy");
+        let mut config = ModelConfig::default();
+        policy.apply(&mut config);
+        let mut model = Model::with_config(config);
+        let withdrawal = |tx, amount| transaction(TransactionType::Withdrawal, 1, tx, Some(amount));
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n");
+        // Allowed only thanks to the limit
+        assert_eq!(model.process_transaction(withdrawal(2, "5.5")), Ok(()));
+        assert_eq!(model.available(1), Some(money("-4.5")));
+        let err = model.process_transaction(withdrawal(3, "1.0")).unwrap_err();
+        assert_eq!(err, RejectReason::InsufficientFunds { overdraft_limit: money("5") });
+        assert_eq!(err.to_string(), format!("insufficient funds, overdraft limit {}", money("5")));
+        // Deposit into overdrawn account, dispute within the limit isn't flagged
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 4, 2.0\ndispute, 1, 4,\n");
+        assert_eq!((model.available(1), model.held(1), model.is_locked(1)), (Some(money("-4.5")), Some(money("2.0")), Some(false)));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+        // Exactly at the limit is allowed
+        let mut boundary = Model::with_config(model.config.clone());
+        process_text(&mut boundary, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n");
+        assert_eq!(boundary.process_transaction(withdrawal(2, "6.0")), Ok(()));
+        assert_eq!(boundary.available(1), Some(money("-5.0")));
+        // Dispute beyond the limit, a deposit still goes in and isn't counted as insufficient funds
+        let mut overdrawn = Model::with_config(model.config.clone());
+        let report = overdrawn.process_reader("type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 8.0\ndispute, 1, 1,\ndeposit, 1, 3, 1.0\n".as_bytes())
+            .expect("Processing failed");
+        assert_eq!((report.applied, overdrawn.available(1)), (4, Some(money("-7.0"))));
+        assert_eq!(overdrawn.counters[&1].insufficient_funds, 0);
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,-4.5000,2.0000,-2.5000,false\n");
+
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n");
+        assert_eq!(model.process_transaction(withdrawal(2, "5.5")).map_err(|err| err.to_string()), Err("insufficient funds".to_string()));
+    }
+
+    #[test]
+    fn test_negative_available_policy() {
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 0.8\ndispute, 1, 1,\n";
+        for (policy, locked) in [("flag", false), ("lock", true), ("ignore", false)] {
+            let negative_available = policy.parse().expect("Invalid policy");
+            let mut model = Model::with_config(ModelConfig { negative_available, ..ModelConfig::default() });
+            process_text(&mut model, csv_text);
+            let client = model.client(1).expect("Client missing");
+            assert_eq!((client.available.clone(), client.held.clone(), client.total.clone(), client.locked),
+                (money("-0.8"), money("1.0"), money("0.2"), locked), "{}", policy);
+        }
+        assert!("freeze".parse::<NegativeAvailable>().is_err());
+    }
+
+    #[test]
+    fn test_max_open_disputes() {
+        let mut model = Model::with_config(ModelConfig { max_open_disputes: Some(1), ..ModelConfig::default() });
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 2, 3, 3.0\ndispute, 1, 1,\n");
+        let dispute = |model: &mut Model, client: ClientID, tx: TransactionID| {
+            model.process_transaction(transaction(TransactionType::Dispute, client, tx, None))
+        };
+        assert_eq!(dispute(&mut model, 1, 2), Err(RejectReason::TooManyOpenDisputes { open: 1 }));
+        // Cap is per client
+        assert_eq!(dispute(&mut model, 2, 3), Ok(()));
+
+        // Rebuilt from snapshot
+        let mut model = round_trip(&mut model);
+        assert_eq!(dispute(&mut model, 1, 2), Err(RejectReason::TooManyOpenDisputes { open: 1 }));
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 1,\n");
+        assert_eq!(dispute(&mut model, 1, 2), Ok(()));
+        assert_eq!(model.held(1), Some(money("2.0")));
+    }
+
+    #[test]
+    fn test_duplicates_across_files() {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let write = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, text).expect("Error writing input");
+            path.to_str().unwrap().to_string()
+        };
+        // Last row of the first day repeats at the start of the second one
+        let day1 = write("day1.csv", "type, client, tx, amount\ndeposit, 1, 1, 5.0\nwithdrawal, 1, 2, 1.0\n");
+        let day2 = write("day2.csv", "type, client, tx, amount\nwithdrawal, 1, 2, 1.0\ndeposit, 1, 3, 2.0\n");
+        let mut model = Model::default();
+        model.process_transactions(&day1).expect("Processing failed");
+        let report = model.process_transactions(&day2).expect("Processing failed");
+        assert_eq!((report.records, report.duplicates_identical), (2, 1));
+        assert_eq!(model.available(1), Some(money("6.0")));
+
+        // Reuse with other amount (or scale) is rejected
+        let day3 = write("day3.csv", "type, client, tx, amount, batch\ndeposit, 1, 3, 2.00, b1\ndeposit, 2, 1, 7.0, b1\n");
+        let report = model.process_transactions(&day3).expect("Processing failed");
+        assert_eq!(report.duplicates_identical, 0);
+        assert_eq!(report.batches["b1"].rejected.get("duplicate_transaction"), Some(&2));
+        assert_eq!((model.available(1), model.client(2)), (Some(money("6.0")), None));
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let mut model = Model::with_capacity(1000);
+        let ClientTable::Hashed(clients) = &model.clients else {
+            panic!("Hashed client table expected");
+        };
+        assert!(clients.capacity() >= 1000);
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        check_accounts(&model, "05-accounts-chargeback");
+
+        // Hint below current size changes nothing
+        model.reserve_clients(1);
+        check_accounts(&model, "05-accounts-chargeback");
+    }
+
+    #[test]
+    fn test_snapshot_version_1() {
+        let snapshot = r#"{"version":1,"clients":[{"client":1,"available":"1.5000","held":"2.0000","total":"3.5000","locked":false}],
+            "transactions":[{"type":"deposit","client":1,"tx":4294967295,"amount":"2.0","timestamp":null},
+            {"type":"deposit","client":1,"tx":7,"amount":"1.5","timestamp":null}],
+            "disputed":[4294967295],"charged_back":[],"rolled_back":[],"counters":[],"max_tx":4294967295}"#;
+        let mut model = Model::from_snapshot(ModelConfig::default(), snapshot.as_bytes()).expect("Error reading snapshot");
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 4294967295,\n");
+        assert_eq!(model.available(1), Some(money("3.5")));
+        assert!(Model::from_snapshot(ModelConfig::default(), snapshot.replace("\"version\":1", "\"version\":99").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_repair_snapshot() {
+        // Total of client 1 misses the held amount
+        let snapshot = r#"{"version":3,"clients":[{"client":1,"available":"1.5000","held":"2.0000","total":"1.5000","locked":false},
+            {"client":2,"available":"1.0000","held":"0.0000","total":"1.0000","locked":false}],
+            "transactions":[{"type":"deposit","client":1,"tx":1,"amount":"2.0","timestamp":null},
+            {"type":"deposit","client":1,"tx":2,"amount":"1.5","timestamp":null},
+            {"type":"deposit","client":2,"tx":3,"amount":"1.0","timestamp":null}],
+            "disputed":[1],"charged_back":[],"rolled_back":[],"counters":[],"max_tx":3}"#;
+        assert!(Model::from_snapshot(ModelConfig::default(), snapshot.as_bytes()).is_err());
+
+        let config = ModelConfig { repair_snapshot: true, ..ModelConfig::default() };
+        let mut model = Model::from_snapshot(config, snapshot.as_bytes()).expect("Error reading snapshot");
+        assert_eq!(model.snapshot_repairs(), [TotalRepair { client: 1, total: money("1.5"), repaired: money("3.5") }]);
+        assert_eq!(model.snapshot_repairs()[0].to_string(), "client 1: total 1.5000 recomputed as 3.5000");
+        assert_eq!((model.total(1), model.total(2)), (Some(money("3.5")), Some(money("1.0"))));
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 1,\n");
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+    }
+
+    #[test]
+    fn test_resume_from_snapshot() {
+        let csv_text = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input");
+        let mut expected = Model::default();
+        process_text(&mut expected, &csv_text);
+
+        let mut expected_out = Vec::new();
+        expected.write_accounts(&mut expected_out, &OutputOptions::default()).expect("Error writing accounts");
+
+        // Crashed runs, which got through tx 2 only, and through tx 4 with dispute and chargeback of tx 1 still to come
+        for (records, max_tx) in [(2, 2), (4, 4)] {
+            let mut crashed = Model::default();
+            let prefix: Vec<&str> = csv_text.lines().take(records + 1).collect();
+            process_text(&mut crashed, &prefix.join("\n"));
+            assert_eq!(crashed.max_tx(), Some(max_tx));
+            let mut snapshot = Vec::new();
+            crashed.write_snapshot(&mut snapshot).expect("Error writing snapshot");
+
+            let config = ModelConfig { resume_from: Some(max_tx - 1), ..Default::default() };
+            let err = Model::from_snapshot(config, snapshot.as_slice()).err().expect("Wrong checkpoint accepted");
+            assert_eq!(err.to_string(), format!("Resume from tx {} doesn't match snapshot, whose highest tx is {}", max_tx - 1, max_tx));
+
+            let config = ModelConfig { resume_from: Some(max_tx), ..Default::default() };
+            let mut resumed = Model::from_snapshot(config, snapshot.as_slice()).expect("Error reading snapshot");
+            process_text(&mut resumed, &csv_text);
+
+            let mut resumed_out = Vec::new();
+            resumed.write_accounts(&mut resumed_out, &OutputOptions::default()).expect("Error writing accounts");
+            assert_eq!(String::from_utf8(resumed_out), String::from_utf8(expected_out.clone()));
+            assert_eq!(resumed.max_tx(), expected.max_tx());
+        }
+    }
+
+    #[test]
+    fn test_top_clients() {
+        // Pseudo-random distribution with ties
+        let mut csv_text = String::from("type, client, tx, amount\n");
+        let mut tx = 0;
+        for client in 1..=100u32 {
+            let deposits = client * 7 % 5 + 1;
+            for _ in 0..deposits {
+                tx += 1;
+                csv_text.push_str(&format!("deposit, {}, {}, {}\n", client, tx, client * 37 % 11));
+            }
+            if client % 3 == 0 {
+                csv_text.push_str(&format!("dispute, {}, {},\n", client, tx));
+            }
+        }
+        let mut model = Model::default();
+        process_text(&mut model, &csv_text);
+
+        let expected = |key: &dyn Fn(&Client) -> Money| {
+            let mut clients: Vec<(ClientID, Money)> = model.clients.values().map(|c| (c.client, key(c))).collect();
+            clients.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            clients.truncate(5);
+            clients
+        };
+        let top = model.top_clients(5);
+        assert_eq!(top.by_total, expected(&|c| c.total.clone()));
+        assert_eq!(top.by_held, expected(&|c| c.held.clone()));
+        let mut by_transactions: Vec<(ClientID, u32)> = model.counters.iter().map(|(&id, c)| (id, c.transactions())).collect();
+        by_transactions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        by_transactions.truncate(5);
+        assert_eq!(top.by_transactions, by_transactions);
+
+        assert_eq!(model.top_clients(0), TopClients::default());
+    }
+
+    #[test]
+    fn test_validate_release() {
+        assert_eq!(validate_release(&money("2.0"), &money("2.0")), Ok(()));
+        assert_eq!(validate_release(&money("2.0"), &money("1.5")), Ok(()));
+        assert_eq!(validate_release(&money("2.0"), &money("2.5")), Err(RejectReason::OverRelease { held: money("2.0"), requested: money("2.5") }));
+    }
+
+    #[test]
+    fn test_batch_report() {
+        let buffer = SharedBuffer::default();
+        let mut model = Model::default();
+        model.set_events_writer(Box::new(buffer.clone()));
+        let report = model.process_transactions("cases/12-transactions-batches.csv").expect("Processing failed");
+        check_accounts(&model, "12-accounts-batches");
+
+        let counts = |rows, applied, rejected: &[(&'static str, u64)]| BatchCounts { rows, applied, rejected: rejected.iter().copied().collect() };
+        assert_eq!(report.batches, BTreeMap::from([
+            ("A".to_string(), counts(3, 2, &[("insufficient_funds", 1)])),
+            ("B".to_string(), counts(4, 3, &[("unknown_transaction", 1)])),
+        ]));
+
+        let mut csv_out = Vec::new();
+        report.write_batch_report(&mut csv_out).expect("Error writing batch report");
+        let csv_out = String::from_utf8(csv_out).expect("Invalid UTF-8");
+        let lines: Vec<&str> = csv_out.lines().collect();
+        assert!(lines[0].starts_with("batch,rows,applied,rejected,no_amount,over_max_balance,insufficient_funds,unknown_transaction,"));
+        assert!(lines[1].starts_with("A,3,2,1,0,0,1,0,"));
+        assert!(lines[2].starts_with("B,4,3,1,0,0,0,1,"));
+        assert_eq!(lines.len(), 3);
+
+        // Audit log carries batch of each applied row
+        let text = String::from_utf8(buffer.0.borrow().clone()).expect("Invalid UTF-8");
+        let batches: Vec<Option<String>> = text.lines()
+            .map(|line| serde_json::from_str::<Event>(line).expect("Invalid event").batch)
+            .collect();
+        assert_eq!(batches, ["A", "A", "B", "B", "B"].map(|b| Some(b.to_string())));
+
+        // Files without the column are not reported per batch
+        let report = Model::default().process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        assert!(report.batches.is_empty());
+    }
+
+    #[test]
+    fn test_chargeback_on_locked_account() {
+        let mut model = Model::default();
+        let state = |model: &Model| model.client(1).map(|c| (c.available.clone(), c.held.clone(), c.total.clone(), c.locked));
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\ndeposit, 1, 3, 4.0\n");
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 1,\nchargeback, 1, 1,\n");
+        assert_eq!(state(&model), Some((money("6.0"), money("0.0"), money("6.0"), true)));
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 2,\n");
+        assert_eq!(state(&model), Some((money("4.0"), money("2.0"), money("6.0"), true)));
+        process_text(&mut model, "type, client, tx, amount\nchargeback, 1, 2,\n");
+        assert_eq!(state(&model), Some((money("4.0"), money("0.0"), money("4.0"), true)));
+        assert_eq!(model.counters(1).map(|c| c.chargebacks), Some(2));
+        // Resolve releases held funds of locked account too, which stays locked
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 3,\n");
+        assert_eq!(state(&model), Some((money("0.0"), money("4.0"), money("4.0"), true)));
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 3,\n");
+        assert_eq!(state(&model), Some((money("4.0"), money("0.0"), money("4.0"), true)));
+        assert_eq!(model.counters(1).map(|c| (c.disputes, c.resolves)), Some((3, 1)));
+        assert_eq!(model.audit().expect("Audit failed"), vec![]);
+    }
+
+    #[test]
+    fn test_table_output() {
+        let mut model = Model::default();
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+        let render = |options: OutputOptions| {
+            let mut out = Vec::new();
+            model.write_accounts(&mut out, &OutputOptions { format: OutputFormat::Table, ..options }).expect("Error writing table");
+            String::from_utf8(out).expect("Invalid UTF-8")
+        };
+        let expected = std::fs::read_to_string("cases/05-accounts-chargeback.txt").expect("Error reading expected");
+        assert_eq!(render(OutputOptions::default()), expected);
+
+        // Narrow terminal loses held, then available
+        let narrow = render(OutputOptions { width: Some(40), ..OutputOptions::default() });
+        assert_eq!(narrow.lines().next(), Some("client  available   total  locked"));
+        let narrow = render(OutputOptions { width: Some(20), ..OutputOptions::default() });
+        assert_eq!(narrow.lines().next(), Some("client   total  locked"));
+    }
+
+    #[test]
+    fn test_accounts_checksum() {
+        let checksum = |input: &str| process_checksum(input.as_bytes()).1;
+        let input = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input");
+        let first = checksum(&input);
+        assert_eq!(first.len(), 64);
+        assert_eq!(first, checksum(&input));
+        assert_ne!(first, checksum(&input.replace("deposit, 2, 2, 2.0", "deposit, 2, 2, 2.5")));
+    }
+
+    #[test]
+    fn test_balance_accessors() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.5\ndeposit, 1, 2, 2.0\ndispute, 1, 1,\n");
+        assert_eq!(model.available(1), Some(money("2.0")));
+        assert_eq!(model.held(1), Some(money("1.5")));
+        assert_eq!(model.total(1), Some(money("3.5")));
+        assert_eq!(model.is_locked(1), Some(false));
+        assert_eq!(model.total(2), None);
+    }
+
+    #[test]
+    fn test_skip_limit() {
+        let csv_text = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input");
+        let lines: Vec<&str> = csv_text.lines().collect();
+        let mut expected = Model::default();
+        process_text(&mut expected, &[&[lines[0]], &lines[3..6]].concat().join("\n"));
+
+        let mut model = Model::with_config(ModelConfig { skip_rows: 2, limit_rows: Some(3), ..ModelConfig::default() });
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!((report.records, report.truncated), (3, true));
+        let (mut expected_out, mut out) = (Vec::new(), Vec::new());
+        expected.write_accounts(&mut expected_out, &OutputOptions::default()).expect("Error writing accounts");
+        model.write_accounts(&mut out, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(out), String::from_utf8(expected_out));
+
+        // Limit reaching exactly the end of input is not truncation
+        let mut model = Model::with_config(ModelConfig { skip_rows: 2, limit_rows: Some(5), ..ModelConfig::default() });
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!((report.records, report.truncated), (5, false));
+    }
+
+    #[test]
+    fn test_empty_amount() {
+        let mut model = Model::default();
+        let first_row = |csv_text: &str| {
+            let mut source = model.csv_source(csv_text.as_bytes());
+            let (tr, _) = source.next_transaction().expect("Row missing").expect("Invalid row");
+            tr
+        };
+        let empty = first_row("type, client, tx, amount\ndeposit, 1, 1,\n");
+        let absent = first_row("type, client, tx\nwithdrawal, 1, 2\n");
+        assert_eq!((empty.amount.is_none(), empty.empty_amount), (true, true));
+        assert_eq!((absent.amount.is_none(), absent.empty_amount), (true, false));
+        assert_eq!(model.process_transaction(empty), Err(RejectReason::EmptyAmount));
+        assert_eq!(model.process_transaction(absent), Err(RejectReason::NoAmount));
+    }
+
+    #[test]
+    fn test_dispute_missing_amount() {
+        // Such state can't be reached through processing, amount-less deposits are not stored
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2,\n");
+        assert!(!model.revertable_transactions.contains(2));
+        let stored = transaction(TransactionType::Deposit, 1, 2, None);
+        assert_eq!(stored_amount(&stored), Err(RejectReason::MissingAmount { tx: 2 }));
+
+        model.revertable_transactions.insert(stored);
+        process_text(&mut model, "type, client, tx, amount\ndispute, 1, 2,\n");
+        assert!(model.disputed().next().is_none());
+        assert_eq!(model.client(1).map(|client| (client.available.clone(), client.held.clone())), Some((money("1.0"), money("0.0"))));
+    }
+
+    #[test]
+    fn test_input_offsets_restart() {
+        let csv_text = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input");
+        let mut expected = Model::default();
+        process_text(&mut expected, &csv_text);
+
+        // First run sees only part of the file, as it is still being written
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().join("input.csv");
+        let path = path.to_str().unwrap();
+        let split = csv_text.find("dispute").unwrap();
+        std::fs::write(path, &csv_text[..split]).expect("Error writing input");
+        let mut first = Model::default();
+        first.process_file_resumable(path).expect("Processing failed");
+        assert_eq!(first.input_offset(path).map(|o| (o.records, o.bytes)), Some((4, split as u64)));
+        let mut snapshot = Vec::new();
+        first.write_snapshot(&mut snapshot).expect("Error writing snapshot");
+
+        // Restart after the file is complete, consumed prefix is skipped
+        std::fs::write(path, &csv_text).expect("Error writing input");
+        let mut restarted = Model::from_snapshot(ModelConfig::default(), snapshot.as_slice()).expect("Error reading snapshot");
+        let report = restarted.process_file_resumable(path).expect("Processing failed");
+        assert_eq!(report.records, 3);
+        assert_eq!(restarted.input_offset(path).map(|o| o.records), Some(7));
+        for id in [1, 2] {
+            assert_eq!(expected.client(id), restarted.client(id), "Client data mismatch for client {}", id);
+        }
+
+        // Prefix changed underneath, offset can't be reused
+        std::fs::write(path, csv_text.replace("deposit, 2, 2, 2.0", "deposit, 2, 2, 9.0")).expect("Error writing input");
+        let mut changed = Model::from_snapshot(ModelConfig::default(), snapshot.as_slice()).expect("Error reading snapshot");
+        assert!(changed.process_file_resumable(path).is_err());
+    }
+
+    #[test]
+    fn test_defer_disputes() {
+        let csv_text = "type, client, tx, amount
+dispute, 1, 2,
+deposit, 1, 1, 1.0
+chargeback, 1, 3,
+deposit, 1, 2, 2.0
+";
+        let mut model = Model::with_config(ModelConfig { defer_disputes: true, ..Default::default() });
+        process_text(&mut model, csv_text);
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone(), client.locked), (money("1.0"), money("2.0"), money("3.0"), false));
+
+        let mut model = Model::default();
+        process_text(&mut model, csv_text);
+        let client = model.client(1).expect("Client missing");
+        assert_eq!((client.available.clone(), client.held.clone(), client.total.clone()), (money("3.0"), money("0.0"), money("3.0")));
+    }
+
+    // Fails after given number of bytes
+    struct FailingWriter<W: Write> {
+        inner: W,
+        remaining: usize,
+    }
+
+    impl<W: Write> Write for FailingWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::other("injected failure"));
+            }
+            let len = buf.len().min(self.remaining);
+            self.remaining -= len;
+            self.inner.write(&buf[..len])
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let mut model = Model::default();
+        model.process_transactions("cases/01-transactions-base.csv").expect("Processing failed");
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().join("accounts.csv");
+
+        let write_failing = |writer: &mut dyn Write| {
+            model.write_accounts(FailingWriter { inner: writer, remaining: 20 }, &OutputOptions::default())
+        };
+        assert!(write_atomic(&path, write_failing).is_err());
+        assert!(!path.exists());
+
+        write_atomic(&path, |writer| model.write_accounts(writer, &OutputOptions::default())).expect("Error writing");
+        let previous = std::fs::read_to_string(&path).expect("Error reading output");
+        assert!(write_atomic(&path, write_failing).is_err());
+        assert_eq!(std::fs::read_to_string(&path).expect("Error reading output"), previous);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1, "Temporary file left behind");
+    }
+
+    #[test]
+    fn test_disputed() {
+        let mut model = Model::default();
+        process_text(&mut model, "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\ndispute, 1, 1,\ndispute, 2, 2,\n");
+        let mut disputed: Vec<TransactionID> = model.disputed().collect();
+        disputed.sort();
+        assert_eq!(disputed, vec![1, 2]);
+
+        process_text(&mut model, "type, client, tx, amount\nresolve, 1, 1,\n");
+        assert_eq!(model.disputed().collect::<Vec<_>>(), vec![2]);
+    }
+
+    #[test]
+    fn test_type_aliases() {
+        let mut config = ModelConfig::default();
+        Policy::from_file("cases/11-policy-aliases.toml").expect("Error reading policy").apply(&mut config);
+        let mut model = Model::with_config(config);
+        let report = model.process_transactions("cases/11-transactions-aliases.csv").expect("Processing failed");
+        assert_eq!(report.parse_errors, 0);
+        check_accounts(&model, "05-accounts-chargeback");
+
+        let mut model = Model::with_config(ModelConfig { type_aliases: model.config.type_aliases.clone(), ..ModelConfig::default() });
+        let ndjson = "{\"type\":\"credit\",\"client\":1,\"tx\":1,\"amount\":1.0}\n{\"type\":\"refund\",\"client\":1,\"tx\":2,\"amount\":1.0}\n";
+        let report = model.process_source(model.ndjson_source(ndjson.as_bytes())).expect("Processing failed");
+        // Unknown values are still rejected
+        assert_eq!((report.parse_errors, report.unknown_types), (0, 1));
+        assert_eq!(model.client(1).map(|client| client.total.clone()), Some(money("1.0")));
+
+        let toml = model.config.to_toml().expect("Error writing config");
+        assert!(toml.contains("[type_aliases]\ncharge_back = \"chargeback\"\ncredit = \"deposit\"\ndebit = \"withdrawal\"\n"), "{}", toml);
+    }
+
+    #[test]
+    fn test_normalize_types() {
+        // Same rows as cases/05 with feed spellings of types
+        let csv_text = "type, client, tx, amount\nDeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\nDEPOSIT, 1, 3, 2.0\n\
+            withdraw, 1, 4, 1.5\ndispute, 1, 1,\ncharge_back, 1, 1,\nWithdrawal, 2, 5, 3.0\n";
+        let mut model = Model::default();
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!((report.parse_errors, report.unknown_types), (0, 5));
+
+        let mut model = Model::with_config(ModelConfig { normalize_types: true, ..ModelConfig::default() });
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!(report.parse_errors, 0);
+        check_accounts(&model, "05-accounts-chargeback");
+
+        // Configured aliases apply case-insensitively too, unknown values are still rejected
+        let mut type_aliases = TypeAliases::new();
+        type_aliases.insert("Credit".to_string(), TransactionType::Deposit);
+        let mut model = Model::with_config(ModelConfig { normalize_types: true, type_aliases, ..ModelConfig::default() });
+        let ndjson = "{\"type\":\"CREDIT\",\"client\":1,\"tx\":1,\"amount\":3.0}\n{\"type\":\" Withdraw\",\"client\":1,\"tx\":2,\"amount\":1.0}\n\
+            {\"type\":\"refund\",\"client\":1,\"tx\":3,\"amount\":1.0}\n";
+        let report = model.process_source(model.ndjson_source(ndjson.as_bytes())).expect("Processing failed");
+        assert_eq!((report.parse_errors, report.unknown_types), (0, 1));
+        assert_eq!(model.client(1).map(|client| client.total.clone()), Some(money("2.0")));
+    }
+
+    #[test]
+    fn test_unknown_types_policy() {
+        // cases/06 has an "unexpected" row between valid ones
+        let run = |unknown_types: &str| {
+            let policy: Policy = toml::from_str(&format!("unknown_types = \"{}\"\n", unknown_types)).expect("Invalid policy");
+            let mut config = ModelConfig::default();
+            policy.apply(&mut config);
+            let mut model = Model::with_config(config);
+            let result = model.process_transactions("cases/06-transactions-unexpected.csv");
+            (result, model)
+        };
+
+        for policy in ["ignore", "warn"] {
+            let (result, model) = run(policy);
+            let report = result.expect("Processing failed");
+            assert_eq!((report.records, report.parse_errors, report.unknown_types), (6, 0, 1), "{}", policy);
+            check_accounts(&model, "06-accounts-unexpected");
+        }
+
+        let (result, _) = run("error");
+        let err = result.expect_err("Unknown type accepted").to_string();
+        assert_eq!(err, "record 4 (line 5): unknown transaction type unexpected");
+
+        assert!(toml::from_str::<Policy>("unknown_types = \"drop\"\n").is_err());
+        assert_eq!("error".parse::<UnknownTypes>(), Ok(UnknownTypes::Error));
+    }
+
+    #[test]
+    fn test_collect_errors() {
+        let input = std::fs::read("cases/18-transactions-dirty.csv").expect("Error reading input");
+        let mut model = Model::default();
+        let (report, errors) = model.process_reader_collect_errors(&input[..], 10).expect("Processing failed");
+        assert_eq!((report.records, report.parse_errors, report.unknown_types), (7, 3, 1));
+        let errors: Vec<_> = errors.iter().map(|err| (err.location.record, err.location.line, err.record.as_str(), &err.kind)).collect();
+        let malformed = |message: &str| RowErrorKind::Malformed(message.to_string());
+        assert_eq!(errors, vec![
+            (2, 3, "deposit,x,2,2.0", &malformed("CSV deserialize error: record 2 (line: 3, byte: 44): field 1: invalid digit found in string")),
+            (3, 4, "refund,1,3,1.0", &RowErrorKind::UnknownType("refund".to_string())),
+            // Amount parser's message depends on money backend
+            (4, 5, "deposit,2,4,abc", &malformed(&format!("CSV deserialize error: record 4 (line: 5, byte: 81): {}", "abc".parse::<Money>().unwrap_err()))),
+            (6, 7, "deposit, 2, 6, 1.0, extra", &malformed("CSV error: record 6 (line: 7, byte: 122): found record with 5 fields, but the previous record has 4 fields")),
+        ]);
+        assert_eq!(model.client(1).map(|client| client.total.clone()), Some(money("0.5")));
+        assert_eq!(model.client(3).map(|client| client.total.clone()), Some(money("1.0")));
+
+        // Cap bounds the list, not the counts
+        let mut model = Model::default();
+        let (report, errors) = model.process_reader_collect_errors(&input[..], 1).expect("Processing failed");
+        assert_eq!((report.parse_errors, report.unknown_types, errors.len()), (3, 1, 1));
+        assert_eq!(errors[0].to_string(), "record 2 (line 3): CSV deserialize error: record 2 (line: 3, byte: 44): field 1: invalid digit found in string [deposit,x,2,2.0]");
+
+        let long = format!("type, client, tx, amount\ndeposit, {}, 1, 1.0\n", "9".repeat(1000));
+        let (_, errors) = Model::default().process_reader_collect_errors(long.as_bytes(), 1).expect("Processing failed");
+        assert_eq!(errors[0].record.len(), ROW_ERROR_SNIPPET_BYTES);
+    }
+
+    #[test]
+    fn test_rounding_policy() {
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 2.345\ndeposit, 2, 2, 2.355\ndeposit, 3, 3, 0.125\ndispute, 3, 3,\n";
+        let run = |rounding: &str| {
+            let policy: Policy = toml::from_str(&format!("rounding = \"{}\"\namount_places = 2\n", rounding)).expect("Invalid policy");
+            let mut config = ModelConfig::default();
+            policy.apply(&mut config);
+            let mut model = Model::with_config(config);
+            let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+            let totals: Vec<Money> = (1..=3).map(|client| model.total(client).expect("Client missing")).collect();
+            (report.rounding, totals, model.held(3))
+        };
+        assert_eq!(run("half-up"), (Rounding::HalfUp, vec![money("2.35"), money("2.36"), money("0.13")], Some(money("0.13"))));
+        assert_eq!(run("half-even"), (Rounding::HalfEven, vec![money("2.34"), money("2.36"), money("0.12")], Some(money("0.12"))));
+        assert_eq!(run("truncate"), (Rounding::Truncate, vec![money("2.34"), money("2.35"), money("0.12")], Some(money("0.12"))));
+        assert!(toml::from_str::<Policy>("rounding = \"half-down\"\n").is_err());
+
+        // Without amount_places input is kept exact and rounding applies to output only
+        let mut model = Model::with_config(ModelConfig { rounding: Rounding::HalfEven, ..ModelConfig::default() });
+        model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+        assert_eq!(model.total(1), Some(money("2.345")));
+        let options = OutputOptions { precision: Some(2), rounding: Rounding::HalfEven, ..OutputOptions::default() };
+        assert_eq!(options.format_amount(&money("2.345")), "2.34");
+        assert!(model.config.to_toml().expect("Error writing config").contains("rounding = \"half-even\"\n"));
+    }
+
+    // Against both client tables, which must write the same output
+    fn run_case(input_name: &str, output_name: &str) {
+        let input = format!("cases/{}.csv", input_name);
+        let outputs: Vec<Vec<u8>> = [false, true].into_iter().map(|ordered_clients| {
+            let mut model = Model::with_config(ModelConfig { ordered_clients, ..ModelConfig::default() });
+            model.process_transactions(&input).expect("Processing failed");
+            check_accounts(&model, output_name);
+            let mut output = Vec::new();
+            model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+            output
+        }).collect();
+        assert_eq!(outputs[0], outputs[1], "{}", input_name);
+    }
+
+    fn check_accounts(model: &Model, output_name: &str) {
+        let output = format!("cases/{}.csv", output_name);
+        let expected_csv = std::fs::read_to_string(output).expect("Error reading expected");
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .trim(csv::Trim::All)
+            .from_reader(expected_csv.as_bytes());
+
+        let mut record_count = 0;
+        for result in rdr.deserialize::<Client>() {
+            let expected_client: Client = result.expect("Error deserializing client");
+            let actual_client = model.clients.get(&expected_client.client).expect("Client missing");
+            assert_client_eq(&expected_client, actual_client, &case_epsilon());
+            record_count += 1;
+        }
+        assert_eq!(model.clients.len(), record_count, "Number of clients mismatch");
+    }
+}
\ No newline at end of file
@@ -0,0 +1,97 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Log file rotated by size: "<path>" is current, "<path>.1" the previous one and so on up to max_files
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path: path.to_path_buf(), max_size, max_files, file, size })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        *self = RotatingFile::open(&self.path, self.max_size, self.max_files)?;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let shifts = (1..=self.max_files).rev().map(|index| {
+            let from = if index == 1 { self.path.clone() } else { self.rotated_path(index - 1) };
+            (from, self.rotated_path(index))
+        });
+        for (from, to) in shifts.collect::<Vec<_>>() {
+            ignore_missing(std::fs::rename(from, to))?;
+        }
+        if self.max_files == 0 {
+            ignore_missing(std::fs::remove_file(&self.path))?;
+        }
+        self.reopen()
+    }
+}
+
+// Files may be removed underneath us, e.g. by an operator cleaning up
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl Write for RotatingFile {
+    // Each log record comes as one write, so records are not split between files
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.path.exists() {
+            self.reopen()?;
+        }
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation_survives_deleted_file() {
+        let dir = tempfile::tempdir().expect("Error creating temp dir");
+        let path = dir.path().join("run.log");
+        let mut log = RotatingFile::open(&path, 100, 2).expect("Error opening log");
+        for i in 0..24 {
+            log.write_all(format!("record {:04}\n", i).as_bytes()).expect("Error writing log");
+            if i == 3 {
+                std::fs::remove_file(&path).expect("Error removing log");
+            }
+        }
+
+        // 12 bytes per record, 8 fit in a file, records before the removal are lost
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).expect("Error reading log");
+        assert_eq!(read("run.log").lines().count(), 4);
+        assert!(read("run.log.1").starts_with("record 0012\n"));
+        assert!(read("run.log.2").starts_with("record 0004\n"));
+        assert!(!dir.path().join("run.log.3").exists());
+    }
+}
@@ -1,254 +1,1196 @@
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::BTreeSet;
 use std::env;
-use serde::Deserialize;
+use std::str::FromStr;
+use std::io::{IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use log::{error, info, warn};
 use serde::Serialize;
-use log::{info, warn};
-use env_logger;
-
-type ClientID = u16;
-type TransactionID = u32;
-
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
-
-#[derive(Debug, Deserialize)]
-struct Transaction {
-    #[serde(rename = "type")]
-    tr_type: TransactionType,
-    client: ClientID, 
-    tx: TransactionID, 
-    amount: Option<f64>
-}
-
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-struct Client {
-    client: ClientID,
-    available: f64,
-    held: f64,
-    total: f64,
-    locked: bool,
-}
-
-struct Model {
-    clients: HashMap<ClientID, Client>,
-    revertable_transactions: HashMap<TransactionID, Transaction>,
-    disputed_transactions: HashSet<TransactionID>
-}
-
-impl Model {
-    fn new() -> Self {
-        Model {
-            clients: HashMap::new(),
-            revertable_transactions: HashMap::new(),
-            disputed_transactions: HashSet::new(),
-        }
-    }
-
-    fn process_revertable_transaction(&mut self, tr: Transaction) {
-        let client = self.clients.entry(tr.client).or_insert(Client {
-            client: tr.client,
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
-            locked: false,
-        });
+use sha2::{Digest, Sha256};
+use process_transactions::anonymize::{write_anonymized, AnonymizeSpec};
+use process_transactions::logfile::RotatingFile;
+use process_transactions::money::Money;
+use process_transactions::sample::{write_sample, SampleSpec};
+use process_transactions::source::SourceError;
+use process_transactions::{decode_reader, diff_accounts, hex, ClientID, InputEncoding, open_file_reader, open_input, open_mmap, open_reader, read_accounts, write_atomic, Model, ModelConfig, OutputFormat, OutputOptions, Policy, ProcessingReport, SarThresholds, UnknownTypes, VelocityAction};
 
-        let sign = if tr.tr_type == TransactionType::Deposit { 1.0 } else { -1.0 };
+#[derive(Debug, Default, PartialEq)]
+enum Command {
+    #[default]
+    Process,
+    // Rebuilds accounts from events file given as input
+    Replay,
+    // Prints per-client changes between two accounts files
+    Diff,
+    // Writes synthetic transactions file
+    Gen,
+    // Copies input with client and tx IDs remapped, for sharing repro cases
+    Anonymize,
+    // Rewrites input as normalized CSV without computing balances
+    Canonicalize,
+}
 
-        let Some(amount) = tr.amount else {
-            warn!("Transaction missing amount: {:?}", tr);
-            return;
-        };
+#[derive(Debug, Default, PartialEq)]
+enum InputFormat {
+    #[default]
+    Csv,
+    Ndjson,
+    Xlsx,
+}
 
-        // TBD: likely should check for locked account here, especially for withdrawal (no requirement in spec)
-        if client.available + sign*amount > 0.0 {
-            client.available += sign*amount;
-            client.total += sign*amount;
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            "xlsx" => Ok(InputFormat::Xlsx),
+            _ => Err(format!("Unknown input format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    command: Command,
+    // Input file, stdin if missing or "-", or pattern like "incoming/2024-*.csv" for all matching files
+    input: Option<String>,
+    // Pattern matching no files processes nothing instead of failing
+    allow_empty_glob: bool,
+    // New accounts file compared with input (the old one) by diff
+    diff_with: Option<String>,
+    input_format: InputFormat,
+    // Text input is transcoded to UTF-8 from it before parsing
+    encoding: InputEncoding,
+    // Worksheet of xlsx input, first one if missing
+    sheet: Option<String>,
+    // Memory-maps input file instead of buffered reading
+    mmap: bool,
+    // Accounts file, stdout if missing
+    out: Option<String>,
+    // Processes each *.csv of batch_dir separately, writing <name>.accounts.csv to out_dir,
+    // with up to jobs files at a time
+    batch_dir: Option<String>,
+    out_dir: Option<String>,
+    jobs: Option<usize>,
+    // Accounts split into out_dir files of at most this many rows instead of out
+    shard_output: Option<usize>,
+    // NDJSON log of applied transaction effects, appended to
+    events_out: Option<String>,
+    // Events written between flushes of events file, each line is flushed when not set
+    flush_interval: Option<u64>,
+    // Directory to spill revertable transactions to, with max number kept in memory
+    spill_dir: Option<String>,
+    spill_memory_cap: Option<usize>,
+    // Number of clients input is expected to have, pre-sizes client maps
+    expected_clients: Option<usize>,
+    // Model state loaded before and saved after processing
+    snapshot_in: Option<String>,
+    snapshot_out: Option<String>,
+    // Saves consumed prefix of input file in snapshot and skips it on restart
+    track_offsets: bool,
+    // Trial balance file, JSON if path ends with ".json", CSV otherwise
+    trial_balance: Option<String>,
+    // Suspicious-activity report of clients exceeding the thresholds
+    sar_out: Option<String>,
+    // Per-batch row, applied and rejected counts for inputs with batch column
+    batch_report: Option<String>,
+    sar: SarThresholds,
+    // Prints top N clients by total, held and transaction count to stderr, 0 disables
+    top: usize,
+    // Waits for lock held by another instance instead of failing
+    wait_for_lock: Option<u64>,
+    // Limits logging to warnings and errors regardless of RUST_LOG
+    quiet: bool,
+    // Prints run summary to stderr
+    summary: bool,
+    // Rejections are tallied by reason at the end instead of logged one by one
+    warn_summary: bool,
+    // Prints one line JSON object with counts and exit code to stderr last
+    exit_summary: bool,
+    // Exits with EXIT_REJECTS when any row was rejected, outputs are written anyway
+    fail_on_reject: bool,
+    unknown_types: Option<UnknownTypes>,
+    overdraft_limit: Option<Money>,
+    velocity_action: Option<VelocityAction>,
+    // Processes input twice, plain and with selected performance options, and compares accounts
+    self_check: bool,
+    // Prints SHA-256 of accounts output to stderr
+    checksum: bool,
+    // Recomputes balances from stored transactions after processing, fails on mismatch
+    audit_pass: bool,
+    // Log file instead of stderr, rotated when exceeding max size, keeping max files old ones
+    log_file: Option<String>,
+    log_max_size: Option<u64>,
+    log_max_files: Option<usize>,
+    // TOML policy file with type aliases
+    policy: Option<String>,
+    // Prints effective configuration to stdout and exits
+    print_config: bool,
+    // Prints version with money backend and exits
+    version: bool,
+    // Accounts format, table when not given and stdout is a terminal
+    format: Option<OutputFormat>,
+    config: ModelConfig,
+    output: OutputOptions,
+    // File written by gen
+    sample: SampleSpec,
+    anonymize: AnonymizeSpec,
+    // JSON record of inputs, config and outputs with their hashes, written after outputs
+    manifest: Option<String>,
+    // Directory of per-client statement files, with clients limited to the listed ones
+    per_client_dir: Option<String>,
+    statement_clients: Option<BTreeSet<ClientID>>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = Args::default();
+        let mut iter = args.iter().peekable();
+        match iter.peek().map(|arg| arg.as_str()) {
+            Some("gen") => {
+                parsed.command = Command::Gen;
+                iter.next();
+            }
+            Some("anonymize") => {
+                parsed.command = Command::Anonymize;
+                iter.next();
+            }
+            Some("replay") => {
+                parsed.command = Command::Replay;
+                iter.next();
+            }
+            Some("diff") => {
+                parsed.command = Command::Diff;
+                let name = iter.next().unwrap();
+                parsed.input = Some(parse_value(name, iter.next())?);
+                parsed.diff_with = Some(parse_value(name, iter.next())?);
+            }
+            _ => {}
         }
-        else {
-            info!("Insufficient funds for withdrawal: {:?}", tr);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "--max-balance" => parsed.config.max_balance = Some(parse_value(arg, iter.next())?),
+                "--min-amount" => parsed.config.min_amount = Some(parse_value(arg, iter.next())?),
+                "--max-amount" => parsed.config.max_amount = Some(parse_value(arg, iter.next())?),
+                "--defer-disputes" => parsed.config.defer_disputes = true,
+                "--max-open-disputes-per-client" => parsed.config.max_open_disputes = Some(parse_value(arg, iter.next())?),
+                "--negative-available-policy" => parsed.config.negative_available = parse_value(arg, iter.next())?,
+                "--redispute" => parsed.config.redispute = parse_value(arg, iter.next())?,
+                "--disputable" => parsed.config.disputable = parse_value(arg, iter.next())?,
+                "--chargeback-semantics" => parsed.config.chargeback_semantics = parse_value(arg, iter.next())?,
+                "--lock-policy" => parsed.config.lock_policy = Some(parse_value(arg, iter.next())?),
+                "--lock-on-overflow" => parsed.config.lock_on_overflow = true,
+                "--max-transactions-per-client" => parsed.config.max_transactions_per_client = Some(parse_value(arg, iter.next())?),
+                "--velocity-window" => parsed.config.velocity_window_secs = Some(parse_value(arg, iter.next())?),
+                "--max-withdrawn-per-client" => parsed.config.max_withdrawn_per_client = Some(parse_value(arg, iter.next())?),
+                "--velocity-action" => parsed.velocity_action = Some(parse_value(arg, iter.next())?),
+                "--canonicalize" => parsed.command = Command::Canonicalize,
+                "--overdraft-limit" => parsed.overdraft_limit = Some(parse_value(arg, iter.next())?),
+                "--policy" => parsed.policy = Some(parse_value(arg, iter.next())?),
+                "--print-config" => parsed.print_config = true,
+                "--version" => parsed.version = true,
+                "--strip-symbols" => parsed.config.strip_symbols = true,
+                "--warn-integer-amounts" => parsed.config.warn_integer_amounts = true,
+                "--strict-schema" => parsed.config.strict_schema = true,
+                "--max-record-bytes" => parsed.config.max_record_bytes = Some(parse_value(arg, iter.next())?),
+                "--normalize-types" => parsed.config.normalize_types = true,
+                "--column-map" => {
+                    for pair in parse_list::<String>(arg, iter.next())? {
+                        let Some((column, name)) = pair.split_once('=') else {
+                            return Err(format!("Invalid value for {}: {}, column=name expected", arg, pair));
+                        };
+                        parsed.config.column_map.insert(column.trim().to_string(), name.trim().to_string());
+                    }
+                }
+                "--amount-locale" => parsed.config.amount_locale = Some(parse_value(arg, iter.next())?),
+                "--order-by-timestamp" => parsed.config.order_by_timestamp = true,
+                "--since" => parsed.config.since = Some(parse_value(arg, iter.next())?),
+                "--until" => parsed.config.until = Some(parse_value(arg, iter.next())?),
+                "--spill-dir" => parsed.spill_dir = Some(parse_value(arg, iter.next())?),
+                "--spill-memory-cap" => parsed.spill_memory_cap = Some(parse_value(arg, iter.next())?),
+                "--expected-clients" => parsed.expected_clients = Some(parse_value(arg, iter.next())?),
+                "--trial-balance" => parsed.trial_balance = Some(parse_value(arg, iter.next())?),
+                "--output-scale" => parsed.output.scale = Some(parse_value(arg, iter.next())?),
+                "--precision" => {
+                    let precision: u32 = parse_value(arg, iter.next())?;
+                    if precision > 6 {
+                        return Err(format!("Invalid value for {}: {}, 0 to 6 expected", arg, precision));
+                    }
+                    parsed.output.precision = Some(precision);
+                }
+                "--batch-report" => parsed.batch_report = Some(parse_value(arg, iter.next())?),
+                "--sar-out" => parsed.sar_out = Some(parse_value(arg, iter.next())?),
+                "--sar-max-chargebacks" => parsed.sar.max_chargebacks = Some(parse_value(arg, iter.next())?),
+                "--sar-max-dispute-rate" => parsed.sar.max_dispute_rate = Some(parse_value(arg, iter.next())?),
+                "--sar-max-insufficient-funds" => parsed.sar.max_insufficient_funds = Some(parse_value(arg, iter.next())?),
+                "--snapshot-in" => parsed.snapshot_in = Some(parse_value(arg, iter.next())?),
+                "--repair" => parsed.config.repair_snapshot = true,
+                "--snapshot-out" => parsed.snapshot_out = Some(parse_value(arg, iter.next())?),
+                "--track-offsets" => parsed.track_offsets = true,
+                "--skip" => parsed.config.skip_rows = parse_value(arg, iter.next())?,
+                "--limit" => parsed.config.limit_rows = Some(parse_value(arg, iter.next())?),
+                "--allow-types" => parsed.config.allowed_types = Some(parse_list(arg, iter.next())?),
+                "--deny-clients" => parsed.config.denied_clients = parse_list(arg, iter.next())?.into_iter().collect(),
+                "--resume-from" => parsed.config.resume_from = Some(parse_value(arg, iter.next())?),
+                "--top" => parsed.top = parse_value(arg, iter.next())?,
+                "--balances-only" => parsed.output.balances_only = true,
+                "--lock-details" => parsed.output.lock_details = true,
+                "--format" => parsed.format = Some(parse_value(arg, iter.next())?),
+                "--wait-for-lock" => parsed.wait_for_lock = Some(parse_value(arg, iter.next())?),
+                "--quiet" => parsed.quiet = true,
+                "--summary" => parsed.summary = true,
+                "--warn-summary" => parsed.warn_summary = true,
+                "--exit-summary" => parsed.exit_summary = true,
+                "--fail-on-reject" => parsed.fail_on_reject = true,
+                "--unknown-types" => parsed.unknown_types = Some(parse_value(arg, iter.next())?),
+                "--self-check" => parsed.self_check = true,
+                "--checksum" => parsed.checksum = true,
+                "--audit-pass" => parsed.audit_pass = true,
+                "--log-file" => parsed.log_file = Some(parse_value(arg, iter.next())?),
+                "--log-max-size" => parsed.log_max_size = Some(parse_value(arg, iter.next())?),
+                "--log-max-files" => parsed.log_max_files = Some(parse_value(arg, iter.next())?),
+                "--out" => parsed.out = Some(parse_value(arg, iter.next())?),
+                "--input-format" => parsed.input_format = parse_value(arg, iter.next())?,
+                "--encoding" => parsed.encoding = parse_value(arg, iter.next())?,
+                "--sheet" => parsed.sheet = Some(parse_value(arg, iter.next())?),
+                "--mmap" => parsed.mmap = true,
+                "--clients" if parsed.command == Command::Gen => parsed.sample.clients = parse_value(arg, iter.next())?,
+                "--clients" => parsed.statement_clients = Some(parse_list(arg, iter.next())?.into_iter().collect()),
+                "--manifest" => parsed.manifest = Some(parse_value(arg, iter.next())?),
+                "--per-client-dir" => parsed.per_client_dir = Some(parse_value(arg, iter.next())?),
+                "--txns" => parsed.sample.transactions = parse_value(arg, iter.next())?,
+                "--dispute-ratio" => parsed.sample.dispute_ratio = parse_value(arg, iter.next())?,
+                "--seed" => parsed.sample.seed = parse_value(arg, iter.next())?,
+                "--key" => parsed.anonymize.key = parse_value(arg, iter.next())?,
+                "--amounts" => parsed.anonymize.amounts = parse_value(arg, iter.next())?,
+                "--pending-delay" => parsed.config.pending_delay = Some(parse_value(arg, iter.next())?),
+                "--retention-secs" => parsed.config.retention_secs = Some(parse_value(arg, iter.next())?),
+                "--retention-transactions" => parsed.config.retention_transactions = Some(parse_value(arg, iter.next())?),
+                "--batch-dir" => parsed.batch_dir = Some(parse_value(arg, iter.next())?),
+                "--out-dir" => parsed.out_dir = Some(parse_value(arg, iter.next())?),
+                "--shard-output" => {
+                    let rows: usize = parse_value(arg, iter.next())?;
+                    if rows == 0 {
+                        return Err(format!("Invalid value for {}: {}, 1 or more expected", arg, rows));
+                    }
+                    parsed.shard_output = Some(rows);
+                }
+                "--jobs" => parsed.jobs = Some(parse_value(arg, iter.next())?),
+                "--events-out" => parsed.events_out = Some(parse_value(arg, iter.next())?),
+                "--flush-interval" => parsed.flush_interval = Some(parse_value(arg, iter.next())?),
+                "--input" => parsed.input = Some(parse_value(arg, iter.next())?),
+                "--allow-empty-glob" => parsed.allow_empty_glob = true,
+                "-" => parsed.input = None,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                _ => parsed.input = Some(arg.clone()),
+            }
         }
+        Ok(parsed)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", name))?;
+    value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value))
+}
+
+// Comma separated values, e.g. "deposit,withdrawal"
+fn parse_list<T: FromStr>(name: &str, value: Option<&String>) -> Result<Vec<T>, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", name))?;
+    value.split(',').map(|item| item.trim().parse().map_err(|_| format!("Invalid value for {}: {}", name, item))).collect()
+}
+
+const DEFAULT_SPILL_MEMORY_CAP: usize = 1_000_000;
+
+const DEFAULT_LOG_MAX_SIZE: u64 = 10_000_000;
+const DEFAULT_LOG_MAX_FILES: usize = 5;
+
+// Exit codes by failure class, for orchestration; see README
+const EXIT_IO: u8 = 1;
+const EXIT_PARSE: u8 = 2;
+const EXIT_REJECTS: u8 = 3;
+const EXIT_OTHER: u8 = 4;
+
+// Run completed with outputs written, but --fail-on-reject found rejected rows; logged by run
+#[derive(Debug)]
+struct RejectsPresent(u64);
 
-        self.revertable_transactions.insert(tr.tx, tr);
+impl std::fmt::Display for RejectsPresent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} rows rejected", self.0)
     }
+}
+
+impl std::error::Error for RejectsPresent {}
+
+fn exit_code(err: &(dyn std::error::Error + 'static)) -> u8 {
+    if err.is::<RejectsPresent>() {
+        EXIT_REJECTS
+    } else if err.is::<std::io::Error>() {
+        EXIT_IO
+    } else if err.is::<SourceError>() {
+        EXIT_PARSE
+    } else {
+        EXIT_OTHER
+    }
+}
+
+// Last line of stderr with --exit-summary, failed runs have just exit_code and error
+#[derive(Serialize)]
+struct ExitSummary<'a> {
+    exit_code: u8,
+    records: u64,
+    applied: u64,
+    malformed: u64,
+    unknown_types: u64,
+    rejected: &'a std::collections::BTreeMap<&'static str, u64>,
+    clients: usize,
+    locked: usize,
+}
 
-    fn process_dispute_resolve_chargeback(&mut self, tr: Transaction) {
-        let Some(original_tr) = self.revertable_transactions.get(&tr.tx) else {
-            warn!("Dispute/Resolve/Chargeback on unknown transaction: {:?}", tr);
-            return;
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args = match Args::parse(&args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return ExitCode::from(EXIT_OTHER);
+        }
+    };
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(path) = &args.log_file {
+        let max_size = args.log_max_size.unwrap_or(DEFAULT_LOG_MAX_SIZE);
+        let max_files = args.log_max_files.unwrap_or(DEFAULT_LOG_MAX_FILES);
+        match RotatingFile::open(Path::new(path), max_size, max_files) {
+            Ok(file) => logger.target(env_logger::Target::Pipe(Box::new(file))),
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+                return ExitCode::from(EXIT_IO);
+            }
         };
+    }
+    logger.init();
+
+    // Fatal errors go to stderr anyway, with --log-file they are logged too
+    let exit_summary = args.exit_summary;
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            let code = exit_code(err.as_ref());
+            // Run with rejects reported them already, before its exit summary
+            if code != EXIT_REJECTS {
+                error!("{}", err);
+                eprintln!("Error: {:?}", err);
+                if exit_summary {
+                    eprintln!("{}", serde_json::json!({ "exit_code": code, "error": err.to_string() }));
+                }
+            }
+            ExitCode::from(code)
+        }
+    }
+}
+
+fn run(mut args: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.version {
+        println!("{} {} (money: {})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), process_transactions::money::BACKEND);
+        return Ok(());
+    }
+    if args.quiet {
+        log::set_max_level(log::max_level().min(log::LevelFilter::Warn));
+    }
+    if args.warn_summary {
+        log::set_max_level(log::max_level().min(log::LevelFilter::Error));
+    }
+    if let Some(path) = &args.policy {
+        Policy::from_file(path)?.apply(&mut args.config);
+    }
+    // Command line wins over policy
+    if let Some(unknown_types) = args.unknown_types {
+        args.config.unknown_types = unknown_types;
+    }
+    if let Some(overdraft_limit) = args.overdraft_limit.clone() {
+        args.config.overdraft_limit = overdraft_limit;
+    }
+    if let Some(velocity_action) = args.velocity_action {
+        args.config.velocity_action = velocity_action;
+    }
+    args.output.rounding = args.config.rounding;
+    if args.print_config {
+        print!("{}", args.config.to_toml()?);
+        return Ok(());
+    }
+    if args.batch_dir.is_some() {
+        return batch(&args);
+    }
+    if args.shard_output.is_some() && (args.out_dir.is_none() || args.out.is_some()) {
+        return Err("--shard-output requires --out-dir and can't be used with --out".into());
+    }
+    let _locks = lock_state(&args)?;
 
-        if original_tr.client != tr.client {
-            warn!("Dispute/Resolve/Chargeback transaction client mismatch: {:?}, {:?}", tr, original_tr);
-            return;
+    args.output.format = match args.format {
+        Some(format) => format,
+        None if args.out.is_none() && args.shard_output.is_none() && std::io::stdout().is_terminal() => OutputFormat::Table,
+        None => OutputFormat::Csv,
+    };
+    if args.output.format == OutputFormat::Table {
+        args.output.width = env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok());
+    }
+
+    if args.self_check {
+        return self_check(&mut args);
+    }
+
+    let started = now();
+    let config_sha256 = hex(&Sha256::digest(args.config.to_toml()?));
+    let skip_rows = args.config.skip_rows;
+    // Event log for statements is removed however the run ends, after the model writing it is dropped
+    let _statement_events = args.per_client_dir.as_deref()
+        .filter(|_| args.command == Command::Process)
+        .map(|dir| RemoveOnDrop(statement_events(dir)));
+    let (mut model, report, inputs) = match args.command {
+        Command::Replay => (replay(&args)?, ProcessingReport { rounding: args.config.rounding, ..ProcessingReport::default() }, Vec::new()),
+        Command::Process => process(&mut args)?,
+        Command::Diff => return diff(&args),
+        Command::Gen => return generate(&args),
+        Command::Anonymize => return anonymize(&args),
+        Command::Canonicalize => return canonicalize(&args),
+    };
+    // Outputs are not written when balances can't be trusted
+    if args.audit_pass {
+        let mismatches = model.audit()?;
+        for mismatch in &mismatches {
+            error!("Audit mismatch, {}", mismatch);
         }
-        if original_tr.tr_type != TransactionType::Deposit {
-            warn!("Dispute/Resolve/Chargeback on non-deposit transaction: {:?}", tr);
-            return;
+        // Balances are intact, but the feed tried to overflow them
+        for (client, count) in model.overflow_clients() {
+            warn!("Audit, client {} had {} transactions rejected as balance overflow", client, count);
+        }
+        if !mismatches.is_empty() {
+            return Err(format!("Audit pass found {} clients with mismatched balances", mismatches.len()).into());
+        }
+    }
+    match (&args.out, args.shard_output, &args.out_dir) {
+        (_, Some(rows), Some(dir)) => {
+            std::fs::create_dir_all(dir)?;
+            let files = model.write_accounts_sharded(Path::new(dir), rows, &args.output)?;
+            info!("Wrote accounts to {} files in {}", files, dir);
         }
-        if tr.tr_type == TransactionType::Dispute {
-            if self.disputed_transactions.contains(&tr.tx) {
-                warn!("Transaction already disputed: {:?}", tr);
-                return;
+        (Some(path), _, _) => write_atomic(Path::new(path), |writer| model.write_accounts(writer, &args.output))?,
+        (None, _, _) => model.print_to_stdout(&args.output)?,
+    }
+    // Accounts output is the same bytes each time it's written, so hashing it again matches the file
+    let checksum = if args.checksum { Some(model.accounts_checksum(&args.output)?) } else { None };
+    if let (Some(checksum), Some(path)) = (&checksum, &args.out) {
+        write_atomic(&checksum_path(path), |writer| Ok(writeln!(writer, "{}  {}", checksum, file_name(path))?))?;
+    }
+
+    if let Some(path) = &args.snapshot_out {
+        write_atomic(Path::new(path), |writer| model.write_snapshot(writer))?;
+    }
+    if let Some(dir) = &args.per_client_dir
+        && args.command == Command::Process
+    {
+        let count = model.write_statements(std::fs::File::open(statement_events(dir))?, Path::new(dir), args.statement_clients.as_ref())?;
+        info!("Wrote {} client statements to {}", count, dir);
+    }
+
+    let trial_balance = model.trial_balance();
+    if let Some(path) = &args.trial_balance {
+        write_atomic(Path::new(path), |writer| {
+            if path.ends_with(".json") {
+                trial_balance.write_json(writer)
+            } else {
+                trial_balance.write_csv(writer)
             }
+        })?;
+    }
+    if let Some(path) = &args.sar_out {
+        write_atomic(Path::new(path), |writer| model.write_suspicious_clients(writer, &args.sar))?;
+    }
+    if let Some(path) = &args.batch_report {
+        write_atomic(Path::new(path), |writer| report.write_batch_report(writer))?;
+    }
+    if let Some(checksum) = &checksum {
+        eprintln!("Checksum: sha256:{}", checksum);
+    }
+    if args.top > 0 {
+        eprintln!("{}", model.top_clients(args.top));
+    }
+    if args.summary {
+        eprintln!("Records: {} ({} malformed)", report.records, report.parse_errors);
+        if report.unknown_types > 0 {
+            eprintln!("Unknown types skipped: {}", report.unknown_types);
+        }
+        if report.duplicates_identical > 0 {
+            eprintln!("Identical duplicates skipped: {}", report.duplicates_identical);
+        }
+        if report.outside_window > 0 {
+            eprintln!("Outside time window skipped: {}", report.outside_window);
+        }
+        if report.integer_amounts > 0 {
+            eprintln!("Amounts without decimal point: {}", report.integer_amounts);
+        }
+        eprintln!("Rounding: {}", report.rounding);
+        if skip_rows > 0 || report.truncated {
+            eprintln!("Run truncated: first {} rows skipped, stopped {} end of input", skip_rows, if report.truncated { "before" } else { "at" });
+        }
+        for (batch, counts) in &report.batches {
+            eprintln!("Batch {}: {} rows, {} applied, {} rejected", batch, counts.rows, counts.applied, counts.rejected_total());
+        }
+        eprintln!("{}", model.stats());
+        let overflows = model.overflow_clients();
+        if !overflows.is_empty() {
+            let rejected: u32 = overflows.iter().map(|(_, count)| count).sum();
+            eprintln!("Balance overflows: {} rejected, clients {}", rejected, overflows.iter().map(|(client, _)| client.to_string()).collect::<Vec<_>>().join(", "));
+        }
+    }
+    if args.warn_summary {
+        eprintln!("Malformed rows: {}", report.parse_errors);
+        if report.rejected.is_empty() {
+            eprintln!("Rejected: none");
         } else {
-            if !self.disputed_transactions.contains(&tr.tx) {
-                warn!("Resolve/Chargeback on non-disputed transaction: {:?}", tr);
-                return;
-            }
+            eprintln!("Rejected: {}", report.rejection_summary());
         }
-
-        let Some(amount) = original_tr.amount else {
-            warn!("Dispute/Resolve/Chargeback {:?} on transaction without amount: {:?}", tr, original_tr);
-            return;
+    }
+    // Last, so output hashes are of the final files
+    if let Some(path) = &args.manifest {
+        let manifest = Manifest {
+            version: env!("CARGO_PKG_VERSION"),
+            started: started.to_rfc3339(),
+            finished: now().to_rfc3339(),
+            config_sha256,
+            inputs: inputs.into_iter()
+                .map(|(path, rows)| Ok(ManifestFile { sha256: file_hash(&path)?, path, rows: Some(rows) }))
+                .collect::<std::io::Result<_>>()?,
+            outputs: output_files(&args)?.into_iter()
+                .map(|path| Ok(ManifestFile { sha256: file_hash(&path)?, path, rows: None }))
+                .collect::<std::io::Result<_>>()?,
+            summary: ManifestSummary {
+                records: report.records,
+                parse_errors: report.parse_errors,
+                duplicates_identical: report.duplicates_identical,
+                truncated: report.truncated,
+                clients: trial_balance.clients,
+                locked_clients: trial_balance.locked_clients,
+            },
         };
-
-        let Some(client) = self.clients.get_mut(&tr.client) else {
-            warn!("Client not found for Dispute/Resolve/Chargeback: {:?}", tr);
-            return;
+        write_atomic(Path::new(path), |writer| Ok(serde_json::to_writer_pretty(writer, &manifest)?))?;
+    }
+    let rejected: u64 = report.rejected.values().sum();
+    let fail = args.fail_on_reject && rejected > 0;
+    if fail {
+        error!("{} rows rejected, failing as asked by --fail-on-reject", rejected);
+    }
+    if args.exit_summary {
+        let summary = ExitSummary {
+            exit_code: if fail { EXIT_REJECTS } else { 0 },
+            records: report.records,
+            applied: report.applied,
+            malformed: report.parse_errors,
+            unknown_types: report.unknown_types,
+            rejected: &report.rejected,
+            clients: trial_balance.clients,
+            locked: trial_balance.locked_clients,
         };
+        eprintln!("{}", serde_json::to_string(&summary)?);
+    }
+    if fail {
+        return Err(RejectsPresent(rejected).into());
+    }
+    Ok(())
+}
 
-        match tr.tr_type {
-            TransactionType::Dispute => {
-                client.available -= amount;
-                client.held += amount;
-                self.disputed_transactions.insert(tr.tx);
-            }
-            TransactionType::Resolve => {
-                client.held -= amount;
-                client.available += amount;
-                self.disputed_transactions.remove(&tr.tx);
-            }
-            TransactionType::Chargeback => {
-                client.held -= amount;
-                client.total -= amount;
-                self.disputed_transactions.remove(&tr.tx);
-                client.locked = true;
-            }
-            _ => {
-                warn!("Unexpected transaction type: {:?}", tr);
-            }
+// Audit record of a run: which inputs and settings produced which outputs
+#[derive(Serialize)]
+struct Manifest {
+    version: &'static str,
+    started: String,
+    finished: String,
+    // SHA-256 of effective config as printed by --print-config, policy included
+    config_sha256: String,
+    inputs: Vec<ManifestFile>,
+    outputs: Vec<ManifestFile>,
+    summary: ManifestSummary,
+}
+
+#[derive(Serialize)]
+struct ManifestFile {
+    path: String,
+    // Of file as stored, compressed if it is; none for stdin
+    sha256: Option<String>,
+    // Data rows read, inputs only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rows: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ManifestSummary {
+    records: u64,
+    parse_errors: u64,
+    duplicates_identical: u64,
+    truncated: bool,
+    clients: usize,
+    locked_clients: usize,
+}
+
+// chrono is built without its clock feature
+fn now() -> DateTime<Utc> {
+    DateTime::from(std::time::SystemTime::now())
+}
+
+fn file_hash(path: &str) -> std::io::Result<Option<String>> {
+    if path == "-" {
+        return Ok(None);
+    }
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(Some(hex(&hasher.finalize())))
+}
+
+// Sidecar of accounts file in sha256sum format, checked by "sha256sum -c" from its directory
+fn checksum_path(out: &str) -> PathBuf {
+    PathBuf::from(format!("{}.sha256", out))
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path).file_name().map_or_else(|| path.to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+// Files written by the run, accounts to stdout are not listed
+fn output_files(args: &Args) -> std::io::Result<Vec<String>> {
+    let mut paths: Vec<String> = [&args.out, &args.snapshot_out, &args.trial_balance, &args.sar_out, &args.batch_report, &args.events_out]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+    if args.checksum && let Some(out) = &args.out {
+        paths.push(checksum_path(out).to_string_lossy().into_owned());
+    }
+    if args.shard_output.is_some() && let Some(dir) = &args.out_dir {
+        paths.extend(dir_files(dir, "accounts_")?);
+    }
+    if let Some(dir) = &args.per_client_dir {
+        paths.extend(dir_files(dir, "client-")?);
+    }
+    Ok(paths)
+}
+
+// Sorted paths of files in dir with names starting with prefix
+fn dir_files(dir: &str, prefix: &str) -> std::io::Result<Vec<String>> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(prefix)) {
+            paths.push(path.to_string_lossy().into_owned());
         }
     }
+    paths.sort();
+    Ok(paths)
+}
+
+// Takes advisory lock "<path>.lock" for each state/output file, so overlapping runs don't corrupt them.
+// Locks are released when files are closed, OS does it on any exit including signals.
+fn lock_state(args: &Args) -> Result<Vec<std::fs::File>, Box<dyn std::error::Error>> {
+    let mut paths: Vec<&String> = [&args.snapshot_in, &args.snapshot_out, &args.out].into_iter().flatten().collect();
+    paths.sort();
+    paths.dedup();
 
-    fn process_transaction(&mut self, tr: Transaction) {
-        match tr.tr_type {
-            TransactionType::Deposit | TransactionType::Withdrawal => {
-                self.process_revertable_transaction(tr);
+    let mut locks = Vec::new();
+    for path in paths {
+        let lock_path = format!("{}.lock", path);
+        let file = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_path)?;
+        let deadline = args.wait_for_lock.map(|secs| Instant::now() + Duration::from_secs(secs));
+        while let Err(err) = file.try_lock_exclusive() {
+            if err.kind() != fs2::lock_contended_error().kind() {
+                return Err(err.into());
             }
-            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
-                self.process_dispute_resolve_chargeback(tr);
+            if deadline.is_none_or(|deadline| Instant::now() >= deadline) {
+                return Err(format!("{} is locked by another instance (lock file {})", path, lock_path).into());
             }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        locks.push(file);
+    }
+    Ok(locks)
+}
+
+// Each file gets a fresh model, failed files are reported and the rest still processed
+fn batch(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(batch_dir), Some(out_dir)) = (&args.batch_dir, &args.out_dir) else {
+        return Err("--batch-dir requires --out-dir".into());
+    };
+    if args.out.is_some() || args.snapshot_in.is_some() || args.snapshot_out.is_some() || args.track_offsets || args.shard_output.is_some() {
+        return Err("--batch-dir can't be used with --out, --snapshot-in, --snapshot-out, --track-offsets or --shard-output".into());
+    }
+    let mut inputs = Vec::new();
+    for entry in std::fs::read_dir(batch_dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|extension| extension == "csv") {
+            inputs.push(path);
         }
     }
+    inputs.sort();
+    std::fs::create_dir_all(out_dir)?;
+    let output = OutputOptions { format: args.format.unwrap_or(OutputFormat::Csv), ..args.output.clone() };
 
-    fn process_transactions(&mut self, input: &String) -> Result<(), Box<dyn std::error::Error>> {
-        let csv_text = std::fs::read_to_string(input).expect("Error reading file");
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .trim(csv::Trim::All)
-            .from_reader(csv_text.as_bytes());
+    // Workers take next file by index, results keep input order
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<Result<ProcessingReport, String>>>> = Mutex::new(inputs.iter().map(|_| None).collect());
+    let jobs = args.jobs.unwrap_or(1).clamp(1, inputs.len().max(1));
+    std::thread::scope(|scope| {
+        for _ in 0..jobs {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(input) = inputs.get(index) else {
+                        break;
+                    };
+                    let result = process_batch_file(input, Path::new(out_dir), &args.config, &output).map_err(|err| err.to_string());
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
 
-        for result in rdr.deserialize::<Transaction>() {
-            if let Ok(tr) = result {
-                self.process_transaction(tr);
-            } else {
-                warn!("Error deserializing transaction: {:?}", result);
+    let mut failed = 0;
+    for (input, result) in inputs.iter().zip(results.into_inner().unwrap()) {
+        match result.expect("Batch file not processed") {
+            Ok(report) => info!("{}: {} records ({} malformed)", input.display(), report.records, report.parse_errors),
+            Err(err) => {
+                error!("{}: {}", input.display(), err);
+                failed += 1;
             }
         }
+    }
+    eprintln!("Batch: {} files processed, {} failed", inputs.len() - failed, failed);
+    if failed > 0 {
+        return Err(format!("{} of {} batch files failed", failed, inputs.len()).into());
+    }
+    Ok(())
+}
+
+fn process_batch_file(input: &Path, out_dir: &Path, config: &ModelConfig, output: &OutputOptions) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+    let mut model = Model::with_config(config.clone());
+    let report = model.process_transactions(&input.to_string_lossy())?;
+    let name = input.file_stem().unwrap_or_default().to_string_lossy();
+    write_atomic(&out_dir.join(format!("{}.accounts.csv", name)), |writer| model.write_accounts(writer, output))?;
+    Ok(report)
+}
+
+// Reference run reads buffered with all clients in memory; only the result is compared, no outputs are written
+fn self_check(args: &mut Args) -> Result<(), Box<dyn std::error::Error>> {
+    if args.command != Command::Process {
+        return Err("--self-check applies to processing only".into());
+    }
+    if args.input.as_deref().is_none_or(|input| input == "-") {
+        return Err("--self-check requires input file, stdin can't be read twice".into());
+    }
+    args.events_out = None;
+    args.per_client_dir = None;
+    let config = args.config.clone();
+    let mmap = std::mem::take(&mut args.mmap);
+    let expected_clients = args.expected_clients.take();
+    let spill_dir = args.spill_dir.take();
+
+    let started = Instant::now();
+    let (reference, _, _) = process(args)?;
+    let reference_time = started.elapsed();
 
-        Ok(())
+    (args.config, args.mmap, args.expected_clients, args.spill_dir) = (config, mmap, expected_clients, spill_dir);
+    let started = Instant::now();
+    let (selected, _, _) = process(args)?;
+    let selected_time = started.elapsed();
+    eprintln!("Self-check: reference run {:.3}s, selected options {:.3}s", reference_time.as_secs_f64(), selected_time.as_secs_f64());
+
+    let render = |model: &Model, options: &OutputOptions| -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, options)?;
+        Ok(output)
+    };
+    if render(&reference, &args.output)? == render(&selected, &args.output)? {
+        eprintln!("Self-check: accounts match");
+        return Ok(());
+    }
+    let options = OutputOptions::default();
+    let expected = read_accounts(&render(&reference, &options)?[..])?;
+    let actual = read_accounts(&render(&selected, &options)?[..])?;
+    for delta in diff_accounts(&expected, &actual) {
+        println!("{}", delta);
     }
+    Err("Self-check failed, selected options change accounts output".into())
+}
 
-    fn print_to_stdout(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut wtr = csv::Writer::from_writer(std::io::stdout());
-        for client in self.clients.values() {
-            wtr.serialize(client)?;
+fn anonymize(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let reader = match &args.input {
+        Some(input) => open_reader(open_input(input)?)?,
+        None => open_reader(std::io::stdin())?,
+    };
+    let rows = match &args.out {
+        Some(path) => {
+            let mut rows = 0;
+            write_atomic(Path::new(path), |writer| {
+                rows = write_anonymized(reader, writer, &args.anonymize)?;
+                Ok(())
+            })?;
+            rows
         }
-        wtr.flush()?;
+        None => write_anonymized(reader, std::io::stdout().lock(), &args.anonymize)?,
+    };
+    info!("Anonymized {} rows", rows);
+    Ok(())
+}
+
+fn canonicalize(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let reader = match &args.input {
+        Some(input) => open_reader(open_input(input)?)?,
+        None => open_reader(std::io::stdin())?,
+    };
+    let model = Model::with_config(args.config.clone());
+    let rows = match &args.out {
+        Some(path) => {
+            let mut rows = 0;
+            write_atomic(Path::new(path), |writer| {
+                rows = model.write_canonical(reader, writer)?;
+                Ok(())
+            })?;
+            rows
+        }
+        None => model.write_canonical(reader, std::io::stdout().lock())?,
+    };
+    info!("Canonicalized {} rows", rows);
+    Ok(())
+}
+
+fn diff(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let (Some(old), Some(new)) = (&args.input, &args.diff_with) else {
+        return Err("diff requires old and new accounts files".into());
+    };
+    let old = read_accounts(open_input(old)?)?;
+    let new = read_accounts(open_input(new)?)?;
+    for delta in diff_accounts(&old, &new) {
+        println!("{}", delta);
+    }
+    Ok(())
+}
+
+fn generate(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    if !(0.0..=1.0).contains(&args.sample.dispute_ratio) {
+        return Err(format!("--dispute-ratio must be between 0 and 1: {}", args.sample.dispute_ratio).into());
+    }
+    match &args.out {
+        Some(path) => write_atomic(Path::new(path), |writer| write_sample(writer, &args.sample)),
+        None => write_sample(std::io::stdout().lock(), &args.sample),
+    }
+}
+
+// Temporary event log of the run in --per-client-dir
+fn statement_events(dir: &str) -> std::path::PathBuf {
+    Path::new(dir).join(".events.ndjson")
+}
+
+// Temporary file removed when dropped, if it was created
+struct RemoveOnDrop(std::path::PathBuf);
 
-        Ok(())
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
     }
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
+// Writes to both, e.g. --events-out file and event log for statements
+struct Tee(Box<dyn Write>, Box<dyn Write>);
 
-    let args: Vec<String> = env::args().collect();
-    let input = &args[1];
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write_all(buf)?;
+        self.1.write_all(buf)?;
+        Ok(buf.len())
+    }
 
-    let mut model = Model::new();
-    model.process_transactions(input)?;
-    model.print_to_stdout()
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()?;
+        self.1.flush()
+    }
+}
+
+fn replay(args: &Args) -> Result<Model, Box<dyn std::error::Error>> {
+    let model = match &args.input {
+        Some(input) => Model::replay_events(open_input(input)?)?,
+        None => Model::replay_events(std::io::stdin())?,
+    };
+    Ok(model)
+}
+
+// Path of each input ("-" for stdin) with data rows read from it
+type InputRows = Vec<(String, u64)>;
+
+// Model after processing, with counters of the whole run
+fn process(args: &mut Args) -> Result<(Model, ProcessingReport, InputRows), Box<dyn std::error::Error>> {
+    // Recorded offsets must cover the whole consumed prefix
+    if args.track_offsets && (args.config.skip_rows > 0 || args.config.limit_rows.is_some()) {
+        return Err("--skip and --limit can't be used with --track-offsets".into());
+    }
+    // Offset to resume from comes from the snapshot
+    if args.config.resume_from.is_some() && args.snapshot_in.is_none() {
+        return Err("--resume-from needs --snapshot-in".into());
+    }
+    let config = std::mem::take(&mut args.config);
+    let mut model = match &args.snapshot_in {
+        Some(path) => Model::from_snapshot(config, std::fs::File::open(path)?)?,
+        None => Model::with_config(config),
+    };
+    if let Some(expected_clients) = args.expected_clients {
+        model.reserve_clients(expected_clients);
+    }
+    if let Some(dir) = &args.spill_dir {
+        model.set_spill_dir(Path::new(dir), args.spill_memory_cap.unwrap_or(DEFAULT_SPILL_MEMORY_CAP))?;
+    }
+    let mut events: Option<Box<dyn Write>> = None;
+    if let Some(path) = &args.events_out {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        events = Some(match args.flush_interval {
+            Some(_) => Box::new(std::io::BufWriter::new(file)),
+            None => Box::new(std::io::LineWriter::new(file)),
+        });
+    } else if args.flush_interval.is_some() {
+        warn!("--flush-interval applies to --events-out only");
+    }
+    // Statements are written from this run's events once processing is done
+    if let Some(dir) = &args.per_client_dir {
+        std::fs::create_dir_all(dir)?;
+        let file: Box<dyn Write> = Box::new(std::io::BufWriter::new(std::fs::File::create(statement_events(dir))?));
+        events = Some(match events {
+            Some(events) => Box::new(Tee(events, file)),
+            None => file,
+        });
+    }
+    match (events, args.flush_interval) {
+        (Some(events), Some(interval)) => model.set_buffered_events_writer(events, interval),
+        (Some(events), None) => model.set_events_writer(events),
+        (None, _) => {}
+    }
+
+    let Some(pattern) = args.input.as_deref().filter(|input| is_glob(input)) else {
+        let report = process_input(&mut model, args, args.input.as_deref())?;
+        let input = args.input.clone().unwrap_or_else(|| "-".to_string());
+        let records = report.records;
+        return Ok((model, report, vec![(input, records)]));
+    };
+    let inputs = expand_glob(pattern)?;
+    if inputs.is_empty() && !args.allow_empty_glob {
+        return Err(format!("Input pattern {} matches no files", pattern).into());
+    }
+    let mut report = ProcessingReport { rounding: args.output.rounding, ..ProcessingReport::default() };
+    let mut rows = Vec::new();
+    for input in inputs {
+        info!("Processing {}", input);
+        let input_report = process_input(&mut model, args, Some(&input))?;
+        rows.push((input, input_report.records));
+        report.merge(input_report);
+    }
+    Ok((model, report, rows))
+}
+
+// Shell-style pattern, for shells and service managers which don't expand them
+fn is_glob(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+// Matching files in lexicographic order
+fn expand_glob(pattern: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut inputs = Vec::new();
+    for path in glob::glob(pattern).map_err(|err| format!("Invalid input pattern {}: {}", pattern, err))? {
+        let path = path?;
+        if !path.is_dir() {
+            inputs.push(path.to_string_lossy().into_owned());
+        }
+    }
+    inputs.sort();
+    Ok(inputs)
+}
+
+// Input file, stdin when not given
+fn process_input(model: &mut Model, args: &Args, input: Option<&str>) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+    if args.track_offsets {
+        let Some(input) = input else {
+            return Err("--track-offsets requires input file".into());
+        };
+        if args.input_format != InputFormat::Csv {
+            return Err("--track-offsets supports CSV input only".into());
+        }
+        // Offsets are of file bytes, decoded ones would not match
+        if args.encoding != InputEncoding::Utf8 {
+            return Err("--track-offsets supports UTF-8 input only".into());
+        }
+        return model.process_file_resumable(input);
+    }
+
+    if args.input_format == InputFormat::Xlsx {
+        return process_xlsx(model, args, input);
+    }
+
+    // Common case of plain or gzipped UTF-8 file, read without boxing
+    if let Some(input) = input
+        && !args.mmap
+        && args.encoding == InputEncoding::Utf8
+    {
+        return process_text(model, args, open_file_reader(open_input(input)?)?);
+    }
+    let reader: Box<dyn Read> = match input {
+        Some(input) if args.mmap => {
+            let file = open_input(input)?;
+            // Pipes and other special files can't be mapped
+            match open_mmap(&file) {
+                Ok(reader) => reader,
+                Err(err) => {
+                    warn!("Can't map {}, reading buffered: {}", input, err);
+                    open_reader(file)?
+                }
+            }
+        }
+        Some(input) => open_reader(open_input(input)?)?,
+        None => {
+            if args.mmap {
+                warn!("--mmap needs input file, reading stdin buffered");
+            }
+            open_reader(std::io::stdin())?
+        }
+    };
+    process_text(model, args, decode_reader(reader, args.encoding))
+}
+
+// CSV or NDJSON input
+fn process_text<R: Read>(model: &mut Model, args: &Args, reader: R) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+    match args.input_format {
+        InputFormat::Csv => model.process_source(model.csv_source(reader)),
+        InputFormat::Ndjson => model.process_source(model.ndjson_source(reader)),
+        InputFormat::Xlsx => unreachable!("xlsx input is processed by process_xlsx"),
+    }
+}
+
+// Workbook is a zip archive read by random access, so it can't come from stdin
+#[cfg(feature = "xlsx")]
+fn process_xlsx(model: &mut Model, args: &Args, input: Option<&str>) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+    let Some(input) = input else {
+        return Err("xlsx input requires input file".into());
+    };
+    let source = process_transactions::xlsx::XlsxSource::open(input, args.sheet.as_deref())?;
+    model.process_source(source)
+}
+
+#[cfg(not(feature = "xlsx"))]
+fn process_xlsx(_model: &mut Model, _args: &Args, _input: Option<&str>) -> Result<ProcessingReport, Box<dyn std::error::Error>> {
+    Err("xlsx input requires building with --features xlsx".into())
 }
 
 #[cfg(test)]
 mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
 
-    #[test]
-    fn test_base() {
-        run_case("01-transactions-base", "01-accounts-base")
+    fn money(text: &str) -> Money {
+        text.parse().expect("Invalid amount")
     }
 
-    #[test]
-    fn test_dispute() {
-        run_case("02-transactions-dispute", "02-accounts-dispute")
+    fn args(list: &[&str]) -> Args {
+        let list: Vec<String> = list.iter().map(|s| s.to_string()).collect();
+        Args::parse(&list).expect("Error parsing args")
     }
 
     #[test]
-    fn test_resolve() {
-        run_case("03-transactions-resolve", "03-accounts-resolve")
+    fn test_output_scale() {
+        let args = args(&["--output-scale", "2"]);
+        let mut model = Model::default();
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 100.5\ndeposit, 2, 2, 0.0001\n";
+        model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &args.output).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,10050,0,10050,false\n2,0,0,0,false\n");
     }
 
+    // Input amount has five decimals, invalid for minor-units backend
+    #[cfg(not(feature = "minor-units"))]
     #[test]
-    fn test_resolve_no_dispute() {
-        run_case("04-transactions-resolve-no-dispute", "04-accounts-resolve-no-dispute")
+    fn test_precision() {
+        let mut model = Model::default();
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 2.34567\nwithdrawal, 1, 2, 0.001\n";
+        model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+
+        let render = |list: &[&str]| {
+            let mut output = Vec::new();
+            model.write_accounts(&mut output, &args(list).output).expect("Error writing accounts");
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(render(&["--precision", "2"]), "client,available,held,total,locked\n1,2.34,0.00,2.34,false\n");
+        assert_eq!(render(&["--precision", "4"]), "client,available,held,total,locked\n1,2.3447,0.0000,2.3447,false\n");
+        assert_eq!(render(&["--precision", "0"]), "client,available,held,total,locked\n1,2,0,2,false\n");
+        assert_eq!(model.available(1), Some(money("2.34467")));
+        assert!(Args::parse(&["--precision".to_string(), "7".to_string()]).is_err());
     }
 
     #[test]
-    fn test_chargeback() {
-        run_case("05-transactions-chargeback", "05-accounts-chargeback")
+    fn test_balances_only() {
+        let args = args(&["--balances-only"]);
+        let mut model = Model::default();
+        model.process_transactions("cases/02-transactions-dispute.csv").expect("Processing failed");
+
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &args.output).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,total\n1,1.5000\n2,2.0000\n");
     }
 
     #[test]
-    fn test_unexpected() {
-        run_case("06-transactions-unexpected", "06-accounts-unexpected")
+    fn test_max_balance() {
+        let args = args(&["--max-balance", "100"]);
+        let mut model = Model::with_config(args.config);
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 60.0\ndeposit, 1, 2, 60.0\n";
+        model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+
+        let client = model.client(1).expect("Client missing");
+        assert_eq!(client.available, money("60"));
+        assert_eq!(client.total, money("60"));
     }
 
     #[test]
-    fn test_dispute_wrong_client() {
-        run_case("07-transactions-dispute-wrong-client", "07-accounts-dispute-wrong-client")
+    fn test_allow_types() {
+        let args = args(&["--allow-types", "deposit"]);
+        let mut model = Model::with_config(args.config);
+        let csv_text = "type, client, tx, amount, batch\ndeposit, 1, 1, 5.0, A\nwithdrawal, 1, 2, 1.0, A\ndispute, 1, 1, , A\n";
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+
+        let counts = &report.batches["A"];
+        assert_eq!((counts.applied, counts.rejected.get("type_not_allowed")), (1, Some(&2)));
+        assert_eq!((model.available(1), model.held(1)), (Some(money("5.0")), Some(money("0"))));
+        assert!(Args::parse(&["--allow-types".to_string(), "deposit,refund".to_string()]).is_err());
     }
 
-    fn run_case(input_name: &str, output_name: &str) {
-        let input = format!("cases/{}.csv", input_name);
-        let mut model = Model::new();
-        model.process_transactions(&input).expect("Processing failed");
+    #[test]
+    fn test_deny_clients() {
+        let args = args(&["--deny-clients", "2"]);
+        let mut model = Model::with_config(args.config);
+        model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
 
-        let output = format!("cases/{}.csv", output_name);
-        let expected_csv = std::fs::read_to_string(output).expect("Error reading expected");
-        let mut rdr = csv::ReaderBuilder::new()
-            .has_headers(true)
-            .trim(csv::Trim::All)
-            .from_reader(expected_csv.as_bytes());
+        let mut output = Vec::new();
+        model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(output).unwrap(), "client,available,held,total,locked\n1,0.5000,0.0000,0.5000,true\n");
+    }
 
-        let mut record_count = 0;
-        for result in rdr.deserialize::<Client>() {
-            let expected_client: Client = result.expect("Error deserializing client");
-            let actual_client = model.clients.get(&expected_client.client).expect("Client missing");
-            assert_eq!(&expected_client, actual_client, "Client data mismatch for client {}", expected_client.client);
-            record_count += 1;
-        }
-        assert_eq!(model.clients.len(), record_count, "Number of clients mismatch");
+    #[test]
+    fn test_amount_range() {
+        let args = args(&["--max-amount", "1000"]);
+        let mut model = Model::with_config(args.config);
+        let csv_text = "type, client, tx, amount\ndeposit, 1, 1, 5000.0\ndeposit, 1, 2, 500.0\n";
+        let report = model.process_reader(csv_text.as_bytes()).expect("Processing failed");
+
+        assert_eq!(report.parse_errors, 0);
+        assert_eq!(model.total(1), Some(money("500")));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,490 @@
+use std::fmt;
+use std::iter::Sum;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Money backend, selected by cargo feature: 96-bit fixed point rust_decimal by default,
+// arbitrary precision bigdecimal for instruments exceeding its 28 significant digits,
+// i64 count of 1/10000 units (minor-units) for the hot path
+#[cfg(all(feature = "bigdecimal", feature = "minor-units"))]
+compile_error!("Features \"bigdecimal\" and \"minor-units\" select different money backends, enable at most one");
+
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+type Inner = rust_decimal::Decimal;
+#[cfg(feature = "bigdecimal")]
+type Inner = bigdecimal::BigDecimal;
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+type Inner = i64;
+
+// Name of the compiled backend, for --version
+#[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+pub const BACKEND: &str = "rust_decimal";
+#[cfg(feature = "bigdecimal")]
+pub const BACKEND: &str = "bigdecimal";
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+pub const BACKEND: &str = "minor-units";
+
+// Decimals of minor-units backend, amounts with more are invalid
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+const MINOR_PLACES: u32 = 4;
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+const MINOR_SCALE: i64 = 10i64.pow(MINOR_PLACES);
+
+// Exact decimal amount, all parsing, arithmetic and formatting of money goes through it.
+// Decimal backends keep scale of parsed text ("10.50" displays as "10.50"), minor-units always
+// displays four decimals. Not Copy, as arbitrary precision backend allocates.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(Inner);
+
+impl Money {
+    pub fn zero() -> Money {
+        Money::default()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Money::zero()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        *self < Money::zero()
+    }
+
+    // Multiplies by 10^places, e.g. to get minor units
+    #[cfg(not(all(feature = "minor-units", not(feature = "bigdecimal"))))]
+    #[allow(clippy::op_ref)]
+    pub fn shift(&self, places: u32) -> Money {
+        Money(&self.0 * Inner::from(10i64.pow(places)))
+    }
+
+    // None instead of overflowing the backend's range, so a balance move can be rejected
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn checked_add(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_add(rhs.0).map(Money)
+    }
+
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn checked_sub(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_sub(rhs.0).map(Money)
+    }
+
+    // Arbitrary precision never overflows
+    #[cfg(feature = "bigdecimal")]
+    pub fn checked_add(&self, rhs: &Money) -> Option<Money> {
+        Some(self + rhs)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn checked_sub(&self, rhs: &Money) -> Option<Money> {
+        Some(self - rhs)
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn checked_add(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_add(rhs.0).map(Money)
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn checked_sub(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_sub(rhs.0).map(Money)
+    }
+
+    // Exactly `places` decimals, rounded half away from zero
+    pub fn format_fixed(&self, places: u32) -> String {
+        self.format_rounded(places, Rounding::HalfUp)
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn shift(&self, places: u32) -> Money {
+        Money(self.0 * 10i64.pow(places))
+    }
+
+    // At most `places` decimals, amounts with fewer keep their scale
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn round(&self, places: u32, rounding: Rounding) -> Money {
+        use rust_decimal::RoundingStrategy;
+        let strategy = match rounding {
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::Truncate => RoundingStrategy::ToZero,
+        };
+        let mut rounded = self.0.round_dp_with_strategy(places, strategy);
+        // Rounding small negative amount keeps the sign, "-0.00" is not wanted
+        if rounded.is_zero() {
+            rounded.set_sign_positive(true);
+        }
+        Money(rounded)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn round(&self, places: u32, rounding: Rounding) -> Money {
+        use bigdecimal::RoundingMode;
+        if self.0.fractional_digit_count() <= places as i64 {
+            return self.clone();
+        }
+        let mode = match rounding {
+            Rounding::HalfUp => RoundingMode::HalfUp,
+            Rounding::HalfEven => RoundingMode::HalfEven,
+            Rounding::Truncate => RoundingMode::Down,
+        };
+        Money(self.0.with_scale_round(places as i64, mode))
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn round(&self, places: u32, rounding: Rounding) -> Money {
+        if places >= MINOR_PLACES {
+            return self.clone();
+        }
+        let factor = 10i64.pow(MINOR_PLACES - places);
+        let (quotient, remainder) = (self.0 / factor, self.0 % factor);
+        let twice = remainder.unsigned_abs() * 2;
+        let away = match rounding {
+            Rounding::HalfUp => twice >= factor as u64,
+            Rounding::HalfEven => twice > factor as u64 || (twice == factor as u64 && quotient % 2 != 0),
+            Rounding::Truncate => false,
+        };
+        let quotient = if away { quotient + self.0.signum() } else { quotient };
+        Money(quotient * factor)
+    }
+
+    // Exactly `places` decimals
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    pub fn format_rounded(&self, places: u32, rounding: Rounding) -> String {
+        let mut rounded = self.round(places, rounding).0;
+        rounded.rescale(places);
+        rounded.to_string()
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn format_rounded(&self, places: u32, rounding: Rounding) -> String {
+        self.round(places, rounding).0.with_scale(places as i64).to_plain_string()
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    pub fn format_rounded(&self, places: u32, rounding: Rounding) -> String {
+        let units = self.round(places, rounding).0;
+        let sign = if units < 0 { "-" } else { "" };
+        let (whole, fraction) = (units.unsigned_abs() / MINOR_SCALE as u64, units.unsigned_abs() % MINOR_SCALE as u64);
+        if places == 0 {
+            return format!("{}{}", sign, whole);
+        }
+        // Digits beyond kept ones are zero after rounding
+        let mut fraction = format!("{:04}", fraction);
+        fraction.truncate(places as usize);
+        format!("{}{}.{:0<width$}", sign, whole, fraction, width = places as usize)
+    }
+}
+
+// Minor units of decimal text without going through f64, at most MINOR_PLACES significant decimals
+#[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+fn parse_minor_units(s: &str) -> Result<i64, &'static str> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    let is_digits = |text: &str| text.bytes().all(|byte| byte.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(whole) || !is_digits(fraction) {
+        return Err("not a decimal number");
+    }
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.len() > MINOR_PLACES as usize {
+        return Err("more than 4 decimal places");
+    }
+    let whole: i64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| "out of range")? };
+    let fraction: i64 = format!("{:0<4}", fraction).parse().map_err(|_| "not a decimal number")?;
+    let units = whole.checked_mul(MINOR_SCALE).and_then(|units| units.checked_add(fraction)).ok_or("out of range")?;
+    Ok(if negative { -units } else { units })
+}
+
+// Rule for dropping decimals beyond the kept ones, set by policy file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+    // Ties away from zero: 2.345 -> 2.35, -2.345 -> -2.35
+    #[default]
+    HalfUp,
+    // Banker's rounding, ties to even digit: 2.345 -> 2.34, 2.355 -> 2.36
+    HalfEven,
+    // Towards zero: 2.349 -> 2.34
+    Truncate,
+}
+
+impl fmt::Display for Rounding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rounding::HalfUp => write!(f, "half-up"),
+            Rounding::HalfEven => write!(f, "half-even"),
+            Rounding::Truncate => write!(f, "truncate"),
+        }
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = if s.contains(['e', 'E']) { Inner::from_scientific(s) } else { Inner::from_str(s) };
+        parsed.map(Money).map_err(|err| format!("Invalid amount {}: {}", s, err))
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Inner::from_str(s).map(Money).map_err(|err| format!("Invalid amount {}: {}", s, err))
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_minor_units(s).map(Money).map_err(|err| format!("Invalid amount {}: {}", s, err))
+    }
+}
+
+impl From<i64> for Money {
+    #[cfg(not(all(feature = "minor-units", not(feature = "bigdecimal"))))]
+    fn from(value: i64) -> Self {
+        Money(Inner::from(value))
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    fn from(value: i64) -> Self {
+        Money(value * MINOR_SCALE)
+    }
+}
+
+impl fmt::Display for Money {
+    #[cfg(not(any(feature = "bigdecimal", feature = "minor-units")))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_plain_string())
+    }
+
+    #[cfg(all(feature = "minor-units", not(feature = "bigdecimal")))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format_fixed(MINOR_PLACES))
+    }
+}
+
+// Written as string, so no precision is lost in JSON
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Reads strings exactly, JSON numbers through their shortest decimal representation
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MoneyVisitor;
+
+        impl serde::de::Visitor<'_> for MoneyVisitor {
+            type Value = Money;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "decimal amount")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Money, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_f64<E: serde::de::Error>(self, value: f64) -> Result<Money, E> {
+                if !value.is_finite() {
+                    return Err(E::custom(format!("Invalid amount {}", value)));
+                }
+                self.visit_str(&value.to_string())
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, value: i64) -> Result<Money, E> {
+                Ok(Money::from(value))
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, value: u64) -> Result<Money, E> {
+                self.visit_str(&value.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, rhs: Money) -> Money {
+        Money(self.0 + rhs.0)
+    }
+}
+
+// Reference operators avoid clones with the allocating backend
+#[allow(clippy::op_ref)]
+impl Add<&Money> for &Money {
+    type Output = Money;
+
+    fn add(self, rhs: &Money) -> Money {
+        Money(&self.0 + &rhs.0)
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, rhs: Money) -> Money {
+        Money(self.0 - rhs.0)
+    }
+}
+
+#[allow(clippy::op_ref)]
+impl Sub<&Money> for &Money {
+    type Output = Money;
+
+    fn sub(self, rhs: &Money) -> Money {
+        Money(&self.0 - &rhs.0)
+    }
+}
+
+impl AddAssign<&Money> for Money {
+    fn add_assign(&mut self, rhs: &Money) {
+        self.0 += &rhs.0;
+    }
+}
+
+impl SubAssign<&Money> for Money {
+    fn sub_assign(&mut self, rhs: &Money) {
+        self.0 -= &rhs.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl<'a> Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        iter.fold(Money::zero(), |sum, amount| &sum + amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn money(text: &str) -> Money {
+        text.parse().expect("Invalid amount")
+    }
+
+    #[test]
+    fn test_exact_arithmetic() {
+        assert_eq!(&money("0.1") + &money("0.2"), money("0.3"));
+        assert_eq!(&money("1.0001") - &money("0.0001"), money("1"));
+        assert_eq!(money("2.5").format_fixed(4), "2.5000");
+        assert_eq!(money("2.3457").format_fixed(2), "2.35");
+        assert_eq!(money("-0.125").format_fixed(2), "-0.13");
+        assert_eq!(money("-0.0001").format_fixed(2), "0.00");
+        assert_eq!(money("100.5").shift(2).format_fixed(0), "10050");
+        assert!("abc".parse::<Money>().is_err());
+        // Decimal backends go beyond four decimals
+        #[cfg(not(feature = "minor-units"))]
+        {
+            assert_eq!(&money("1.00000001") - &money("0.00000001"), money("1"));
+            assert_eq!(money("2.34567").format_fixed(2), "2.35");
+        }
+    }
+
+    #[cfg(feature = "minor-units")]
+    #[test]
+    fn test_minor_units() {
+        assert_eq!(money("10.5").to_string(), "10.5000");
+        assert_eq!(money("-0.0001").shift(4).format_fixed(0), "-1");
+        assert_eq!(money(".5"), money("0.50000"));
+        assert_eq!(Money::from(3), money("3"));
+        for invalid in ["1.00001", "1e2", "", "-", ".", "1.2.3", " 1", "99999999999999999999"] {
+            assert!(invalid.parse::<Money>().is_err(), "{}", invalid);
+        }
+        assert_eq!(super::BACKEND, "minor-units");
+    }
+
+    // Edges of i64 minor units
+    #[cfg(feature = "minor-units")]
+    #[test]
+    fn test_minor_units_bounds() {
+        let max = money("922337203685477.5807");
+        assert_eq!(max.to_string().replace('.', ""), i64::MAX.to_string());
+        assert_eq!((-max.clone()).to_string(), "-922337203685477.5807");
+        assert!("922337203685477.5808".parse::<Money>().is_err());
+        assert!("922337203685478".parse::<Money>().is_err());
+        assert_eq!(max.checked_add(&money("0.0001")), None);
+        assert_eq!((-max.clone()).checked_sub(&money("0.0002")), None);
+        assert_eq!(max.checked_sub(&money("0.0001")), Some(money("922337203685477.5806")));
+    }
+
+    // Canonical four-decimal text of any representable amount parses back to the same text
+    #[test]
+    fn test_parse_render_identity() {
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        for round in 0..10_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            // Mix of full range and small amounts, both signs
+            let units = if round % 2 == 0 { state as i64 } else { (state % 1_000_000) as i64 - 500_000 };
+            let units = units.max(-i64::MAX);
+            let sign = if units < 0 { "-" } else { "" };
+            let text = format!("{}{}.{:04}", sign, units.unsigned_abs() / 10_000, units.unsigned_abs() % 10_000);
+            assert_eq!(money(&text).format_fixed(4), text);
+            // Trailing zeros don't change the amount
+            assert_eq!(money(text.trim_end_matches('0').trim_end_matches('.')).format_fixed(4), text);
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        let amount: Money = serde_json::from_str("1.5").expect("Invalid number");
+        assert_eq!(amount, money("1.5"));
+        let amount: Money = serde_json::from_str("\"12345.1234\"").expect("Invalid string");
+        assert_eq!(serde_json::to_string(&amount).expect("Error serializing"), "\"12345.1234\"");
+        #[cfg(not(feature = "minor-units"))]
+        {
+            let amount: Money = serde_json::from_str("\"12345.12345678\"").expect("Invalid string");
+            assert_eq!(serde_json::to_string(&amount).expect("Error serializing"), "\"12345.12345678\"");
+        }
+    }
+
+    #[test]
+    fn test_rounding_ties() {
+        let round = |text: &str, rounding| money(text).format_rounded(2, rounding);
+        for (text, half_up, half_even, truncate) in [
+            ("2.345", "2.35", "2.34", "2.34"),
+            ("2.355", "2.36", "2.36", "2.35"),
+            ("2.3451", "2.35", "2.35", "2.34"),
+            ("-2.345", "-2.35", "-2.34", "-2.34"),
+            ("-0.005", "-0.01", "0.00", "0.00"),
+            ("0.125", "0.13", "0.12", "0.12"),
+            ("7", "7.00", "7.00", "7.00"),
+        ] {
+            assert_eq!(round(text, Rounding::HalfUp), half_up, "{}", text);
+            assert_eq!(round(text, Rounding::HalfEven), half_even, "{}", text);
+            assert_eq!(round(text, Rounding::Truncate), truncate, "{}", text);
+        }
+        // Amounts within kept decimals are not touched
+        assert_eq!(money("10.5").round(4, Rounding::Truncate), money("10.5"));
+        #[cfg(not(feature = "minor-units"))]
+        assert_eq!(money("10.5").round(4, Rounding::Truncate).to_string(), "10.5");
+    }
+
+    // Beyond 28 significant digits of the default backend
+    #[cfg(feature = "bigdecimal")]
+    #[test]
+    fn test_arbitrary_precision() {
+        let large = money("123456789012345678901234567890.12345678");
+        assert_eq!((&large + &large).to_string(), "246913578024691357802469135780.24691356");
+    }
+}
@@ -0,0 +1,100 @@
+use std::io::Write;
+use crate::{ClientID, TransactionID};
+
+// Shape of a synthetic transactions file, for onboarding and benchmarks
+#[derive(Debug, Clone)]
+pub struct SampleSpec {
+    pub clients: ClientID,
+    pub transactions: u64,
+    // Share of rows disputing an earlier deposit of the same client, 0.0 to 1.0
+    pub dispute_ratio: f64,
+    // Same seed gives the same file
+    pub seed: u64,
+}
+
+impl Default for SampleSpec {
+    fn default() -> Self {
+        SampleSpec { clients: 100, transactions: 10_000, dispute_ratio: 0.01, seed: 1 }
+    }
+}
+
+// xorshift64*, good enough for test data and keeps output stable across platforms
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, ratio: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < ratio
+    }
+}
+
+#[derive(Default)]
+struct SampleClient {
+    // Minor units (1/10000), withdrawals stay within it so none is rejected
+    available: u64,
+    deposits: Vec<(TransactionID, u64)>,
+    disputed: Vec<(TransactionID, u64)>,
+    locked: bool,
+}
+
+// Writes CSV rows of deposits and withdrawals, and disputes of earlier deposits at dispute_ratio,
+// later resolved or charged back by the same client's rows. All rows parse and apply cleanly.
+pub fn write_sample<W: Write>(writer: W, spec: &SampleSpec) -> Result<(), Box<dyn std::error::Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["type", "client", "tx", "amount"])?;
+    let mut rng = Rng(spec.seed.max(1));
+    let mut clients: Vec<SampleClient> = (0..spec.clients.max(1)).map(|_| SampleClient::default()).collect();
+    let mut next_tx: TransactionID = 1;
+    let format_units = |units: u64| format!("{}.{:04}", units / 10_000, units % 10_000);
+
+    for _ in 0..spec.transactions {
+        let index = rng.below(clients.len() as u64) as usize;
+        let id = (index + 1).to_string();
+        let client = &mut clients[index];
+        if !client.disputed.is_empty() && rng.chance(0.5) {
+            let (tx, units) = client.disputed.swap_remove(rng.below(client.disputed.len() as u64) as usize);
+            if rng.chance(0.9) {
+                client.available += units;
+                wtr.write_record(["resolve", &id, &tx.to_string(), ""])?;
+            } else {
+                client.locked = true;
+                wtr.write_record(["chargeback", &id, &tx.to_string(), ""])?;
+            }
+            continue;
+        }
+        // Disputed deposit must be covered by available funds, so the dispute can't push it negative
+        if !client.locked && rng.chance(spec.dispute_ratio)
+            && let Some(position) = client.deposits.iter().position(|(_, units)| *units <= client.available)
+        {
+            let (tx, units) = client.deposits.swap_remove(position);
+            client.available -= units;
+            client.disputed.push((tx, units));
+            wtr.write_record(["dispute", &id, &tx.to_string(), ""])?;
+            continue;
+        }
+        let tx = next_tx;
+        next_tx += 1;
+        if client.available > 10_000 && rng.chance(0.4) {
+            let units = 1 + rng.below(client.available - 1);
+            client.available -= units;
+            wtr.write_record(["withdrawal", &id, &tx.to_string(), &format_units(units)])?;
+        } else {
+            let units = 1 + rng.below(1_000_000);
+            client.available += units;
+            client.deposits.push((tx, units));
+            wtr.write_record(["deposit", &id, &tx.to_string(), &format_units(units)])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
@@ -0,0 +1,1011 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read};
+use std::rc::Rc;
+use serde::Serialize;
+use crate::{ClientID, Transaction, TransactionID, TransactionType};
+use crate::money::Money;
+
+// Position of a transaction in its input
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SourceLocation {
+    // 1-based number of data record, header is not counted
+    pub record: u64,
+    // 1-based line in input
+    pub line: u64,
+    // Byte offset just after the record in (decompressed) input
+    pub end_byte: u64,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "record {} (line {})", self.record, self.line)
+    }
+}
+
+#[derive(Debug)]
+pub enum SourceError {
+    // Malformed record, skipped by processing
+    Record { location: SourceLocation, message: String },
+    // Input can't be read further, aborts processing
+    Io(std::io::Error),
+    // Header doesn't have expected columns in strict schema mode or doesn't fit column map,
+    // or row doesn't fit the schema in strict schema mode, aborts processing
+    Schema(String),
+    // Well-formed row with type value which isn't a transaction type, handled by ModelConfig::unknown_types
+    UnknownType { location: SourceLocation, name: String },
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Record { location, message } => write!(f, "{}: {}", location, message),
+            SourceError::Io(err) => write!(f, "{}", err),
+            SourceError::Schema(message) => write!(f, "{}", message),
+            SourceError::UnknownType { location, name } => write!(f, "{}: unknown transaction type {}", location, name),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {}
+
+// Input format yielding transactions with their location, used by Model::process_source
+pub trait TransactionSource {
+    fn next_transaction(&mut self) -> Option<Result<(Transaction, SourceLocation), SourceError>>;
+
+    // Text of the record last read, as in input, for reporting rows which failed
+    fn raw_record(&self) -> Option<String> {
+        None
+    }
+
+    // Whether header has a timestamp column, once the first record was requested; None for formats without header
+    fn has_timestamp_column(&self) -> Option<bool> {
+        None
+    }
+}
+
+// Convention of thousands separators and decimal mark in amounts
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AmountLocale {
+    // "1,234.56"
+    Dot,
+    // "1 234,56" or "1.234,56"
+    Comma,
+    // Decided per value, ambiguous values like "1,234" are rejected
+    Auto,
+}
+
+impl std::str::FromStr for AmountLocale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(AmountLocale::Dot),
+            "comma" => Ok(AmountLocale::Comma),
+            "auto" => Ok(AmountLocale::Auto),
+            _ => Err(format!("Unknown amount locale: {}", s)),
+        }
+    }
+}
+
+// Rewrites amount to plain "1234.56" form before the strict numeric parse
+pub fn normalize_amount(text: &str, locale: AmountLocale) -> Result<String, String> {
+    // Spaces and apostrophes are only ever thousands separators
+    let text: String = text.chars().filter(|c| !c.is_whitespace() && *c != '\'' && *c != '\u{202f}').collect();
+    let decimal_comma = match locale {
+        AmountLocale::Dot => false,
+        AmountLocale::Comma => true,
+        AmountLocale::Auto => match (text.rfind(','), text.rfind('.')) {
+            (Some(comma), Some(dot)) => comma > dot,
+            (Some(_), None) if text.matches(',').count() > 1 => false,
+            (Some(comma), None) => {
+                let fraction = &text[comma + 1..];
+                if fraction.len() == 3 && fraction.chars().all(|c| c.is_ascii_digit()) {
+                    return Err(format!("ambiguous amount {}: {} or {}", text, text.replace(',', ""), text.replace(',', ".")));
+                }
+                true
+            }
+            _ => false,
+        },
+    };
+    if decimal_comma {
+        Ok(text.replace('.', "").replace(',', "."))
+    } else {
+        Ok(text.replace(',', ""))
+    }
+}
+
+// Removes leading currency symbol, e.g. "$100.00"
+pub fn strip_currency_symbol(text: &str) -> &str {
+    text.strip_prefix(['$', '€', '£']).map_or(text, str::trim_start)
+}
+
+pub(crate) const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+pub(crate) const OPTIONAL_COLUMNS: [&str; 2] = ["timestamp", "batch"];
+
+// Strict schema: all required columns and no unknown ones, so a renamed column isn't silently ignored
+pub fn check_schema(headers: &csv::StringRecord) -> Result<(), String> {
+    let missing: Vec<&str> = REQUIRED_COLUMNS.into_iter().filter(|name| !headers.iter().any(|header| header == *name)).collect();
+    let unexpected: Vec<&str> = headers.iter()
+        .filter(|header| !REQUIRED_COLUMNS.contains(header) && !OPTIONAL_COLUMNS.contains(header))
+        .collect();
+    if missing.is_empty() && unexpected.is_empty() {
+        return Ok(());
+    }
+    Err(format!("Invalid CSV header, missing columns: [{}], unexpected columns: [{}]", missing.join(", "), unexpected.join(", ")))
+}
+
+// Strict schema row: integer IDs in client and tx, amount exactly for types moving funds.
+// Field count is checked by csv reader, as it's not flexible.
+fn parse_strict_record(record: &csv::StringRecord, headers: &csv::StringRecord, amount_index: Option<usize>) -> Result<Transaction, String> {
+    let field = |name: &str| headers.iter().position(|header| header == name).and_then(|index| record.get(index)).unwrap_or_default();
+    if field("client").parse::<ClientID>().is_err() {
+        return Err(format!("client is not an integer ID: \"{}\"", field("client")));
+    }
+    if field("tx").parse::<TransactionID>().is_err() {
+        return Err(format!("tx is not an integer ID: \"{}\"", field("tx")));
+    }
+    let tr = parse_record(record, Some(headers), amount_index)?;
+    let needs_amount = matches!(tr.tr_type, TransactionType::Deposit | TransactionType::Withdrawal);
+    match (needs_amount, tr.amount.is_some()) {
+        (true, false) => Err(format!("{} without amount", tr.tr_type.name())),
+        (false, true) => Err(format!("{} with amount", tr.tr_type.name())),
+        _ => Ok(tr),
+    }
+}
+
+// Canonical column names mapped to header names of a partner's file, e.g. tx = "txn_id"
+pub type ColumnMap = BTreeMap<String, String>;
+
+// Renames mapped header names to canonical ones, mapped columns must be known and present in header
+pub fn apply_column_map(headers: &csv::StringRecord, column_map: &ColumnMap) -> Result<csv::StringRecord, String> {
+    let unknown: Vec<&str> = column_map.keys().map(String::as_str)
+        .filter(|name| !REQUIRED_COLUMNS.contains(name) && !OPTIONAL_COLUMNS.contains(name))
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!("Column map has unknown columns: [{}]", unknown.join(", ")));
+    }
+    let absent: Vec<&str> = column_map.values().map(String::as_str).filter(|name| !headers.iter().any(|header| header == *name)).collect();
+    if !absent.is_empty() {
+        return Err(format!("Column map refers to columns not in header: [{}]", absent.join(", ")));
+    }
+    let renamed: csv::StringRecord = headers.iter()
+        .map(|header| column_map.iter().find(|(_, name)| *name == header).map_or(header, |(canonical, _)| canonical.as_str()))
+        .collect();
+    let missing: Vec<&str> = REQUIRED_COLUMNS.into_iter().filter(|name| !renamed.iter().any(|header| header == *name)).collect();
+    if !missing.is_empty() {
+        return Err(format!("Required columns not in header or column map: [{}]", missing.join(", ")));
+    }
+    Ok(renamed)
+}
+
+// Alternative names of type values, mapped before the strict enum parse
+pub type TypeAliases = BTreeMap<String, TransactionType>;
+
+// Synonyms seen in partner feeds, used with type normalization on top of configured aliases
+pub fn default_type_aliases() -> TypeAliases {
+    TypeAliases::from([
+        ("withdraw".to_string(), TransactionType::Withdrawal),
+        ("charge_back".to_string(), TransactionType::Chargeback),
+        ("charge-back".to_string(), TransactionType::Chargeback),
+    ])
+}
+
+// Canonical text of type value, None if it stays as is. With normalization the value is trimmed
+// and lowercased first, so "Withdraw " matches alias "withdraw" and "DEPOSIT" becomes "deposit".
+fn resolve_type(name: &str, aliases: &TypeAliases, normalize: bool) -> Option<String> {
+    if !normalize {
+        return aliases.get(name).map(|tr_type| tr_type.name().to_string());
+    }
+    let key = name.trim().to_lowercase();
+    match aliases.get(&key) {
+        Some(tr_type) => Some(tr_type.name().to_string()),
+        None => (key != name).then_some(key),
+    }
+}
+
+// Longest CSV record read (all its lines), unless configured otherwise
+pub const DEFAULT_MAX_RECORD_BYTES: usize = 1 << 20;
+
+// Record replaced by RecordGuard, reported as error of the record read from it
+#[derive(Debug)]
+struct BadLine {
+    // First line of the record
+    line: u64,
+    message: String,
+    // Input bytes minus bytes of replacement, so later records get input offsets
+    skipped: i64,
+}
+
+type BadLines = Rc<RefCell<VecDeque<BadLine>>>;
+
+// Feeds CSV reader record by record, so an unterminated quote can't make it swallow the rest of input
+// into one field. A record goes on to the next line while a quoted field is open (memo spanning lines),
+// up to the cap. Records over the cap (dropped as read, never held whole) or with NUL byte are replaced by
+// a row of empty fields keeping the record's line breaks, which CsvSource reports as malformed record.
+// Quote still open at end of input is a stray one: only its line is replaced, the lines after it are read
+// again as records. Broken header row fails the run.
+pub struct RecordGuard<R: Read> {
+    inner: BufReader<R>,
+    max_record_bytes: usize,
+    // Current record or its replacement, and bytes of it already read
+    line: Vec<u8>,
+    consumed: usize,
+    // Lines after a stray quote, read before inner, and bytes of them already read
+    reread: Vec<u8>,
+    reread_pos: usize,
+    line_number: u64,
+    // Fields of header row, for replacement rows
+    fields: usize,
+    bad_lines: BadLines,
+}
+
+impl<R: Read> RecordGuard<R> {
+    fn new(reader: R, bad_lines: BadLines) -> Self {
+        RecordGuard { inner: BufReader::new(reader), max_record_bytes: DEFAULT_MAX_RECORD_BYTES, line: Vec::new(), consumed: 0, reread: Vec::new(), reread_pos: 0, line_number: 0, fields: 0, bad_lines }
+    }
+
+    // Appends one physical line to the record, bytes over the cap are only counted;
+    // returns its length and whether it has an odd number of quotes
+    fn read_line(&mut self, length: usize) -> std::io::Result<(usize, bool)> {
+        let mut read = 0;
+        let mut odd_quotes = false;
+        loop {
+            let rereading = self.reread_pos < self.reread.len();
+            let available = if rereading { &self.reread[self.reread_pos..] } else { self.inner.fill_buf()? };
+            if available.is_empty() {
+                break;
+            }
+            let end = available.iter().position(|&byte| byte == b'\n');
+            let chunk = &available[..end.map_or(available.len(), |end| end + 1)];
+            if length + read + chunk.len() <= self.max_record_bytes {
+                self.line.extend_from_slice(chunk);
+            }
+            // Escaped quote ("") counts twice
+            odd_quotes ^= chunk.iter().filter(|&&byte| byte == b'"').count() % 2 == 1;
+            let chunk_len = chunk.len();
+            read += chunk_len;
+            if rereading {
+                self.reread_pos += chunk_len;
+            } else {
+                self.inner.consume(chunk_len);
+            }
+            if end.is_some() {
+                break;
+            }
+        }
+        Ok((read, odd_quotes))
+    }
+
+    fn next_record(&mut self) -> std::io::Result<()> {
+        self.line.clear();
+        self.consumed = 0;
+        let first_line = self.line_number + 1;
+        let mut first_line_length = 0;
+        let mut length = 0;
+        let mut quoted = false;
+        loop {
+            let (read, odd_quotes) = self.read_line(length)?;
+            if read == 0 {
+                break;
+            }
+            // Passed on as is, csv reader skips it without counting the line
+            if length == 0 && self.line.len() == read && self.line.iter().all(|&byte| byte == b'\r' || byte == b'\n') {
+                return Ok(());
+            }
+            if length == 0 {
+                first_line_length = read;
+            }
+            length += read;
+            quoted ^= odd_quotes;
+            self.line_number += 1;
+            if !quoted || length > self.max_record_bytes {
+                break;
+            }
+        }
+        if length == 0 {
+            return Ok(());
+        }
+        if quoted && length <= self.max_record_bytes && first_line_length < length {
+            let mut rest = self.line.split_off(first_line_length);
+            rest.extend_from_slice(&self.reread[self.reread_pos..]);
+            self.reread = rest;
+            self.reread_pos = 0;
+            length = first_line_length;
+            self.line_number = first_line;
+        }
+
+        let message = if length > self.max_record_bytes {
+            Some(format!("record exceeds {} bytes", self.max_record_bytes))
+        } else if self.line.contains(&0) {
+            Some("NUL byte in record".to_string())
+        } else if quoted {
+            Some("unterminated quote".to_string())
+        } else {
+            None
+        };
+        if first_line == 1 {
+            if let Some(message) = message {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid header row, {}", message)));
+            }
+            let mut quoted = false;
+            self.fields = 1 + self.line.iter().filter(|&&byte| {
+                quoted ^= byte == b'"';
+                byte == b',' && !quoted
+            }).count();
+        } else if let Some(message) = message {
+            self.line = vec![b','; self.fields - 1];
+            // Quoted line breaks in the last field keep line numbers of later records
+            let line_breaks = (self.line_number - first_line) as usize;
+            if line_breaks > 0 {
+                self.line.push(b'"');
+                self.line.resize(self.line.len() + line_breaks, b'\n');
+                self.line.push(b'"');
+            }
+            self.line.push(b'\n');
+            self.bad_lines.borrow_mut().push_back(BadLine { line: first_line, message, skipped: length as i64 - self.line.len() as i64 });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for RecordGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.consumed == self.line.len() {
+            self.next_record()?;
+        }
+        let remaining = &self.line[self.consumed..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.consumed += n;
+        Ok(n)
+    }
+}
+
+pub struct CsvSource<R: Read> {
+    reader: csv::Reader<R>,
+    // Lines replaced by RecordGuard of CsvSource::new, none for caller's reader
+    bad_lines: Option<BadLines>,
+    // Sum of BadLine::skipped of lines read so far
+    skipped_bytes: i64,
+    headers: Option<csv::StringRecord>,
+    // Columns decoded from raw record, by header position
+    known_columns: Vec<bool>,
+    byte_record: csv::ByteRecord,
+    record: csv::StringRecord,
+    count: u64,
+    amount_locale: Option<AmountLocale>,
+    strip_symbols: bool,
+    amount_index: Option<usize>,
+    type_aliases: TypeAliases,
+    normalize_types: bool,
+    type_index: Option<usize>,
+    strict_schema: bool,
+    column_map: ColumnMap,
+}
+
+// Reader settings of CsvSource::new, header row and fields trimmed
+pub fn csv_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.has_headers(true).trim(csv::Trim::All);
+    builder
+}
+
+impl<R: Read> CsvSource<RecordGuard<R>> {
+    pub fn new(reader: R) -> Self {
+        let bad_lines = BadLines::default();
+        let mut source = Self::from_csv_reader(csv_reader_builder().from_reader(RecordGuard::new(reader, bad_lines.clone())));
+        source.bad_lines = Some(bad_lines);
+        source
+    }
+
+    // Longer lines are malformed records, DEFAULT_MAX_RECORD_BYTES by default
+    pub fn with_max_record_bytes(mut self, max_record_bytes: usize) -> Self {
+        self.reader.get_mut().max_record_bytes = max_record_bytes;
+        self
+    }
+}
+
+impl<R: Read> CsvSource<R> {
+    // Reader configured by caller, e.g. with custom quoting or escapes, header row is required.
+    // Records are read as the reader gives them, without line checks of CsvSource::new.
+    pub fn from_csv_reader(reader: csv::Reader<R>) -> Self {
+        CsvSource { reader, bad_lines: None, skipped_bytes: 0, headers: None, known_columns: Vec::new(), byte_record: csv::ByteRecord::new(), record: csv::StringRecord::new(), count: 0, amount_locale: None, strip_symbols: false, amount_index: None, type_aliases: TypeAliases::new(), normalize_types: false, type_index: None, strict_schema: false, column_map: ColumnMap::new() }
+    }
+
+    pub fn with_amount_locale(mut self, locale: Option<AmountLocale>) -> Self {
+        self.amount_locale = locale;
+        self
+    }
+
+    pub fn with_strip_symbols(mut self, strip_symbols: bool) -> Self {
+        self.strip_symbols = strip_symbols;
+        self
+    }
+
+    pub fn with_type_aliases(mut self, aliases: TypeAliases) -> Self {
+        self.type_aliases = aliases;
+        self
+    }
+
+    pub fn with_normalize_types(mut self, normalize_types: bool) -> Self {
+        self.normalize_types = normalize_types;
+        self
+    }
+
+    pub fn with_strict_schema(mut self, strict_schema: bool) -> Self {
+        self.strict_schema = strict_schema;
+        self
+    }
+
+    pub fn with_column_map(mut self, column_map: ColumnMap) -> Self {
+        self.column_map = column_map;
+        self
+    }
+
+    // Header row with column map applied, once the first record was requested
+    pub fn headers(&self) -> Option<&csv::StringRecord> {
+        self.headers.as_ref()
+    }
+
+    // Error of line replaced by RecordGuard, earlier entries are its lines csv reader skipped as blank
+    fn take_bad_line(&mut self, line: u64) -> Option<String> {
+        let mut bad_lines = self.bad_lines.as_ref()?.borrow_mut();
+        while let Some(bad) = bad_lines.front()
+            && bad.line <= line
+        {
+            let bad = bad_lines.pop_front().unwrap();
+            self.skipped_bytes += bad.skipped;
+            if bad.line == line {
+                return Some(bad.message);
+            }
+        }
+        None
+    }
+
+    // Only known columns have to be UTF-8, junk bytes in other ones (e.g. Latin-1 memo) don't reject the row
+    fn decode_record(&mut self) -> Result<(), String> {
+        let mut record = csv::StringRecord::with_capacity(self.byte_record.as_slice().len(), self.byte_record.len());
+        for (index, bytes) in self.byte_record.iter().enumerate() {
+            if !self.known_columns.get(index).copied().unwrap_or(false) {
+                record.push_field("");
+                continue;
+            }
+            match std::str::from_utf8(bytes) {
+                Ok(text) => record.push_field(text),
+                Err(_) => {
+                    let name = self.headers.as_ref().and_then(|headers| headers.get(index)).unwrap_or_default();
+                    return Err(format!("invalid UTF-8 in {} column: {}", name, String::from_utf8_lossy(bytes)));
+                }
+            }
+        }
+        record.set_position(self.byte_record.position().cloned());
+        self.record = record;
+        Ok(())
+    }
+
+    fn normalize_record(&mut self) -> Result<(), String> {
+        let mut rewrites: Vec<(usize, String)> = Vec::new();
+        if let Some(index) = self.amount_index
+            && let Some(amount) = self.record.get(index).filter(|amount| !amount.is_empty()) {
+            let stripped = if self.strip_symbols { strip_currency_symbol(amount) } else { amount };
+            let normalized = match self.amount_locale {
+                Some(locale) => normalize_amount(stripped, locale)?,
+                None => stripped.to_string(),
+            };
+            if normalized != amount {
+                rewrites.push((index, normalized));
+            }
+        }
+        if let Some(index) = self.type_index
+            && let Some(name) = self.record.get(index).and_then(|name| resolve_type(name, &self.type_aliases, self.normalize_types)) {
+            rewrites.push((index, name));
+        }
+        if rewrites.is_empty() {
+            return Ok(());
+        }
+        let record: csv::StringRecord = self.record.iter()
+            .enumerate()
+            .map(|(i, field)| rewrites.iter().find(|(index, _)| *index == i).map_or(field, |(_, text)| text.as_str()))
+            .collect();
+        let position = self.record.position().cloned();
+        self.record = record;
+        self.record.set_position(position);
+        Ok(())
+    }
+}
+
+impl<R: Read> TransactionSource for CsvSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<(Transaction, SourceLocation), SourceError>> {
+        if self.headers.is_none() {
+            match self.reader.byte_headers() {
+                Ok(headers) => {
+                    let headers = csv::StringRecord::from_byte_record_lossy(headers.clone());
+                    // Columns are resolved by name, so any order works
+                    let headers = if self.column_map.is_empty() {
+                        headers
+                    } else {
+                        match apply_column_map(&headers, &self.column_map) {
+                            Ok(headers) => headers,
+                            Err(message) => return Some(Err(SourceError::Schema(message))),
+                        }
+                    };
+                    if self.strict_schema
+                        && let Err(message) = check_schema(&headers)
+                    {
+                        return Some(Err(SourceError::Schema(message)));
+                    }
+                    self.amount_index = headers.iter().position(|name| name == "amount");
+                    self.type_index = headers.iter().position(|name| name == "type");
+                    self.known_columns = headers.iter().map(|name| REQUIRED_COLUMNS.contains(&name) || OPTIONAL_COLUMNS.contains(&name)).collect();
+                    self.headers = Some(headers);
+                }
+                Err(err) => return Some(Err(csv_error(err, SourceLocation::default()))),
+            }
+        }
+
+        self.count += 1;
+        let mut location = SourceLocation { record: self.count, line: self.reader.position().line(), end_byte: 0 };
+        let result = self.reader.read_byte_record(&mut self.byte_record);
+        let end_byte = self.reader.position().byte();
+        location.end_byte = end_byte.saturating_add_signed(self.skipped_bytes);
+        match result {
+            Ok(false) => None,
+            Ok(true) => {
+                location.line = self.byte_record.position().map_or(location.line, |pos| pos.line());
+                if let Some(message) = self.take_bad_line(location.line) {
+                    location.end_byte = end_byte.saturating_add_signed(self.skipped_bytes);
+                    return Some(Err(SourceError::Record { location, message }));
+                }
+                if let Err(message) = self.decode_record() {
+                    return Some(Err(SourceError::Record { location, message }));
+                }
+                if let Err(message) = self.normalize_record() {
+                    return Some(Err(SourceError::Record { location, message }));
+                }
+                if let Some(name) = unknown_type(&self.record, self.type_index) {
+                    return Some(Err(SourceError::UnknownType { location, name }));
+                }
+                if self.strict_schema
+                    && let Some(headers) = &self.headers
+                {
+                    return match parse_strict_record(&self.record, headers, self.amount_index) {
+                        Ok(tr) => Some(Ok((tr, location))),
+                        Err(message) => Some(Err(SourceError::Schema(format!("Invalid row, {}: {}", location, message)))),
+                    };
+                }
+                match parse_record(&self.record, self.headers.as_ref(), self.amount_index) {
+                    Ok(tr) => Some(Ok((tr, location))),
+                    Err(message) => Some(Err(SourceError::Record { location, message })),
+                }
+            }
+            Err(err) => {
+                if self.strict_schema
+                    && let csv::ErrorKind::UnequalLengths { pos, expected_len, len } = err.kind()
+                {
+                    location.line = pos.as_ref().map_or(location.line, |pos| pos.line());
+                    return Some(Err(SourceError::Schema(format!("Invalid row, {}: {} fields, header has {}", location, len, expected_len))));
+                }
+                Some(Err(csv_error(err, location)))
+            }
+        }
+    }
+
+    fn raw_record(&self) -> Option<String> {
+        let fields: Vec<_> = self.byte_record.iter().map(String::from_utf8_lossy).collect();
+        Some(fields.join(","))
+    }
+
+    fn has_timestamp_column(&self) -> Option<bool> {
+        Some(self.headers.as_ref()?.iter().any(|header| header == "timestamp"))
+    }
+}
+
+// Non-empty type value not naming a transaction type (after aliases), shared by CSV and spreadsheet input
+pub(crate) fn unknown_type(record: &csv::StringRecord, type_index: Option<usize>) -> Option<String> {
+    let name = record.get(type_index?)?;
+    (!name.is_empty() && name.parse::<TransactionType>().is_err()).then(|| name.to_string())
+}
+
+// Transaction from text fields named by headers, shared by CSV and spreadsheet input
+pub(crate) fn parse_record(record: &csv::StringRecord, headers: Option<&csv::StringRecord>, amount_index: Option<usize>) -> Result<Transaction, String> {
+    let mut tr: Transaction = match record.deserialize(headers) {
+        Ok(tr) => tr,
+        // Amount beyond f64 range or precision fails csv's numeric inference, it's parsed from text below anyway
+        Err(err) => match amount_index {
+            Some(index) if record.get(index).is_some_and(|text| text.parse::<Money>().is_ok()) => {
+                let blanked: csv::StringRecord = record.iter().enumerate().map(|(i, field)| if i == index { "" } else { field }).collect();
+                blanked.deserialize(headers).map_err(|_| err.to_string())?
+            }
+            _ => return Err(err.to_string()),
+        },
+    };
+    // csv infers numeric fields as f64, amount is parsed again from text to stay exact
+    if let Some(index) = amount_index
+        && let Some(text) = record.get(index)
+    {
+        if text.is_empty() {
+            tr.empty_amount = true;
+        } else {
+            tr.amount = Some(text.parse()?);
+            tr.integer_amount = !text.contains('.');
+        }
+    }
+    Ok(tr)
+}
+
+fn csv_error(err: csv::Error, location: SourceLocation) -> SourceError {
+    if err.is_io_error() {
+        match err.into_kind() {
+            csv::ErrorKind::Io(err) => SourceError::Io(err),
+            kind => SourceError::Record { location, message: format!("{:?}", kind) },
+        }
+    } else {
+        SourceError::Record { location, message: err.to_string() }
+    }
+}
+
+// One JSON object per line, with the same fields as CSV columns
+pub struct NdjsonSource<R: Read> {
+    reader: BufReader<R>,
+    line: String,
+    count: u64,
+    line_number: u64,
+    bytes: u64,
+    type_aliases: TypeAliases,
+    normalize_types: bool,
+}
+
+impl<R: Read> NdjsonSource<R> {
+    pub fn new(reader: R) -> Self {
+        NdjsonSource { reader: BufReader::new(reader), line: String::new(), count: 0, line_number: 0, bytes: 0, type_aliases: TypeAliases::new(), normalize_types: false }
+    }
+
+    pub fn with_type_aliases(mut self, aliases: TypeAliases) -> Self {
+        self.type_aliases = aliases;
+        self
+    }
+
+    pub fn with_normalize_types(mut self, normalize_types: bool) -> Self {
+        self.normalize_types = normalize_types;
+        self
+    }
+
+    // Looked at only once the line failed to parse, so valid lines are parsed once
+    fn unknown_type(&self) -> Option<String> {
+        let value: serde_json::Value = serde_json::from_str(&self.line).ok()?;
+        let name = value.get("type")?.as_str()?;
+        let name = resolve_type(name, &self.type_aliases, self.normalize_types).unwrap_or_else(|| name.to_string());
+        (!name.is_empty() && name.parse::<TransactionType>().is_err()).then_some(name)
+    }
+
+    fn parse_line(&self) -> Result<Transaction, serde_json::Error> {
+        if self.type_aliases.is_empty() && !self.normalize_types {
+            return serde_json::from_str(&self.line);
+        }
+        let mut value: serde_json::Value = serde_json::from_str(&self.line)?;
+        if let Some(field) = value.get_mut("type")
+            && let Some(name) = field.as_str().and_then(|name| resolve_type(name, &self.type_aliases, self.normalize_types)) {
+            *field = serde_json::Value::from(name);
+        }
+        serde_json::from_value(value)
+    }
+}
+
+impl<R: Read> TransactionSource for NdjsonSource<R> {
+    fn next_transaction(&mut self) -> Option<Result<(Transaction, SourceLocation), SourceError>> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(read) => self.bytes += read as u64,
+                Err(err) => return Some(Err(SourceError::Io(err))),
+            }
+            self.line_number += 1;
+            if self.line.trim().is_empty() {
+                continue;
+            }
+
+            self.count += 1;
+            let location = SourceLocation { record: self.count, line: self.line_number, end_byte: self.bytes };
+            return match self.parse_line() {
+                Ok(tr) => Some(Ok((tr, location))),
+                Err(err) => match self.unknown_type() {
+                    Some(name) => Some(Err(SourceError::UnknownType { location, name })),
+                    None => Some(Err(SourceError::Record { location, message: err.to_string() })),
+                },
+            };
+        }
+    }
+    fn raw_record(&self) -> Option<String> {
+        Some(self.line.trim_end().to_string())
+    }
+}
+
+// Skips records already consumed by an earlier run, locations still count them
+pub struct SkipRecords<S: TransactionSource> {
+    inner: S,
+    remaining: u64,
+}
+
+impl<S: TransactionSource> SkipRecords<S> {
+    pub fn new(inner: S, records: u64) -> Self {
+        SkipRecords { inner, remaining: records }
+    }
+
+    // Records still to skip, nonzero once input ended before all were skipped
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
+
+impl<S: TransactionSource> TransactionSource for SkipRecords<S> {
+    fn next_transaction(&mut self) -> Option<Result<(Transaction, SourceLocation), SourceError>> {
+        while self.remaining > 0 {
+            match self.inner.next_transaction()? {
+                Err(err @ (SourceError::Io(_) | SourceError::Schema(_))) => return Some(Err(err)),
+                _ => self.remaining -= 1,
+            }
+        }
+        self.inner.next_transaction()
+    }
+
+    fn raw_record(&self) -> Option<String> {
+        self.inner.raw_record()
+    }
+
+    fn has_timestamp_column(&self) -> Option<bool> {
+        self.inner.has_timestamp_column()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Model, OutputOptions, ProcessingReport};
+    use crate::money::Money;
+
+    fn money(text: &str) -> Money {
+        text.parse().expect("Invalid amount")
+    }
+
+    #[test]
+    fn test_csv_and_ndjson_sources_match() {
+        let csv_text = std::fs::read_to_string("cases/05-transactions-chargeback.csv").expect("Error reading input")
+            + "\ndeposit, 3, x, 1.0\ndeposit, 3, 6, 4.0\n";
+        let ndjson_text = r#"{"type":"deposit","client":1,"tx":1,"amount":1.0}
+{"type":"deposit","client":2,"tx":2,"amount":2.0}
+{"type":"deposit","client":1,"tx":3,"amount":2.0}
+{"type":"withdrawal","client":1,"tx":4,"amount":1.5}
+{"type":"dispute","client":1,"tx":1}
+{"type":"chargeback","client":1,"tx":1,"amount":null}
+
+{"type":"withdrawal","client":2,"tx":5,"amount":3.0}
+{"type":"deposit","client":3,"tx":"x","amount":1.0}
+{"type":"deposit","client":3,"tx":6,"amount":4.0}
+"#;
+
+        let mut csv_model = Model::default();
+        let csv_report = csv_model.process_source(CsvSource::new(csv_text.as_bytes())).expect("Processing failed");
+        let mut ndjson_model = Model::default();
+        let ndjson_report = ndjson_model.process_source(NdjsonSource::new(ndjson_text.as_bytes())).expect("Processing failed");
+
+        // Byte offsets are format specific
+        assert_eq!(ProcessingReport { end_byte: 0, ..csv_report.clone() }, ProcessingReport { end_byte: 0, ..ndjson_report });
+        assert_eq!((csv_report.records, csv_report.parse_errors), (9, 1));
+
+        let mut csv_out = Vec::new();
+        csv_model.write_accounts(&mut csv_out, &OutputOptions::default()).expect("Error writing accounts");
+        let mut ndjson_out = Vec::new();
+        ndjson_model.write_accounts(&mut ndjson_out, &OutputOptions::default()).expect("Error writing accounts");
+        assert_eq!(String::from_utf8(csv_out), String::from_utf8(ndjson_out));
+    }
+
+    #[test]
+    fn test_normalize_amount() {
+        assert_eq!(normalize_amount("1,234.56", AmountLocale::Dot), Ok("1234.56".to_string()));
+        assert_eq!(normalize_amount("1 234,56", AmountLocale::Comma), Ok("1234.56".to_string()));
+        assert_eq!(normalize_amount("1.234,56", AmountLocale::Comma), Ok("1234.56".to_string()));
+        assert_eq!(normalize_amount("1,234", AmountLocale::Dot), Ok("1234".to_string()));
+        assert_eq!(normalize_amount("1,234", AmountLocale::Comma), Ok("1.234".to_string()));
+        assert_eq!(normalize_amount("1.234,56", AmountLocale::Auto), Ok("1234.56".to_string()));
+        assert_eq!(normalize_amount("1,234,567", AmountLocale::Auto), Ok("1234567".to_string()));
+        assert_eq!(normalize_amount("2,5", AmountLocale::Auto), Ok("2.5".to_string()));
+        assert_eq!(normalize_amount("1,234", AmountLocale::Auto), Err("ambiguous amount 1,234: 1234 or 1.234".to_string()));
+    }
+
+    #[test]
+    fn test_strip_symbols() {
+        let process = |strip_symbols: bool| {
+            let mut model = Model::default();
+            let input = "type,client,tx,amount\ndeposit,1,1,$100.00\ndeposit,1,2,£1.5\n";
+            let report = model.process_source(CsvSource::new(input.as_bytes()).with_strip_symbols(strip_symbols)).expect("Processing failed");
+            (report.parse_errors, model.client(1).map(|client| client.total.clone()))
+        };
+        assert_eq!(process(true), (0, Some(money("101.5"))));
+        assert_eq!(process(false), (2, None));
+        assert_eq!(strip_currency_symbol("€ 7"), "7");
+    }
+
+    #[test]
+    fn test_strict_schema() {
+        let process = |input: &str, strict_schema: bool| {
+            let file = std::fs::File::open(input).expect("Error opening input");
+            let mut model = Model::default();
+            let result = model.process_source(CsvSource::new(file).with_strict_schema(strict_schema));
+            (result.map(|report| report.parse_errors).map_err(|err| err.to_string()), model.client(1).is_some())
+        };
+        assert_eq!(process("cases/13-transactions-missing-column.csv", true),
+            (Err("Invalid CSV header, missing columns: [tx], unexpected columns: []".to_string()), false));
+        assert_eq!(process("cases/13-transactions-renamed-column.csv", true),
+            (Err("Invalid CSV header, missing columns: [tx], unexpected columns: [transaction_id]".to_string()), false));
+        assert_eq!(process("cases/13-transactions-extra-column.csv", true),
+            (Err("Invalid CSV header, missing columns: [], unexpected columns: [memo]".to_string()), false));
+        assert_eq!(process("cases/12-transactions-batches.csv", true).0, Ok(0));
+
+        // Lenient mode rejects rows without tx, and ignores extra column
+        assert_eq!(process("cases/13-transactions-renamed-column.csv", false), (Ok(2), false));
+        assert_eq!(process("cases/13-transactions-extra-column.csv", false), (Ok(0), true));
+    }
+
+    #[test]
+    fn test_strict_schema_rows() {
+        let process = |rows: &str, strict_schema: bool| {
+            let input = format!("type, client, tx, amount\ndeposit, 1, 1, 1.0\n{}", rows);
+            let mut model = Model::default();
+            let result = model.process_source(CsvSource::new(input.as_bytes()).with_strict_schema(strict_schema));
+            result.map(|report| (report.parse_errors, report.rejected.values().sum::<u64>())).map_err(|err| err.to_string())
+        };
+        for (rows, error, lenient) in [
+            ("deposit, 1, 2\n", "Invalid row, record 2 (line 3): 3 fields, header has 4", (1, 0)),
+            ("deposit, 1, 2, 1.0, memo\n", "Invalid row, record 2 (line 3): 5 fields, header has 4", (1, 0)),
+            ("deposit, 1, , 1.0\n", "Invalid row, record 2 (line 3): tx is not an integer ID: \"\"", (1, 0)),
+            ("deposit, one, 2, 1.0\n", "Invalid row, record 2 (line 3): client is not an integer ID: \"one\"", (1, 0)),
+            ("withdrawal, 1, 2,\n", "Invalid row, record 2 (line 3): withdrawal without amount", (0, 1)),
+            ("dispute, 1, 1, 1.0\n", "Invalid row, record 2 (line 3): dispute with amount", (0, 0)),
+        ] {
+            assert_eq!(process(rows, true), Err(error.to_string()), "{}", rows);
+            assert_eq!(process(rows, false), Ok(lenient), "{}", rows);
+        }
+        assert_eq!(process("withdrawal, 1, 2, 0.5\ndispute, 1, 1,\n", true), Ok((0, 0)));
+    }
+
+    #[test]
+    fn test_column_map() {
+        let accounts = |input: &str, column_map: ColumnMap| {
+            let file = std::fs::File::open(input).expect("Error opening input");
+            let mut model = Model::default();
+            let report = model.process_source(CsvSource::new(file).with_column_map(column_map).with_strict_schema(true))
+                .map_err(|err| err.to_string())?;
+            assert_eq!(report.parse_errors, 0);
+            let mut output = Vec::new();
+            model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+            Ok::<String, String>(String::from_utf8(output).unwrap())
+        };
+        let map = |pairs: &[(&str, &str)]| pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect::<ColumnMap>();
+        let column_map = map(&[("tx", "txn_id"), ("client", "client_id"), ("amount", "value")]);
+        assert_eq!(accounts("cases/14-transactions-column-map.csv", column_map.clone()),
+            accounts("cases/05-transactions-chargeback.csv", ColumnMap::new()));
+
+        assert_eq!(accounts("cases/14-transactions-column-map.csv", map(&[("tx", "txn_id"), ("client", "client_id")])),
+            Err("Required columns not in header or column map: [amount]".to_string()));
+        assert_eq!(accounts("cases/05-transactions-chargeback.csv", column_map),
+            Err("Column map refers to columns not in header: [value, client_id, txn_id]".to_string()));
+        assert_eq!(accounts("cases/14-transactions-column-map.csv", map(&[("memo", "value")])),
+            Err("Column map has unknown columns: [memo]".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let file = std::fs::File::open("cases/15-transactions-latin1.csv").expect("Error opening input");
+        let mut source = CsvSource::new(file);
+        let mut next = || source.next_transaction().expect("Record missing").map(|(tr, _)| (tr.client, tr.tx)).map_err(|err| err.to_string());
+        // Latin-1 in ignored memo column is fine, in amount column rejects the row only
+        assert_eq!(next(), Ok((1, 1)));
+        assert_eq!(next(), Err("record 2 (line 3): invalid UTF-8 in amount column: 2.\u{fffd}0".to_string()));
+        assert_eq!(next(), Ok((2, 3)));
+        assert!(source.next_transaction().is_none());
+    }
+
+    #[test]
+    fn test_malformed_lines() {
+        for (input, message) in [
+            ("cases/17-transactions-unterminated-quote.csv", "unterminated quote"),
+            ("cases/17-transactions-long-line.csv", "record exceeds 1024 bytes"),
+            ("cases/17-transactions-nul.csv", "NUL byte in record"),
+        ] {
+            let file = std::fs::File::open(input).expect("Error opening input");
+            let size = file.metadata().expect("Error reading metadata").len();
+            let mut source = CsvSource::new(file).with_max_record_bytes(1024);
+            let mut next = || source.next_transaction().expect("Record missing").map(|(tr, location)| (tr.tx, location.end_byte)).map_err(|err| err.to_string());
+            assert_eq!(next().map(|(tx, _)| tx), Ok(1), "{}", input);
+            // Only the broken line is lost, rows after it are read as usual
+            assert_eq!(next(), Err(format!("record 2 (line 3): {}", message)), "{}", input);
+            assert_eq!(next().map(|(tx, _)| tx), Ok(3), "{}", input);
+            // Offsets are of input bytes, not of the replacement row
+            assert_eq!(next(), Ok((4, size)), "{}", input);
+            assert!(source.next_transaction().is_none());
+        }
+
+        // Megabytes after a stray quote are dropped as read, not collected into a field
+        let input = "type,client,tx,amount\ndeposit,1,1,\"1.0".as_bytes()
+            .chain(std::io::repeat(b'9').take(32 << 20))
+            .chain("\ndeposit,1,2,2.0\n".as_bytes());
+        let mut source = CsvSource::new(input).with_max_record_bytes(4096);
+        let errors: Vec<String> = std::iter::from_fn(|| source.next_transaction())
+            .filter_map(|result| result.err().map(|err| err.to_string()))
+            .collect();
+        assert_eq!(errors, vec!["record 1 (line 2): record exceeds 4096 bytes"]);
+        assert!(source.reader.get_ref().line.capacity() <= 8192);
+        assert!(source.byte_record.as_slice().len() < 4096);
+
+        let mut source = CsvSource::new("type,\"client\ntx,amount\n".as_bytes());
+        assert!(matches!(source.next_transaction(), Some(Err(SourceError::Io(_)))));
+    }
+
+    #[test]
+    fn test_multiline_quoted_field() {
+        let file = std::fs::File::open("cases/22-transactions-multiline-memo.csv").expect("Error opening input");
+        let size = file.metadata().expect("Error reading metadata").len();
+        let mut source = CsvSource::new(file).with_max_record_bytes(1024);
+        let mut next = || source.next_transaction().expect("Record missing").map(|(tr, location)| (tr.tx, location.line, location.end_byte)).map_err(|err| err.to_string());
+        assert_eq!(next().map(|(tx, line, _)| (tx, line)), Ok((1, 2)));
+        assert_eq!(next().map(|(tx, line, _)| (tx, line)), Ok((2, 4)));
+        assert_eq!(next(), Ok((3, 6, size)));
+        assert!(source.next_transaction().is_none());
+
+        let mut model = Model::default();
+        let report = model.process_reader(std::fs::File::open("cases/22-transactions-multiline-memo.csv").unwrap()).expect("Processing failed");
+        assert_eq!((report.parse_errors, report.applied), (0, 3));
+        assert_eq!(model.client(1).map(|client| client.total.clone()), Some("11.0".parse().unwrap()));
+
+        // Record over the cap spanning lines is lost whole, line numbers after it stay right
+        let input = format!("type,client,tx,amount,memo\ndeposit,1,1,1.0,\"{}\n{}\"\ndeposit,1,2,2.0,\n", "x".repeat(600), "y".repeat(600));
+        let mut source = CsvSource::new(input.as_bytes()).with_max_record_bytes(1024);
+        let mut next = || source.next_transaction().expect("Record missing").map(|(tr, location)| (tr.tx, location.line)).map_err(|err| err.to_string());
+        assert_eq!(next(), Err("record 1 (line 2): record exceeds 1024 bytes".to_string()));
+        assert_eq!(next(), Ok((2, 4)));
+    }
+
+    #[test]
+    fn test_amount_locale_fixtures() {
+        let process = |input: &str, locale: AmountLocale| {
+            let file = std::fs::File::open(input).expect("Error opening input");
+            let mut model = Model::default();
+            let report = model.process_source(CsvSource::new(file).with_amount_locale(Some(locale))).expect("Processing failed");
+            (model, report)
+        };
+        for (input, locale) in [
+            ("cases/10-transactions-locale-dot.csv", AmountLocale::Dot),
+            ("cases/10-transactions-locale-comma.csv", AmountLocale::Comma),
+            ("cases/10-transactions-locale-dot.csv", AmountLocale::Auto),
+            ("cases/10-transactions-locale-comma.csv", AmountLocale::Auto),
+        ] {
+            let (model, report) = process(input, locale);
+            assert_eq!(report.parse_errors, 0, "{} {:?}", input, locale);
+            let client = model.client(1).expect("Client missing");
+            assert_eq!(client.total, money("1233.06"), "{} {:?}", input, locale);
+        }
+
+        // "1,234" is rejected in auto mode, but is fine with explicit locale
+        let (model, report) = process("cases/10-transactions-locale-ambiguous.csv", AmountLocale::Auto);
+        assert_eq!(report.parse_errors, 1);
+        assert_eq!(model.client(1).expect("Client missing").total, money("1"));
+        let (model, _) = process("cases/10-transactions-locale-ambiguous.csv", AmountLocale::Dot);
+        assert_eq!(model.client(1).expect("Client missing").total, money("1235"));
+    }
+
+    #[test]
+    fn test_csv_source_location() {
+        let mut source = CsvSource::new("type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, x, 1.0\n".as_bytes());
+        let (_, location) = source.next_transaction().unwrap().expect("Valid record expected");
+        assert_eq!(location, SourceLocation { record: 1, line: 2, end_byte: 44 });
+        match source.next_transaction() {
+            Some(Err(SourceError::Record { location, .. })) => assert_eq!(location, SourceLocation { record: 2, line: 3, end_byte: 63 }),
+            other => panic!("Record error expected, got {:?}", other),
+        }
+        assert!(source.next_transaction().is_none());
+    }
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::BufReader;
+use calamine::{Data, Range, Reader, Xlsx};
+use crate::Transaction;
+use crate::source::{parse_record, unknown_type, SourceError, SourceLocation, TransactionSource, REQUIRED_COLUMNS};
+
+// First sheet of xlsx workbook, or the named one, with the same columns as CSV in its header row
+pub struct XlsxSource {
+    range: Range<Data>,
+    headers: Option<csv::StringRecord>,
+    amount_index: Option<usize>,
+    type_index: Option<usize>,
+    // Rows of range read so far, header included
+    next_row: usize,
+}
+
+impl XlsxSource {
+    pub fn open(path: &str, sheet: Option<&str>) -> Result<Self, SourceError> {
+        let mut workbook: Xlsx<BufReader<File>> = calamine::open_workbook(path).map_err(workbook_error)?;
+        let range = match sheet {
+            Some(name) => workbook.worksheet_range(name).map_err(workbook_error)?,
+            None => workbook.worksheet_range_at(0)
+                .ok_or_else(|| SourceError::Schema(format!("Workbook {} has no sheets", path)))?
+                .map_err(workbook_error)?,
+        };
+        Ok(XlsxSource { range, headers: None, amount_index: None, type_index: None, next_row: 0 })
+    }
+
+    // Cells read by position, rows() would walk from the top on every call
+    fn row(&self, index: usize) -> Option<csv::StringRecord> {
+        if index >= self.range.height() {
+            return None;
+        }
+        Some((0..self.range.width()).map(|col| self.range.get((index, col)).map_or_else(String::new, cell_text)).collect())
+    }
+
+    // 1-based sheet row, for messages matching what spreadsheet shows
+    fn sheet_row(&self, index: usize) -> u64 {
+        self.range.start().map_or(0, |(row, _)| row as u64) + index as u64 + 1
+    }
+}
+
+fn workbook_error(err: calamine::XlsxError) -> SourceError {
+    match err {
+        calamine::XlsxError::Io(err) => SourceError::Io(err),
+        err => SourceError::Schema(format!("Invalid workbook: {}", err)),
+    }
+}
+
+// Numeric cells are written in shortest form parsing back to the same float, without exponent,
+// so 0.1 stays "0.1" rather than "0.1000000000000000055511151231257827"
+fn cell_text(cell: &Data) -> String {
+    cell.to_string().trim().to_string()
+}
+
+impl TransactionSource for XlsxSource {
+    fn next_transaction(&mut self) -> Option<Result<(Transaction, SourceLocation), SourceError>> {
+        if self.headers.is_none() {
+            let headers = self.row(0)?;
+            let missing: Vec<&str> = REQUIRED_COLUMNS.into_iter().filter(|name| !headers.iter().any(|header| header == *name)).collect();
+            if !missing.is_empty() {
+                return Some(Err(SourceError::Schema(format!("Sheet header is missing columns: [{}]", missing.join(", ")))));
+            }
+            self.amount_index = headers.iter().position(|name| name == "amount");
+            self.type_index = headers.iter().position(|name| name == "type");
+            self.headers = Some(headers);
+            self.next_row = 1;
+        }
+
+        let record = self.row(self.next_row)?;
+        let location = SourceLocation { record: self.next_row as u64, line: self.sheet_row(self.next_row), end_byte: 0 };
+        self.next_row += 1;
+        if let Some(name) = unknown_type(&record, self.type_index) {
+            return Some(Err(SourceError::UnknownType { location, name }));
+        }
+        match parse_record(&record, self.headers.as_ref(), self.amount_index) {
+            Ok(tr) => Some(Ok((tr, location))),
+            Err(message) => Some(Err(SourceError::Record { location, message })),
+        }
+    }
+    fn raw_record(&self) -> Option<String> {
+        let record = self.row(self.next_row.checked_sub(1)?)?;
+        Some(record.iter().collect::<Vec<_>>().join(","))
+    }
+
+    fn has_timestamp_column(&self) -> Option<bool> {
+        Some(self.headers.as_ref()?.iter().any(|header| header == "timestamp"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Model, OutputOptions};
+
+    #[test]
+    fn test_xlsx_matches_csv() {
+        let accounts = |model: &Model| {
+            let mut output = Vec::new();
+            model.write_accounts(&mut output, &OutputOptions::default()).expect("Error writing accounts");
+            String::from_utf8(output).unwrap()
+        };
+        let mut csv_model = Model::default();
+        csv_model.process_transactions("cases/05-transactions-chargeback.csv").expect("Processing failed");
+
+        // Second sheet has the same rows with amounts as numbers, first one as text
+        for sheet in [None, Some("Numbers")] {
+            let mut model = Model::default();
+            let source = XlsxSource::open("cases/16-transactions-chargeback.xlsx", sheet).expect("Error opening workbook");
+            let report = model.process_source(source).expect("Processing failed");
+            assert_eq!((report.records, report.parse_errors), (8, 1), "{:?}", sheet);
+            assert_eq!(accounts(&model), accounts(&csv_model), "{:?}", sheet);
+        }
+
+        let mut source = XlsxSource::open("cases/16-transactions-chargeback.xlsx", None).expect("Error opening workbook");
+        let errors: Vec<String> = std::iter::from_fn(|| source.next_transaction())
+            .filter_map(|result| result.err().map(|err| err.to_string()))
+            .collect();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("record 8 (line 9): "), "{}", errors[0]);
+        assert!(XlsxSource::open("cases/16-transactions-chargeback.xlsx", Some("Missing")).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+const INPUT: &str = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+
+fn command(out: &std::path::Path, extra: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_process-transactions"));
+    command.arg("--out").arg(out).args(extra).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    command
+}
+
+#[test]
+fn test_second_instance_fails_on_lock() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let out = dir.path().join("accounts.csv");
+
+    // First instance holds the lock while waiting for stdin
+    let mut first = command(&out, &[]).spawn().expect("Error starting first instance");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let second = command(&out, &[]).output().expect("Error running second instance");
+    assert!(!second.status.success());
+    assert!(String::from_utf8_lossy(&second.stderr).contains("locked by another instance"));
+
+    first.stdin.take().unwrap().write_all(INPUT.as_bytes()).expect("Error writing input");
+    assert!(first.wait().expect("Error waiting first instance").success());
+    assert!(std::fs::read_to_string(&out).expect("Error reading output").contains("1,1.0000"));
+}
+
+#[test]
+fn test_second_instance_waits_for_lock() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let out = dir.path().join("accounts.csv");
+
+    let mut first = command(&out, &[]).spawn().expect("Error starting first instance");
+    std::thread::sleep(Duration::from_millis(300));
+
+    let mut second = command(&out, &["--wait-for-lock", "10"]).spawn().expect("Error starting second instance");
+    let mut second_stdin = second.stdin.take().unwrap();
+    second_stdin.write_all(b"type, client, tx, amount\ndeposit, 2, 1, 2.0\n").expect("Error writing input");
+    drop(second_stdin);
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(second.try_wait().expect("Error checking second instance").is_none(), "Second instance didn't wait");
+
+    first.stdin.take().unwrap().write_all(INPUT.as_bytes()).expect("Error writing input");
+    assert!(first.wait().expect("Error waiting first instance").success());
+    assert!(second.wait().expect("Error waiting second instance").success());
+    assert!(std::fs::read_to_string(&out).expect("Error reading output").contains("2,2.0000"));
+}
+
+#[test]
+fn test_quiet_suppresses_info() {
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\nwithdrawal, 1, 2, 5.0\ndispute, 1, 7,\n";
+    let run = |extra: &[&str]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+            .args(extra)
+            .env("RUST_LOG", "info")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Error starting instance");
+        child.stdin.take().unwrap().write_all(input.as_bytes()).expect("Error writing input");
+        let output = child.wait_with_output().expect("Error waiting instance");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stderr).to_string()
+    };
+
+    let stderr = run(&[]);
+    assert!(stderr.contains("Insufficient funds"));
+    assert!(stderr.contains("unknown transaction"));
+
+    let stderr = run(&["--quiet"]);
+    assert!(!stderr.contains("Insufficient funds"));
+    assert!(stderr.contains("unknown transaction"));
+}
+
+#[test]
+fn test_log_file_rotation() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let log = dir.path().join("run.log");
+    let mut input = String::from("type, client, tx, amount\n");
+    for tx in 1..=200 {
+        input.push_str(&format!("withdrawal, 1, {}, 1.0\n", tx));
+    }
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg("--log-file").arg(&log)
+        .args(["--log-max-size", "4000", "--log-max-files", "2"])
+        .env("RUST_LOG", "info")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Error starting instance");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).expect("Error writing input");
+    let output = child.wait_with_output().expect("Error waiting instance");
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains("Insufficient funds"));
+
+    for name in ["run.log", "run.log.1", "run.log.2"] {
+        let text = std::fs::read_to_string(dir.path().join(name)).expect("Error reading log");
+        assert!(text.len() <= 4000, "{} too big", name);
+        for line in text.lines() {
+            assert!(line.starts_with('[') && line.contains("Insufficient funds"), "Unexpected line in {}: {}", name, line);
+        }
+    }
+    assert!(!dir.path().join("run.log.3").exists());
+}
+
+#[test]
+fn test_batch_dir() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let batch_dir = dir.path().join("in");
+    let out_dir = dir.path().join("out");
+    std::fs::create_dir(&batch_dir).expect("Error creating batch dir");
+    std::fs::copy("cases/01-transactions-base.csv", batch_dir.join("merchant-a.csv")).expect("Error copying input");
+    std::fs::copy("cases/05-transactions-chargeback.csv", batch_dir.join("merchant-b.csv")).expect("Error copying input");
+    // Gzip magic followed by junk, fails reading
+    std::fs::write(batch_dir.join("merchant-c.csv"), b"\x1f\x8b\x08\x00junk").expect("Error writing input");
+    std::fs::write(batch_dir.join("notes.txt"), b"not an input").expect("Error writing notes");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg("--batch-dir").arg(&batch_dir)
+        .arg("--out-dir").arg(&out_dir)
+        .args(["--jobs", "2"])
+        .output()
+        .expect("Error running instance");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("merchant-c.csv"), "{}", stderr);
+    assert!(stderr.contains("Batch: 2 files processed, 1 failed"), "{}", stderr);
+
+    let read = |name: &str| std::fs::read_to_string(out_dir.join(name)).expect("Error reading output");
+    assert_eq!(read("merchant-a.accounts.csv"), "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n");
+    assert_eq!(read("merchant-b.accounts.csv"), "client,available,held,total,locked\n1,0.5000,0.0000,0.5000,true\n2,2.0000,0.0000,2.0000,false\n");
+    assert!(!out_dir.join("merchant-c.accounts.csv").exists());
+    assert!(!out_dir.join("notes.accounts.csv").exists());
+}
+
+#[test]
+fn test_input_glob() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let write = |name: &str, text: &str| std::fs::write(dir.path().join(name), text).expect("Error writing input");
+    // Withdrawal only succeeds when applied after the deposit of the earlier file
+    write("2024-02.csv", "type, client, tx, amount\nwithdrawal, 1, 2, 3.0\n");
+    write("2024-01.csv", "type, client, tx, amount\ndeposit, 1, 1, 5.0\n");
+    write("2023-12.csv", "type, client, tx, amount\ndeposit, 2, 3, 1.0\n");
+    write("2024-notes.txt", "not an input\n");
+    let run = |pattern: &str, extra: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+            .arg("--input").arg(dir.path().join(pattern))
+            .args(extra)
+            .output()
+            .expect("Error running instance")
+    };
+
+    let output = run("2024-*.csv", &[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n");
+
+    let output = run("2025-*.csv", &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("matches no files"));
+
+    let output = run("2025-*.csv", &["--allow-empty-glob"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_gen_processes_cleanly() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let sample = dir.path().join("sample.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .args(["gen", "--clients", "10", "--txns", "500", "--dispute-ratio", "0.1", "--out"])
+        .arg(&sample)
+        .output()
+        .expect("Error running gen");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let text = std::fs::read_to_string(&sample).expect("Error reading sample");
+    assert_eq!(text.lines().count(), 501);
+    assert!(text.contains("\ndispute,"));
+
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg(&sample)
+        .args(["--summary", "--audit-pass"])
+        .env("RUST_LOG", "info")
+        .output()
+        .expect("Error running instance");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Records: 500 (0 malformed)"), "{}", stderr);
+    // Nothing rejected
+    assert!(!stderr.contains("INFO") && !stderr.contains("WARN"), "{}", stderr);
+}
+
+#[test]
+fn test_per_client_statements() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let run = |statements: &str, extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+            .arg("cases/05-transactions-chargeback.csv")
+            .arg("--per-client-dir").arg(dir.path().join(statements))
+            .args(extra)
+            .output()
+            .expect("Error running instance");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        // Accounts still go to stdout
+        assert!(String::from_utf8_lossy(&output.stdout).starts_with("client,available,held,total,locked\n"));
+        let mut names: Vec<String> = std::fs::read_dir(dir.path().join(statements)).expect("Error listing statements")
+            .map(|entry| entry.expect("Error listing statements").file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    assert_eq!(run("all", &[]), vec!["client-1.csv", "client-2.csv"]);
+    // Amounts as written, minor-units backend has no scale to keep and shows four decimals
+    let amount = |written: &'static str, minor: &'static str| if cfg!(feature = "minor-units") { minor } else { written };
+    let (one, two, one_half) = (amount("1.0", "1.0000"), amount("2.0", "2.0000"), amount("1.5", "1.5000"));
+    assert_eq!(std::fs::read_to_string(dir.path().join("all/client-1.csv")).expect("Error reading statement"), format!("\
+        seq,type,tx,amount,available,held,total,locked\n\
+        1,deposit,1,{one},1.0000,0.0000,1.0000,false\n\
+        3,deposit,3,{two},3.0000,0.0000,3.0000,false\n\
+        4,withdrawal,4,{one_half},1.5000,0.0000,1.5000,false\n\
+        5,dispute,1,{one},0.5000,1.0000,1.5000,false\n\
+        6,chargeback,1,{one},0.5000,0.0000,0.5000,true\n\
+        ,closing,,,0.5000,0.0000,0.5000,true\n"));
+
+    assert_eq!(run("some", &["--clients", "2,3"]), vec!["client-2.csv"]);
+
+    // Failed run leaves no event log behind
+    let status = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg("cases/missing.csv")
+        .arg("--per-client-dir").arg(dir.path().join("failed"))
+        .output()
+        .expect("Error running instance")
+        .status;
+    assert!(!status.success());
+    assert_eq!(std::fs::read_dir(dir.path().join("failed")).expect("Error listing statements").count(), 0);
+}
+
+#[test]
+fn test_shard_output() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let shards = dir.path().join("shards");
+    let input: String = (1..=5).map(|client| format!("deposit, {}, {}, 1.0\n", client, client)).collect();
+    let run = |rows: &str| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+            .args(["--shard-output", rows, "--out-dir"]).arg(&shards)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Error starting instance");
+        child.stdin.take().unwrap().write_all(format!("type, client, tx, amount\n{}", input).as_bytes()).expect("Error writing input");
+        let output = child.wait_with_output().expect("Error waiting instance");
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        assert!(output.stdout.is_empty());
+        let mut names: Vec<String> = std::fs::read_dir(&shards).expect("Error listing shards")
+            .map(|entry| entry.expect("Error listing shards").file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names.into_iter().map(|name| {
+            let text = std::fs::read_to_string(shards.join(&name)).expect("Error reading shard");
+            assert!(text.starts_with("client,available,held,total,locked\n"), "{}", text);
+            let clients: Vec<String> = text.lines().skip(1).map(|line| line.split(',').next().unwrap().to_string()).collect();
+            (name, clients)
+        }).collect::<Vec<_>>()
+    };
+
+    let shard = |name: &str, clients: &[&str]| (name.to_string(), clients.iter().map(|client| client.to_string()).collect::<Vec<_>>());
+    assert_eq!(run("2"), vec![shard("accounts_0001.csv", &["1", "2"]), shard("accounts_0002.csv", &["3", "4"]), shard("accounts_0003.csv", &["5"])]);
+    // Shards of the earlier run beyond the new count are removed
+    assert_eq!(run("4"), vec![shard("accounts_0001.csv", &["1", "2", "3", "4"]), shard("accounts_0002.csv", &["5"])]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .args(["--shard-output", "2", "cases/01-transactions-base.csv"])
+        .output()
+        .expect("Error running instance");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--shard-output requires --out-dir"));
+}
+
+#[test]
+fn test_manifest() {
+    use sha2::{Digest, Sha256};
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let out = dir.path().join("accounts.csv");
+    let manifest = dir.path().join("manifest.json");
+    let input = "cases/05-transactions-chargeback.csv";
+    let output = command(&out, &["--manifest", manifest.to_str().unwrap(), input]).output().expect("Error running instance");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    let sha256 = |path: &std::path::Path| {
+        let hash = Sha256::digest(std::fs::read(path).expect("Error reading file"));
+        hash.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    };
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest).expect("Error reading manifest"))
+        .expect("Invalid manifest");
+    assert_eq!(manifest["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(manifest["inputs"][0]["path"], input);
+    assert_eq!(manifest["inputs"][0]["sha256"], sha256(input.as_ref()));
+    assert_eq!(manifest["inputs"][0]["rows"], 7);
+    assert_eq!(manifest["outputs"][0]["path"], out.to_str().unwrap());
+    assert_eq!(manifest["outputs"][0]["sha256"], sha256(&out));
+    assert_eq!(manifest["outputs"].as_array().unwrap().len(), 1);
+    assert_eq!(manifest["config_sha256"].as_str().unwrap().len(), 64);
+    assert_eq!(manifest["summary"]["records"], 7);
+    assert!(manifest["started"].as_str().unwrap() <= manifest["finished"].as_str().unwrap());
+}
+
+#[test]
+fn test_checksum_sidecar() {
+    use sha2::{Digest, Sha256};
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let out = dir.path().join("accounts.csv");
+    let output = command(&out, &["--checksum", "cases/05-transactions-chargeback.csv"]).output().expect("Error running instance");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+
+    let hash = Sha256::digest(std::fs::read(&out).expect("Error reading output"));
+    let hash: String = hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let sidecar = std::fs::read_to_string(dir.path().join("accounts.csv.sha256")).expect("Error reading checksum");
+    assert_eq!(sidecar, format!("{}  accounts.csv\n", hash));
+    assert!(stderr.contains(&format!("Checksum: sha256:{}", hash)), "{}", stderr);
+}
+
+#[test]
+fn test_self_check() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let sample = dir.path().join("sample.csv");
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .args(["gen", "--clients", "50", "--txns", "5000", "--dispute-ratio", "0.05", "--out"])
+        .arg(&sample)
+        .output()
+        .expect("Error running gen");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    std::fs::create_dir(dir.path().join("spill")).expect("Error creating spill dir");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg(&sample)
+        .args(["--self-check", "--mmap", "--expected-clients", "50", "--spill-dir"])
+        .arg(dir.path().join("spill"))
+        .args(["--spill-memory-cap", "10"])
+        .output()
+        .expect("Error running instance");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("Self-check: reference run "), "{}", stderr);
+    assert!(stderr.contains("Self-check: accounts match"), "{}", stderr);
+    assert!(output.stdout.is_empty());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .args(["--self-check", "--mmap"])
+        .stdin(Stdio::null())
+        .output()
+        .expect("Error running instance");
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_warn_summary() {
+    let input = "type,client,tx,amount\n\
+        deposit,1,1,5.0\n\
+        withdrawal,1,2,9.0\n\
+        withdrawal,1,3,8.0\n\
+        withdrawal,1,4,7.0\n\
+        dispute,1,99,\n\
+        dispute,2,1,\n\
+        dispute,1,1,\n\
+        dispute,1,1,\n\
+        deposit,x,5,1.0\n";
+    let mut child = Command::new(env!("CARGO_BIN_EXE_process-transactions"))
+        .arg("--warn-summary")
+        .env("RUST_LOG", "info")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Error running instance");
+    child.stdin.take().unwrap().write_all(input.as_bytes()).expect("Error writing input");
+    let output = child.wait_with_output().expect("Error waiting instance");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("WARN") && !stderr.contains("INFO"), "{}", stderr);
+    assert!(stderr.contains("Malformed rows: 1\n"), "{}", stderr);
+    assert!(stderr.contains("Rejected: 3 insufficient_funds, 1 already_disputed, 1 client_mismatch, 1 unknown_transaction\n"), "{}", stderr);
+}
+
+#[test]
+fn test_exit_codes() {
+    let dir = tempfile::tempdir().expect("Error creating temp dir");
+    let out = dir.path().join("accounts.csv");
+    let last_line = |stderr: &[u8]| -> serde_json::Value {
+        let stderr = String::from_utf8_lossy(stderr);
+        serde_json::from_str(stderr.lines().last().expect("No stderr")).expect("Invalid exit summary")
+    };
+
+    let missing = dir.path().join("missing.csv");
+    let output = command(&out, &["--exit-summary", missing.to_str().unwrap()]).output().expect("Error running instance");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(last_line(&output.stderr)["exit_code"], 1);
+
+    // cases/05 has a withdrawal over available funds
+    let output = command(&out, &["--exit-summary", "--fail-on-reject", "cases/05-transactions-chargeback.csv"]).output().expect("Error running instance");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(out.exists());
+    let summary = last_line(&output.stderr);
+    assert_eq!(summary["exit_code"], 3);
+    assert_eq!(summary["records"], 7);
+    assert_eq!(summary["applied"], 6);
+    assert_eq!(summary["rejected"]["insufficient_funds"], 1);
+    assert_eq!((summary["clients"].clone(), summary["locked"].clone()), (2.into(), 1.into()));
+
+    let output = command(&out, &["--exit-summary", "cases/05-transactions-chargeback.csv"]).output().expect("Error running instance");
+    assert!(output.status.success());
+    assert_eq!(last_line(&output.stderr)["exit_code"], 0);
+
+    let output = command(&out, &["--unknown-types", "error", "cases/06-transactions-unexpected.csv"]).output().expect("Error running instance");
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_version_names_backend() {
+    let output = Command::new(env!("CARGO_BIN_EXE_process-transactions")).arg("--version").output().expect("Error running instance");
+    let backend = if cfg!(feature = "bigdecimal") { "bigdecimal" } else if cfg!(feature = "minor-units") { "minor-units" } else { "rust_decimal" };
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("process-transactions {} (money: {})\n", env!("CARGO_PKG_VERSION"), backend));
+}